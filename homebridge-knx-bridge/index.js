@@ -15,6 +15,7 @@ class KNXBridgePlatform {
         this.api = api;
 
         this.bridgeUrl = config.bridgeUrl || 'http://localhost:8080';
+        this.authHeaders = config.apiToken ? { Authorization: `Bearer ${config.apiToken}` } : {};
         this.accessories = [];
 
         this.log('KNX Bridge Platform initialized');
@@ -27,7 +28,7 @@ class KNXBridgePlatform {
 
     async discoverDevices() {
         try {
-            const response = await fetch(`${this.bridgeUrl}/devices`);
+            const response = await fetch(`${this.bridgeUrl}/devices`, { headers: this.authHeaders });
             const data = await response.json();
 
             this.log(`Discovered ${data.total} devices`);
@@ -53,6 +54,7 @@ class KNXBridgePlatform {
 
         const accessory = new this.api.platformAccessory(device.name, uuid);
         accessory.context.device = device;
+        this.applyMetadata(accessory, device.metadata || {});
 
         switch (device.device_type) {
             case 'Light':
@@ -63,9 +65,15 @@ class KNXBridgePlatform {
             case 'TemperatureSensor':
                 this.addTemperatureService(accessory, device);
                 break;
+            case 'HumiditySensor':
+                this.addHumidityService(accessory, device);
+                break;
             case 'WindowCovering':
                 this.addWindowCoveringService(accessory, device);
                 break;
+            case 'Outlet':
+                this.addOutletService(accessory, device);
+                break;
             case 'Fan':
                 this.addFanService(accessory, device);
                 break;
@@ -82,6 +90,19 @@ class KNXBridgePlatform {
         this.log(`Added accessory: ${device.name}`);
     }
 
+    applyMetadata(accessory, metadata) {
+        const info = accessory.getService(Service.AccessoryInformation);
+        if (metadata.manufacturer) {
+            info.setCharacteristic(Characteristic.Manufacturer, metadata.manufacturer);
+        }
+        if (metadata.model) {
+            info.setCharacteristic(Characteristic.Model, metadata.model);
+        }
+        if (metadata.serial) {
+            info.setCharacteristic(Characteristic.SerialNumber, metadata.serial);
+        }
+    }
+
     addLightService(accessory, device) {
         const service = accessory.addService(Service.Lightbulb, device.name);
 
@@ -93,6 +114,22 @@ class KNXBridgePlatform {
             onCharacteristic.updateValue(device.state.on);
         }
 
+        let brightnessCharacteristic = null;
+        if (device.capabilities && device.capabilities.brightness === 'continuous') {
+            brightnessCharacteristic = service.getCharacteristic(Characteristic.Brightness);
+            brightnessCharacteristic.updateValue(device.state.level);
+            brightnessCharacteristic.on('set', async (value, callback) => {
+                try {
+                    await this.setBrightness(device.key, value);
+                    this.log(`${device.name} brightness set to ${value}%`);
+                    callback(null);
+                } catch (error) {
+                    this.log.error(`Failed to set brightness of ${device.name}:`, error.message);
+                    callback(error);
+                }
+            });
+        }
+
         onCharacteristic.on('set', async (value, callback) => {
             try {
                 await this.toggleDevice(device.key, value);
@@ -111,16 +148,41 @@ class KNXBridgePlatform {
                     onCharacteristic.updateValue(state.on);
                 } else if (state.type === 'brightness') {
                     onCharacteristic.updateValue(state.on);
+                    if (brightnessCharacteristic) {
+                        brightnessCharacteristic.updateValue(state.level);
+                    }
                 }
             } catch (error) {
             }
         }, 5000);
     }
 
+    trackBattery(service, device) {
+        if (device.battery_low === undefined) {
+            return;
+        }
+        const lowBattery = service.getCharacteristic(Characteristic.StatusLowBattery);
+        const update = (low) => lowBattery.updateValue(low
+            ? Characteristic.StatusLowBattery.BATTERY_LEVEL_LOW
+            : Characteristic.StatusLowBattery.BATTERY_LEVEL_NORMAL);
+        update(device.battery_low);
+
+        setInterval(async () => {
+            try {
+                const info = await this.getDevice(device.key);
+                if (info.battery_low !== undefined) {
+                    update(info.battery_low);
+                }
+            } catch (error) {
+            }
+        }, 300000);
+    }
+
     addTemperatureService(accessory, device) {
         const service = accessory.addService(Service.TemperatureSensor, device.name);
 
         const tempCharacteristic = service.getCharacteristic(Characteristic.CurrentTemperature);
+        this.trackBattery(service, device);
 
         if (device.state.type === 'temperature') {
             tempCharacteristic.updateValue(device.state.celsius);
@@ -137,15 +199,57 @@ class KNXBridgePlatform {
         }, 30000);
     }
 
+    addHumidityService(accessory, device) {
+        const service = accessory.addService(Service.HumiditySensor, device.name);
+
+        const humidityCharacteristic = service.getCharacteristic(Characteristic.CurrentRelativeHumidity);
+        this.trackBattery(service, device);
+
+        if (device.state.type === 'humidity') {
+            humidityCharacteristic.updateValue(device.state.percent);
+        }
+
+        setInterval(async () => {
+            try {
+                const state = await this.getDeviceState(device.key);
+                if (state.type === 'humidity') {
+                    humidityCharacteristic.updateValue(state.percent);
+                }
+            } catch (error) {
+            }
+        }, 30000);
+    }
+
     addWindowCoveringService(accessory, device) {
         const service = accessory.addService(Service.WindowCovering, device.name);
 
         const positionCharacteristic = service.getCharacteristic(Characteristic.CurrentPosition);
         const targetCharacteristic = service.getCharacteristic(Characteristic.TargetPosition);
+        const obstructionCharacteristic = service.getCharacteristic(Characteristic.ObstructionDetected);
 
         if (device.state.type === 'windowcovering') {
             positionCharacteristic.updateValue(device.state.position);
             targetCharacteristic.updateValue(device.state.position);
+            obstructionCharacteristic.updateValue(device.state.obstruction);
+        }
+
+        // HomeKit tilts from -90° to 90°, the bridge takes the lamella angle in percent.
+        let tiltCharacteristic = null;
+        if (device.capabilities && device.capabilities.tilt === 'continuous') {
+            tiltCharacteristic = service.getCharacteristic(Characteristic.TargetHorizontalTiltAngle);
+            if (device.state.tilt !== undefined) {
+                tiltCharacteristic.updateValue(Math.round(device.state.tilt * 1.8 - 90));
+            }
+            tiltCharacteristic.on('set', async (value, callback) => {
+                try {
+                    await this.setBlindTilt(device.key, Math.round((value + 90) / 1.8));
+                    this.log(`${device.name} tilted to ${value}°`);
+                    callback(null);
+                } catch (error) {
+                    this.log.error(`Failed to tilt ${device.name}:`, error.message);
+                    callback(error);
+                }
+            });
         }
 
         targetCharacteristic.on('set', async (value, callback) => {
@@ -159,6 +263,51 @@ class KNXBridgePlatform {
                 callback(error);
             }
         });
+
+        setInterval(async () => {
+            try {
+                const state = await this.getDeviceState(device.key);
+                if (state.type === 'windowcovering') {
+                    positionCharacteristic.updateValue(state.position);
+                    obstructionCharacteristic.updateValue(state.obstruction);
+                }
+            } catch (error) {
+            }
+        }, 30000);
+    }
+
+    addOutletService(accessory, device) {
+        const service = accessory.addService(Service.Outlet, device.name);
+
+        const onCharacteristic = service.getCharacteristic(Characteristic.On);
+        const inUseCharacteristic = service.getCharacteristic(Characteristic.OutletInUse);
+
+        if (device.state.type === 'outlet') {
+            onCharacteristic.updateValue(device.state.on);
+            inUseCharacteristic.updateValue(device.state.in_use);
+        }
+
+        onCharacteristic.on('set', async (value, callback) => {
+            try {
+                await this.toggleDevice(device.key, value);
+                this.log(`${device.name} set to ${value ? 'ON' : 'OFF'}`);
+                callback(null);
+            } catch (error) {
+                this.log.error(`Failed to set ${device.name}:`, error.message);
+                callback(error);
+            }
+        });
+
+        setInterval(async () => {
+            try {
+                const state = await this.getDeviceState(device.key);
+                if (state.type === 'outlet') {
+                    onCharacteristic.updateValue(state.on);
+                    inUseCharacteristic.updateValue(state.in_use);
+                }
+            } catch (error) {
+            }
+        }, 5000);
     }
 
     addFanService(accessory, device) {
@@ -192,7 +341,7 @@ class KNXBridgePlatform {
         onCharacteristic.on('set', async (value, callback) => {
             if (value) {
                 try {
-                    await this.toggleDevice(device.key, true);
+                    await this.triggerScene(device.key);
                     this.log(`${device.name} activated`);
 
                     setTimeout(() => {
@@ -213,7 +362,7 @@ class KNXBridgePlatform {
     async toggleDevice(deviceKey, on) {
         const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/toggle`, {
             method: 'POST',
-            headers: { 'Content-Type': 'application/json' },
+            headers: { ...this.authHeaders, 'Content-Type': 'application/json' },
             body: JSON.stringify({ on })
         });
 
@@ -224,8 +373,18 @@ class KNXBridgePlatform {
         return await response.json();
     }
 
+    async getDevice(deviceKey) {
+        const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}`, { headers: this.authHeaders });
+
+        if (!response.ok) {
+            throw new Error(`HTTP ${response.status}: ${response.statusText}`);
+        }
+
+        return await response.json();
+    }
+
     async getDeviceState(deviceKey) {
-        const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/state`);
+        const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/state`, { headers: this.authHeaders });
 
         if (!response.ok) {
             throw new Error(`HTTP ${response.status}: ${response.statusText}`);
@@ -237,7 +396,7 @@ class KNXBridgePlatform {
     async setBlindPosition(deviceKey, position) {
         const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/position`, {
             method: 'POST',
-            headers: { 'Content-Type': 'application/json' },
+            headers: { ...this.authHeaders, 'Content-Type': 'application/json' },
             body: JSON.stringify({ position })
         });
 
@@ -248,6 +407,47 @@ class KNXBridgePlatform {
         return await response.json();
     }
 
+    async setBlindTilt(deviceKey, tilt) {
+        const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/tilt`, {
+            method: 'POST',
+            headers: { ...this.authHeaders, 'Content-Type': 'application/json' },
+            body: JSON.stringify({ tilt })
+        });
+
+        if (!response.ok) {
+            throw new Error(`HTTP ${response.status}: ${response.statusText}`);
+        }
+
+        return await response.json();
+    }
+
+    async triggerScene(deviceKey) {
+        const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/trigger`, {
+            method: 'POST',
+            headers: this.authHeaders
+        });
+
+        if (!response.ok) {
+            throw new Error(`HTTP ${response.status}: ${response.statusText}`);
+        }
+
+        return await response.json();
+    }
+
+    async setBrightness(deviceKey, level) {
+        const response = await fetch(`${this.bridgeUrl}/device/${deviceKey}/brightness`, {
+            method: 'POST',
+            headers: { ...this.authHeaders, 'Content-Type': 'application/json' },
+            body: JSON.stringify({ level })
+        });
+
+        if (!response.ok) {
+            throw new Error(`HTTP ${response.status}: ${response.statusText}`);
+        }
+
+        return await response.json();
+    }
+
     configureAccessory(accessory) {
         this.log('Loading accessory from cache:', accessory.displayName);
         this.accessories.push(accessory);
@@ -1,22 +1,43 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::{
-    extract::{Path, State},
-    http::{Method, StatusCode},
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     routing::{get, post},
     Json, Router,
 };
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::{info, warn};
 
-use crate::device::{Device, DeviceState};
-use crate::state_manager::StateManager;
+use crate::command_mapper::{CommandMapper, KnxCommand, ValueOutOfRange, MAPPINGS_PATH};
+use crate::config::{Config, EffectiveConfig};
+use crate::device::{Capabilities, Device, DeviceState, DeviceType};
+use crate::state_manager::{
+    AttentionReason, DeviceAction, DiscoveryInProgress, EventSubscription, StateEvent, StateManager,
+    TooManySubscribers, UnsupportedAction,
+};
 
 #[derive(Clone)]
 pub struct ApiState {
     pub state_manager: Arc<StateManager>,
+    pub admin_token: Option<Arc<str>>,
+    pub api_token: Option<Arc<str>>,
+    pub effective_config: Arc<EffectiveConfig>,
+    /// Flips to `true` once the bridge shuts down; ends open event streams.
+    pub shutdown: watch::Receiver<bool>,
 }
 
 #[derive(Debug, Serialize)]
@@ -25,23 +46,80 @@ pub struct DeviceInfo {
     pub id: String,
     pub name: String,
     pub device_type: String,
+    pub homekit_service: String,
     pub page: String,
     pub state: DeviceStateInfo,
+    pub capabilities: Capabilities,
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// ISO-8601 time of the last state change.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_changed: Option<DateTime<Utc>>,
+    /// Pass-through fields from the `[metadata]` mappings section.
+    pub metadata: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_low: Option<bool>,
+    /// Signal strength in percent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<u8>,
 }
 
-#[derive(Debug, Serialize)]
+/// Also the body of `PUT /device/:key/state`, where read-only fields
+/// (`obstruction`, `in_use`) may be left out and are ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum DeviceStateInfo {
     OnOff { on: bool },
     Brightness { on: bool, level: u8 },
-    WindowCovering { position: u8 },
+    WindowCovering {
+        position: u8,
+        #[serde(default)]
+        obstruction: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tilt: Option<u8>,
+    },
     Temperature { celsius: f32 },
+    Humidity { percent: f32 },
     FanSpeed { speed: u8 },
+    Outlet {
+        on: bool,
+        #[serde(default)]
+        in_use: bool,
+    },
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ToggleRequest {
     pub on: bool,
+    /// Same as `?confirm=true`.
+    #[serde(default)]
+    pub wait_confirm: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfirmQuery {
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// `GET /events?key=a,b&type=Light,Dimmer`; both lists are optional.
+#[derive(Debug, Default, Deserialize)]
+pub struct EventsQuery {
+    pub key: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollingRequest {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceTypeRequest {
+    #[serde(rename = "type")]
+    pub type_: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -49,10 +127,142 @@ pub struct BlindPositionRequest {
     pub position: u8,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct BrightnessRequest {
+    pub level: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FanSpeedRequest {
+    pub speed: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TiltRequest {
+    pub tilt: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub commands: Vec<BatchCommand>,
+}
+
+/// One entry of `POST /batch`: a device key plus either the shorthand
+/// `toggle`/`position` actions or anything `POST /device/:key/action` accepts.
+#[derive(Debug, Deserialize)]
+pub struct BatchCommand {
+    pub key: String,
+    #[serde(flatten)]
+    pub action: BatchAction,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BatchAction {
+    Toggle { on: bool },
+    Position { position: u8 },
+    #[serde(untagged)]
+    Device(DeviceAction),
+}
+
+impl From<BatchAction> for DeviceAction {
+    fn from(action: BatchAction) -> Self {
+        match action {
+            BatchAction::Toggle { on: true } => DeviceAction::On,
+            BatchAction::Toggle { on: false } => DeviceAction::Off,
+            BatchAction::Position { position } => DeviceAction::SetPosition { position },
+            BatchAction::Device(action) => action,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct DeviceListQuery {
+    /// Only list devices whose commands resolve in the mappings.
+    pub mapped: Option<bool>,
+    /// Only list devices of this type, e.g. `WindowCovering` (case-insensitive).
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    /// Only list devices on this page; `2` and `02` are the same page.
+    pub page: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct DeviceListResponse {
     pub devices: Vec<DeviceInfo>,
     pub total: usize,
+    pub unmapped: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SceneListResponse {
+    pub scenes: Vec<SceneInfo>,
+    pub total: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SceneInfo {
+    pub key: String,
+    pub name: String,
+    pub page: String,
+    /// `false` when the scene has no command or is mapped `READONLY`.
+    pub controllable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttentionResponse {
+    pub devices: Vec<AttentionInfo>,
+    pub total: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AttentionInfo {
+    pub key: String,
+    pub name: String,
+    pub device_type: String,
+    pub reasons: Vec<AttentionReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Listings with more devices than this are streamed in chunks instead of
+/// serialized into a single buffer.
+const STREAM_THRESHOLD: usize = 200;
+const STREAM_CHUNK_SIZE: usize = 50;
+
+/// Outcome of one command within a multi-device operation.
+#[derive(Debug, Serialize)]
+pub struct CommandResult {
+    pub key: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CommandResult {
+    fn from_result(key: String, result: &Result<()>) -> Self {
+        Self {
+            key,
+            success: result.is_ok(),
+            error: result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateCommandRequest {
+    pub command: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidateCommandResponse {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<KnxCommand>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -60,68 +270,184 @@ pub struct ErrorResponse {
     pub error: String,
 }
 
-impl From<&Device> for DeviceInfo {
-    fn from(device: &Device) -> Self {
-        let device_type = format!("{:?}", device.type_);
-        let state = match &device.state {
+impl From<&DeviceState> for DeviceStateInfo {
+    fn from(state: &DeviceState) -> Self {
+        match state {
             DeviceState::OnOff(on) => DeviceStateInfo::OnOff { on: *on },
             DeviceState::Brightness { on, level } => DeviceStateInfo::Brightness {
                 on: *on,
                 level: *level,
             },
-            DeviceState::WindowCovering { position, .. } => DeviceStateInfo::WindowCovering {
+            DeviceState::WindowCovering { position, obstruction, tilt, .. } => DeviceStateInfo::WindowCovering {
                 position: *position,
+                obstruction: *obstruction,
+                tilt: *tilt,
             },
             DeviceState::Temperature(temp) => DeviceStateInfo::Temperature { celsius: *temp },
+            DeviceState::Humidity(humidity) => DeviceStateInfo::Humidity { percent: *humidity },
             DeviceState::FanSpeed(speed) => DeviceStateInfo::FanSpeed { speed: *speed },
-        };
+            DeviceState::Outlet { on, in_use } => DeviceStateInfo::Outlet {
+                on: *on,
+                in_use: *in_use,
+            },
+        }
+    }
+}
 
+impl DeviceInfo {
+    fn new(device: &Device, state_manager: &StateManager) -> Self {
+        let key = device.key();
         DeviceInfo {
-            key: device.key(),
+            metadata: state_manager.command_mapper().metadata(&key),
+            capabilities: state_manager.capabilities(device),
+            key,
             id: device.id.clone(),
             name: device.name.clone(),
-            device_type,
+            device_type: format!("{:?}", device.type_),
+            homekit_service: device.type_.homekit_service().to_string(),
             page: device.page.clone(),
-            state,
+            state: DeviceStateInfo::from(&device.state),
+            reachable: device.reachable,
+            last_error: device.last_error.clone(),
+            last_changed: device.last_changed,
+            battery_low: device.battery_low,
+            signal: device.signal,
         }
     }
 }
 
-pub async fn start_api_server(state_manager: Arc<StateManager>, port: u16) -> Result<()> {
-    let state = ApiState { state_manager };
+/// Serves the API until `shutdown` turns `true`, then stops accepting connections
+/// and returns once in-flight requests have finished.
+pub async fn start_api_server(
+    state_manager: Arc<StateManager>,
+    full_config: &Config,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let config = &full_config.homekit;
+    let port = config.port;
+    let state = ApiState {
+        state_manager,
+        admin_token: config.admin_token.as_deref().map(Arc::from),
+        api_token: config.api_token.as_deref().map(Arc::from),
+        effective_config: Arc::new(full_config.effective()),
+        shutdown: shutdown.clone(),
+    };
 
     let cors = CorsLayer::new()
         .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::OPTIONS])
         .allow_headers(Any);
 
     let app = Router::new()
         .route("/", get(root))
         .route("/devices", get(list_devices))
+        .route("/scenes", get(list_scenes))
+        .route("/scenes/:key/activate", post(activate_scene))
+        .route("/attention", get(list_attention))
+        .route("/devices/by-name/:name", get(get_device_by_name))
         .route("/device/:key", get(get_device))
-        .route("/device/:key/state", get(get_device_state))
+        .route("/device/:key/state", get(get_device_state).put(put_device_state))
         .route("/device/:key/toggle", post(toggle_device))
         .route("/device/:key/position", post(set_blind_position))
+        .route("/device/:key/brightness", post(set_brightness))
+        .route("/device/:key/fan-speed", post(set_fan_speed))
+        .route("/device/:key/tilt", post(set_blind_tilt))
+        .route("/device/:key/action", post(device_action))
+        .route("/device/:key/identify", post(identify_device))
+        .route("/device/:key/trigger", post(trigger_scene))
+        .route("/page/:page/toggle", post(toggle_page))
+        .route("/batch", post(run_batch))
+        .route("/index/:page/:index/toggle", post(toggle_by_index))
+        .route("/export", get(export_registry))
+        .route("/polling", post(set_polling))
+        .route("/diagnostics", get(diagnostics))
         .route("/health", get(health_check))
+        .route("/ready", get(readiness))
+        .route("/events", get(stream_events));
+
+    let admin = Router::new()
+        .route("/device/:key/type", post(set_device_type))
+        .route("/rediscover", post(rediscover))
+        .route("/mappings/reload", post(reload_mappings))
+        .route("/config", get(effective_config))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
+    let app = app.merge(admin);
+
+    let app = if config.debug_endpoints {
+        app.route("/import", post(import_registry))
+            .route("/validate-command", post(validate_command))
+    } else {
+        app
+    };
+
+    let app = if config.metrics_endpoint {
+        app.route("/metrics", get(metrics))
+    } else {
+        app
+    };
+
+    let app = app
+        .layer(middleware::from_fn_with_state(state.clone(), require_token))
         .layer(cors)
         .with_state(state);
 
-    let addr = format!("0.0.0.0:{port}");
+    let addr = std::net::SocketAddr::new(config.bind_address, port);
     info!("🌐 HTTP API server listening on http://{}", addr);
+    if config.api_token.is_none() {
+        warn!("SMARTHOME_API_TOKEN is not set, the API accepts requests from anyone who can reach {}", addr);
+    }
     info!("   API endpoints:");
-    info!("   - GET  /devices                List all devices");
+    info!("   - GET  /devices                List all devices (?type=&page=&mapped=true&offset=&limit=)");
+    info!("   - GET  /scenes                 List scenes and whether they can be activated");
+    info!("   - POST /scenes/:key/activate   Activate a scene (same as /device/:key/trigger)");
+    info!("   - GET  /attention              Devices that need fixing, with reasons");
+    info!("   - GET  /devices/by-name/:name  Get device info by name");
     info!("   - GET  /device/:key            Get device info");
     info!("   - GET  /device/:key/state      Get device state");
+    info!("   - PUT  /device/:key/state      Set the full desired device state");
     info!("   - POST /device/:key/toggle     Toggle device");
     info!("   - POST /device/:key/position   Set blind position");
+    info!("   - POST /device/:key/brightness Set dimmer brightness");
+    info!("   - POST /device/:key/fan-speed  Set fan speed step (0 = off)");
+    info!("   - POST /device/:key/tilt       Set blind lamella angle");
+    info!("   - POST /device/:key/action     Run an action (on/off/set_position/brightness/fan_speed/tilt/stop/identify)");
+    info!("   - POST /device/:key/identify   Blink device to locate it");
+    info!("   - POST /device/:key/trigger    Fire a scene once");
+    info!("   - POST /page/:page/toggle      Switch all lights/switches on a page");
+    info!("   - POST /batch                  Run several device commands in order");
+    info!("   - POST /index/:page/:index/toggle  Toggle device by KNX index");
+    info!("   - GET  /export                 Export device registry");
+    info!("   - GET  /events                 Stream state changes as SSE (?key=&type=)");
+    info!("   - POST /polling                Pause/resume state polling");
+    info!("   - GET  /diagnostics            Runtime diagnostics");
+    info!("   - POST /device/:key/type       Override device type until restart (admin)");
+    info!("   - POST /rediscover             Re-run device discovery (admin)");
+    info!("   - POST /mappings/reload        Re-read device_mappings.toml (admin)");
+    info!("   - GET  /config                 Effective configuration without secrets (admin)");
+    if config.debug_endpoints {
+        info!("   - POST /import                 Restore device registry (debug)");
+        info!("   - POST /validate-command       Check a command string without sending it (debug)");
+    }
+    if config.metrics_endpoint {
+        info!("   - GET  /metrics                Prometheus metrics");
+    }
     info!("   - GET  /health                 Health check");
+    info!("   - GET  /ready                  Ready once logged in and devices are loaded");
 
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {addr}"))?;
+    axum::serve(listener, app).with_graceful_shutdown(shutdown_requested(shutdown)).await?;
+    info!("API server stopped");
 
     Ok(())
 }
 
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    // A dropped sender means the bridge is exiting too.
+    let _ = shutdown.wait_for(|stop| *stop).await;
+}
+
 async fn root() -> &'static str {
     "KNX-HomeKit Bridge API v1.0"
 }
@@ -130,28 +456,289 @@ async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, Json(serde_json::json!({"status": "ok"})))
 }
 
-async fn list_devices(State(state): State<ApiState>) -> impl IntoResponse {
-    let devices = state.state_manager.get_all_devices().await;
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::METRICS.render(),
+    )
+}
 
-    let filtered_devices: Vec<DeviceInfo> = devices
-        .iter()
-        .filter(|d| !should_filter_device(d))
-        .map(DeviceInfo::from)
-        .collect();
+async fn readiness(State(state): State<ApiState>) -> impl IntoResponse {
+    let readiness = state.state_manager.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+async fn diagnostics(State(state): State<ApiState>) -> impl IntoResponse {
+    (StatusCode::OK, Json(state.state_manager.diagnostics().await))
+}
+
+async fn set_polling(
+    State(state): State<ApiState>,
+    Json(payload): Json<PollingRequest>,
+) -> impl IntoResponse {
+    info!("API: Polling {} request", if payload.enabled { "resume" } else { "pause" });
+    state.state_manager.set_polling_enabled(payload.enabled);
+
+    let diagnostics = state.state_manager.diagnostics().await;
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": "ok", "polling": diagnostics.polling})),
+    )
+}
+
+async fn list_devices(
+    State(state): State<ApiState>,
+    Query(query): Query<DeviceListQuery>,
+) -> impl IntoResponse {
+    let listing = match device_listing(&state.state_manager, &query).await {
+        Ok(listing) => listing,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response(),
+    };
+
+    if listing.devices.len() > STREAM_THRESHOLD {
+        return stream_device_list(listing.devices, listing.total, listing.unmapped);
+    }
+    (StatusCode::OK, Json(listing)).into_response()
+}
+
+/// The page of devices `GET /devices` returns. `unmapped` counts the controllable
+/// devices matching the type and page filters that have no usable command, whether
+/// or not `mapped` hides them, so it drops to 0 once everything is mapped.
+async fn device_listing(state_manager: &StateManager, query: &DeviceListQuery) -> Result<DeviceListResponse, String> {
+    let mapper = state_manager.command_mapper();
+    let type_: Option<DeviceType> = query.type_.as_deref().map(str::parse).transpose()?;
+    let page = query.page.clone().map(normalize_page);
+
+    let mut unmapped = 0;
+    let mut filtered_devices: Vec<DeviceInfo> = state_manager
+        .map_devices(|d| {
+            let listed = !should_filter_device(d, &mapper)
+                && type_.as_ref().is_none_or(|type_| d.type_ == *type_)
+                && page.as_ref().is_none_or(|page| d.page == *page);
+            if !listed {
+                return None;
+            }
+            let actionable = mapper.is_actionable(d);
+            if !actionable && !d.type_.is_sensor() {
+                unmapped += 1;
+            }
+            query
+                .mapped
+                .is_none_or(|mapped| actionable == mapped)
+                .then(|| DeviceInfo::new(d, state_manager))
+        })
+        .await;
+    filtered_devices.sort_by(|a, b| a.key.cmp(&b.key));
 
     let total = filtered_devices.len();
+    let devices: Vec<DeviceInfo> = filtered_devices
+        .into_iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect();
+
+    Ok(DeviceListResponse {
+        devices,
+        total,
+        unmapped,
+    })
+}
+
+async fn get_device_by_name(
+    State(state): State<ApiState>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let devices = state.state_manager.find_by_name(&name).await;
+    match devices.as_slice() {
+        [device] => (StatusCode::OK, Json(DeviceInfo::new(device, &state.state_manager))).into_response(),
+        [] => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("No device named: {name}"),
+            }),
+        )
+            .into_response(),
+        _ => (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                error: format!(
+                    "Several devices are named {name}, use a key instead: {}",
+                    devices.iter().map(Device::key).collect::<Vec<_>>().join(", ")
+                ),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn list_scenes(State(state): State<ApiState>) -> impl IntoResponse {
+    let scenes: Vec<SceneInfo> = state
+        .state_manager
+        .scenes()
+        .await
+        .into_iter()
+        .map(|(device, controllable)| SceneInfo {
+            key: device.key(),
+            name: device.name,
+            page: device.page,
+            controllable,
+        })
+        .collect();
+    let total = scenes.len();
+    Json(SceneListResponse { scenes, total })
+}
+
+async fn list_attention(State(state): State<ApiState>) -> impl IntoResponse {
+    let devices: Vec<AttentionInfo> = state
+        .state_manager
+        .attention()
+        .await
+        .into_iter()
+        .map(|(device, reasons)| AttentionInfo {
+            key: device.key(),
+            device_type: format!("{:?}", device.type_),
+            name: device.name,
+            reasons,
+            last_error: device.last_error,
+        })
+        .collect();
+    let total = devices.len();
+    Json(AttentionResponse { devices, total })
+}
 
+/// Streams a `DeviceListResponse`-shaped body chunk by chunk.
+fn stream_device_list(devices: Vec<DeviceInfo>, total: usize, unmapped: usize) -> Response {
     (
         StatusCode::OK,
-        Json(DeviceListResponse {
-            devices: filtered_devices,
-            total,
-        }),
+        [(header::CONTENT_TYPE, "application/json")],
+        Body::from_stream(device_list_chunks(devices, total, unmapped)),
     )
+        .into_response()
+}
+
+/// The pieces of a `DeviceListResponse` body; each chunk of devices is serialized
+/// only when the response body polls for it.
+fn device_list_chunks(
+    devices: Vec<DeviceInfo>,
+    total: usize,
+    unmapped: usize,
+) -> impl futures::Stream<Item = Result<Bytes, serde_json::Error>> {
+    let chunk_count = devices.len().div_ceil(STREAM_CHUNK_SIZE);
+    let chunks = futures::stream::iter(0..chunk_count).map(move |i| {
+        let start = i * STREAM_CHUNK_SIZE;
+        let chunk = &devices[start..(start + STREAM_CHUNK_SIZE).min(devices.len())];
+        let mut buf = Vec::new();
+        for (j, device) in chunk.iter().enumerate() {
+            if i > 0 || j > 0 {
+                buf.push(b',');
+            }
+            serde_json::to_writer(&mut buf, device).inspect_err(|e| {
+                warn!("API: Failed to serialize device {}: {}", device.key, e);
+            })?;
+        }
+        Ok(Bytes::from(buf))
+    });
+
+    futures::stream::once(async { Ok(Bytes::from_static(b"{\"devices\":[")) })
+        .chain(chunks)
+        .chain(futures::stream::once(async move {
+            Ok(Bytes::from(format!("],\"total\":{total},\"unmapped\":{unmapped}}}")))
+        }))
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks `Authorization: Bearer` against `SMARTHOME_API_TOKEN` on everything
+/// but `/health`, `/ready` and `/metrics`, so probes and scrapers keep working
+/// without the token.
+async fn require_token(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let Some(expected) = state.api_token.as_deref() else {
+        return next.run(request).await;
+    };
+    if matches!(request.uri().path(), "/health" | "/ready" | "/metrics") {
+        return next.run(request).await;
+    }
+
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+
+    if constant_time_eq(provided.trim().as_bytes(), expected.as_bytes()) {
+        next.run(request).await
+    } else {
+        warn!("API: Rejected unauthenticated request to {}", request.uri().path());
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(ErrorResponse {
+                error: "Invalid or missing bearer token".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+async fn require_admin(State(state): State<ApiState>, request: Request, next: Next) -> Response {
+    let Some(expected) = state.admin_token.as_deref() else {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ErrorResponse {
+                error: "Admin endpoints are disabled (set SMARTHOME_ADMIN_TOKEN)".to_string(),
+            }),
+        )
+            .into_response();
+    };
+
+    let provided = request
+        .headers()
+        .get("x-admin-token")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+
+    if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        next.run(request).await
+    } else {
+        warn!("API: Rejected admin request to {}", request.uri().path());
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(ErrorResponse {
+                error: "Invalid or missing X-Admin-Token".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+/// Zero-pads numeric page numbers so `2` and `02` address the same page.
+fn normalize_page(page: String) -> String {
+    page.parse::<u8>().map_or(page, |n| format!("{n:02}"))
+}
+
+/// Rejects percentages above 100 that `u8` deserialization happily accepts.
+fn validate_percent(field: &str, value: u8) -> Result<(), String> {
+    if value > 100 {
+        Err(format!("{field} must be between 0 and 100, got {value}"))
+    } else {
+        Ok(())
+    }
 }
 
-fn should_filter_device(_device: &Device) -> bool {
-    false
+/// Controllable devices mapped `READONLY` only mirror something on the visu
+/// (status displays and the like) and are left out of listings. Sensors are
+/// read-only by nature and stay.
+fn should_filter_device(device: &Device, mapper: &CommandMapper) -> bool {
+    !device.type_.is_sensor() && mapper.is_readonly(&device.id, &device.page)
 }
 
 async fn get_device(
@@ -160,7 +747,7 @@ async fn get_device(
 ) -> impl IntoResponse {
     match state.state_manager.get_device(&key).await {
         Some(device) => {
-            let info = DeviceInfo::from(&device);
+            let info = DeviceInfo::new(&device, &state.state_manager);
             (StatusCode::OK, Json(info)).into_response()
         }
         None => (
@@ -179,8 +766,8 @@ async fn get_device_state(
 ) -> impl IntoResponse {
     match state.state_manager.get_device(&key).await {
         Some(device) => {
-            let info = DeviceInfo::from(&device);
-            (StatusCode::OK, Json(info.state)).into_response()
+            let info = DeviceStateInfo::from(&device.state);
+            (StatusCode::OK, Json(info)).into_response()
         }
         None => (
             StatusCode::NOT_FOUND,
@@ -192,14 +779,131 @@ async fn get_device_state(
     }
 }
 
+/// Reconciles the device to the submitted state, sending only what differs, and
+/// answers with the state the device ended up in.
+async fn put_device_state(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(desired): Json<DeviceStateInfo>,
+) -> impl IntoResponse {
+    info!("API: Desired state for {}: {:?}", key, desired);
+
+    let Some(device) = state.state_manager.get_device(&key).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Device not found: {key}"),
+            }),
+        )
+            .into_response();
+    };
+
+    let actions = match reconcile_actions(&DeviceStateInfo::from(&device.state), &desired) {
+        Ok(actions) => actions,
+        Err(e) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(ErrorResponse {
+                    error: format!("{key} ({:?}): {e}", device.type_),
+                }),
+            )
+                .into_response()
+        }
+    };
+    for action in actions {
+        if let Err(e) = action.check_range() {
+            return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e.to_string() })).into_response();
+        }
+        if let Err(e) = state.state_manager.perform_action(&key, action).await {
+            warn!("API: Setting state failed for {}: {}", key, e);
+            return action_error_response(&e, "Setting state failed");
+        }
+    }
+
+    match state.state_manager.get_device(&key).await {
+        Some(device) => (StatusCode::OK, Json(DeviceStateInfo::from(&device.state))).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Device not found: {key}"),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+/// Actions that take a device from `current` to `desired`; empty when it's already
+/// there. Errs when `desired` is a different kind of state or one that can't be set.
+fn reconcile_actions(current: &DeviceStateInfo, desired: &DeviceStateInfo) -> Result<Vec<DeviceAction>, String> {
+    let on_off = |on: bool| if on { DeviceAction::On } else { DeviceAction::Off };
+    match (current, desired) {
+        (DeviceStateInfo::OnOff { on: is_on }, DeviceStateInfo::OnOff { on })
+        | (DeviceStateInfo::Outlet { on: is_on, .. }, DeviceStateInfo::Outlet { on, .. }) => {
+            Ok(if is_on == on { vec![] } else { vec![on_off(*on)] })
+        }
+        (
+            DeviceStateInfo::Brightness { on: is_on, level: current_level },
+            DeviceStateInfo::Brightness { on, level },
+        ) => Ok(if !on {
+            if *is_on { vec![DeviceAction::Off] } else { vec![] }
+        } else if current_level != level {
+            vec![DeviceAction::Brightness { level: *level }]
+        } else if !is_on {
+            vec![DeviceAction::On]
+        } else {
+            vec![]
+        }),
+        (
+            DeviceStateInfo::WindowCovering { position: current_position, tilt: current_tilt, .. },
+            DeviceStateInfo::WindowCovering { position, tilt, .. },
+        ) => {
+            let mut actions = Vec::new();
+            if current_position != position {
+                actions.push(DeviceAction::SetPosition { position: *position });
+            }
+            // Leaving `tilt` out keeps the current angle.
+            if let Some(tilt) = tilt.filter(|tilt| *current_tilt != Some(*tilt)) {
+                actions.push(DeviceAction::Tilt { tilt });
+            }
+            Ok(actions)
+        }
+        (DeviceStateInfo::FanSpeed { speed: current_speed }, DeviceStateInfo::FanSpeed { speed }) => {
+            Ok(if current_speed == speed { vec![] } else { vec![DeviceAction::FanSpeed { speed: *speed }] })
+        }
+        (DeviceStateInfo::Temperature { .. }, DeviceStateInfo::Temperature { .. })
+        | (DeviceStateInfo::Humidity { .. }, DeviceStateInfo::Humidity { .. }) => {
+            Err("this state is read-only".to_string())
+        }
+        _ => Err(format!("expects a {} state", state_kind(current))),
+    }
+}
+
+fn state_kind(state: &DeviceStateInfo) -> &'static str {
+    match state {
+        DeviceStateInfo::OnOff { .. } => "onoff",
+        DeviceStateInfo::Brightness { .. } => "brightness",
+        DeviceStateInfo::WindowCovering { .. } => "windowcovering",
+        DeviceStateInfo::Temperature { .. } => "temperature",
+        DeviceStateInfo::Humidity { .. } => "humidity",
+        DeviceStateInfo::FanSpeed { .. } => "fanspeed",
+        DeviceStateInfo::Outlet { .. } => "outlet",
+    }
+}
+
+/// With `?confirm=true` (or `wait_confirm`), answers only after the gateway reports
+/// the new state, or with 202 and the optimistic state once `confirm_timeout` passes.
 async fn toggle_device(
     State(state): State<ApiState>,
     Path(key): Path<String>,
+    Query(query): Query<ConfirmQuery>,
     Json(payload): Json<ToggleRequest>,
 ) -> impl IntoResponse {
     info!("API: Toggle request for {} to {}", key, payload.on);
 
-    match state.state_manager.toggle_device(&key, payload.on).await {
+    let action = if payload.on { DeviceAction::On } else { DeviceAction::Off };
+    let confirm = query.confirm || payload.wait_confirm;
+    match state.state_manager.perform_action(&key, action).await {
+        Ok(()) if confirm => confirmed_toggle_response(&state, &key, payload.on).await,
         Ok(()) => (
             StatusCode::OK,
             Json(serde_json::json!({"status": "ok", "device": key, "on": payload.on})),
@@ -207,17 +911,71 @@ async fn toggle_device(
             .into_response(),
         Err(e) => {
             warn!("API: Failed to toggle device {}: {}", key, e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to toggle device: {e}"),
-                }),
-            )
-                .into_response()
+            action_error_response(&e, "Failed to toggle device")
         }
     }
 }
 
+async fn confirmed_toggle_response(state: &ApiState, key: &str, on: bool) -> Response {
+    let readable = state
+        .state_manager
+        .get_device(key)
+        .await
+        .is_some_and(|d| !d.type_.is_sensor() && d.type_ != DeviceType::Scene);
+    if !readable {
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "device": key, "on": on, "confirmed": false})),
+        )
+            .into_response();
+    }
+
+    let confirmed = state.state_manager.confirm_on(key, on).await.unwrap_or_else(|e| {
+        warn!("API: Could not confirm {}: {}", key, e);
+        false
+    });
+    if confirmed {
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "device": key, "on": on, "confirmed": true})),
+        )
+            .into_response();
+    }
+
+    let optimistic = state.state_manager.get_device(key).await.map(|d| DeviceStateInfo::from(&d.state));
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "status": "pending",
+            "device": key,
+            "on": on,
+            "confirmed": false,
+            "state": optimistic,
+        })),
+    )
+        .into_response()
+}
+
+async fn toggle_by_index(
+    State(state): State<ApiState>,
+    Path((page, index)): Path<(String, String)>,
+    query: Query<ConfirmQuery>,
+    Json(payload): Json<ToggleRequest>,
+) -> impl IntoResponse {
+    let page = normalize_page(page);
+    let Some(key) = state.state_manager.find_key_by_index(&page, &index).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("No device with index {index} on page {page}"),
+            }),
+        )
+            .into_response();
+    };
+
+    toggle_device(State(state), Path(key), query, Json(payload)).await.into_response()
+}
+
 async fn set_blind_position(
     State(state): State<ApiState>,
     Path(key): Path<String>,
@@ -225,7 +983,12 @@ async fn set_blind_position(
 ) -> impl IntoResponse {
     info!("API: Blind position request for {} to {}%", key, payload.position);
 
-    match state.state_manager.set_blind_position(&key, payload.position).await {
+    if let Err(error) = validate_percent("position", payload.position) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response();
+    }
+
+    let action = DeviceAction::SetPosition { position: payload.position };
+    match state.state_manager.perform_action(&key, action).await {
         Ok(()) => (
             StatusCode::OK,
             Json(serde_json::json!({"status": "ok", "device": key, "position": payload.position})),
@@ -233,13 +996,713 @@ async fn set_blind_position(
             .into_response(),
         Err(e) => {
             warn!("API: Failed to set blind position {}: {}", key, e);
+            action_error_response(&e, "Failed to set blind position")
+        }
+    }
+}
+
+async fn set_brightness(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(payload): Json<BrightnessRequest>,
+) -> impl IntoResponse {
+    info!("API: Brightness request for {} to {}%", key, payload.level);
+
+    if let Err(error) = validate_percent("level", payload.level) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response();
+    }
+    let dimmable = state
+        .state_manager
+        .get_device(&key)
+        .await
+        .is_some_and(|device| state.state_manager.command_mapper().is_dimmable(&device));
+    if !dimmable {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Dimmer not found: {key}"),
+            }),
+        )
+            .into_response();
+    }
+
+    let action = DeviceAction::Brightness { level: payload.level };
+    match state.state_manager.perform_action(&key, action).await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "device": key, "level": payload.level})),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("API: Failed to set brightness {}: {}", key, e);
+            action_error_response(&e, "Failed to set brightness")
+        }
+    }
+}
+
+async fn set_fan_speed(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(payload): Json<FanSpeedRequest>,
+) -> impl IntoResponse {
+    info!("API: Fan speed request for {} to {}", key, payload.speed);
+
+    let Some(device) = state.state_manager.get_device(&key).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Device not found: {key}"),
+            }),
+        )
+            .into_response();
+    };
+    if device.type_ != DeviceType::Fan {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("{key} is a {:?}, not a fan", device.type_),
+            }),
+        )
+            .into_response();
+    }
+
+    let action = DeviceAction::FanSpeed { speed: payload.speed };
+    match state.state_manager.perform_action(&key, action).await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "device": key, "speed": payload.speed})),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("API: Failed to set fan speed {}: {}", key, e);
+            action_error_response(&e, "Failed to set fan speed")
+        }
+    }
+}
+
+async fn set_blind_tilt(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(payload): Json<TiltRequest>,
+) -> impl IntoResponse {
+    info!("API: Tilt request for {} to {}%", key, payload.tilt);
+
+    if let Err(error) = validate_percent("tilt", payload.tilt) {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response();
+    }
+    let Some(device) = state.state_manager.get_device(&key).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Device not found: {key}"),
+            }),
+        )
+            .into_response();
+    };
+    if state.state_manager.capabilities(&device).tilt.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("{key}: tilt not supported"),
+            }),
+        )
+            .into_response();
+    }
+
+    let action = DeviceAction::Tilt { tilt: payload.tilt };
+    match state.state_manager.perform_action(&key, action).await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "device": key, "tilt": payload.tilt})),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("API: Failed to set tilt {}: {}", key, e);
+            action_error_response(&e, "Failed to set tilt")
+        }
+    }
+}
+
+async fn trigger_scene(State(state): State<ApiState>, Path(key): Path<String>) -> impl IntoResponse {
+    info!("API: Trigger request for {}", key);
+
+    let Some(device) = state.state_manager.get_device(&key).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Device not found: {key}"),
+            }),
+        )
+            .into_response();
+    };
+    if device.type_ != DeviceType::Scene {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("{key} is a {:?}, not a scene", device.type_),
+            }),
+        )
+            .into_response();
+    }
+
+    match state.state_manager.trigger_scene(&key).await {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({"status": "triggered", "device": key}))).into_response(),
+        Err(e) => {
+            warn!("API: Failed to trigger scene {}: {}", key, e);
+            action_error_response(&e, "Failed to trigger scene")
+        }
+    }
+}
+
+/// `/device/:key/trigger` for scene panels; anything but a scene is not found here.
+async fn activate_scene(State(state): State<ApiState>, Path(key): Path<String>) -> Response {
+    let is_scene = state
+        .state_manager
+        .get_device(&key)
+        .await
+        .is_some_and(|device| device.type_ == DeviceType::Scene);
+    if !is_scene {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Scene not found: {key}"),
+            }),
+        )
+            .into_response();
+    }
+    trigger_scene(State(state), Path(key)).await.into_response()
+}
+
+async fn device_action(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(action): Json<DeviceAction>,
+) -> impl IntoResponse {
+    info!("API: Action request for {}: {:?}", key, action);
+
+    if let Err(e) = action.check_range() {
+        return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e.to_string() })).into_response();
+    }
+    if state.state_manager.get_device(&key).await.is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("Device not found: {key}"),
+            }),
+        )
+            .into_response();
+    }
+
+    match state.state_manager.perform_action(&key, action).await {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({"status": "ok", "device": key}))).into_response(),
+        Err(e) => {
+            warn!("API: Action failed for {}: {}", key, e);
+            action_error_response(&e, "Action failed")
+        }
+    }
+}
+
+/// 409 when the action doesn't fit the device type, 400 for a value the gateway
+/// can't encode, 500 for anything else.
+fn action_error_response(error: &anyhow::Error, context: &str) -> Response {
+    let status = if error.downcast_ref::<UnsupportedAction>().is_some() {
+        StatusCode::CONFLICT
+    } else if error.downcast_ref::<ValueOutOfRange>().is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    (
+        status,
+        Json(ErrorResponse {
+            error: format!("{context}: {error}"),
+        }),
+    )
+        .into_response()
+}
+
+async fn effective_config(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(serde_json::to_value(&*state.effective_config).unwrap_or_default())
+}
+
+async fn rediscover(State(state): State<ApiState>) -> impl IntoResponse {
+    info!("API: Rediscovery request");
+    match state.state_manager.rediscover().await {
+        Ok(devices) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "devices": devices})),
+        )
+            .into_response(),
+        Err(e) => {
+            let status = if e.downcast_ref::<DiscoveryInProgress>().is_some() {
+                StatusCode::CONFLICT
+            } else {
+                warn!("API: Rediscovery failed: {:#}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            (status, Json(ErrorResponse { error: e.to_string() })).into_response()
+        }
+    }
+}
+
+async fn reload_mappings(State(state): State<ApiState>) -> impl IntoResponse {
+    info!("API: Mappings reload request");
+    match state.state_manager.reload_mappings(MAPPINGS_PATH).await {
+        Ok(total) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "mappings": total})),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("API: Mappings reload failed, keeping current mappings: {:#}", e);
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(ErrorResponse { error: format!("{e:#}") }),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn set_device_type(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+    Json(payload): Json<DeviceTypeRequest>,
+) -> impl IntoResponse {
+    let type_: DeviceType = match payload.type_.parse() {
+        Ok(type_) => type_,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response(),
+    };
+    info!("API: Type override request for {} to {:?}", key, type_);
+
+    match state.state_manager.set_device_type(&key, type_).await {
+        Ok(device) => {
+            let info = DeviceInfo::new(&device, &state.state_manager);
+            (StatusCode::OK, Json(info)).into_response()
+        }
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn identify_device(
+    State(state): State<ApiState>,
+    Path(key): Path<String>,
+) -> impl IntoResponse {
+    info!("API: Identify request for {}", key);
+
+    match state.state_manager.get_device(&key).await {
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse {
+                    error: format!("Device not found: {key}"),
+                }),
+            )
+                .into_response();
+        }
+        Some(device) if matches!(device.type_, DeviceType::Scene) || device.type_.is_sensor() => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("Identify not supported for {:?} devices", device.type_),
+                }),
+            )
+                .into_response();
+        }
+        Some(_) => {}
+    }
+
+    match state.state_manager.identify_device(&key).await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "device": key})),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("API: Failed to identify device {}: {}", key, e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
-                    error: format!("Failed to set blind position: {e}"),
+                    error: format!("Failed to identify device: {e}"),
                 }),
             )
                 .into_response()
         }
     }
 }
+
+async fn toggle_page(
+    State(state): State<ApiState>,
+    Path(page): Path<String>,
+    Json(payload): Json<ToggleRequest>,
+) -> impl IntoResponse {
+    let page = normalize_page(page);
+    info!("API: Page toggle request for page {} to {}", page, payload.on);
+
+    let results = state.state_manager.toggle_page(&page, payload.on).await;
+    if results.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: format!("No switchable devices on page: {page}"),
+            }),
+        )
+            .into_response();
+    }
+
+    let results: Vec<CommandResult> = results
+        .into_iter()
+        .map(|(key, result)| CommandResult::from_result(key, &result))
+        .collect();
+    let failed = results.iter().filter(|r| !r.success).count();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": if failed == 0 { "ok" } else { "partial" },
+            "page": page,
+            "on": payload.on,
+            "failed": failed,
+            "results": results,
+        })),
+    )
+        .into_response()
+}
+
+async fn run_batch(State(state): State<ApiState>, Json(payload): Json<BatchRequest>) -> impl IntoResponse {
+    info!("API: Batch request with {} commands", payload.commands.len());
+    if payload.commands.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: "Batch has no commands".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    let actions = payload
+        .commands
+        .into_iter()
+        .map(|command| (command.key, command.action.into()))
+        .collect();
+    let results: Vec<CommandResult> = state
+        .state_manager
+        .perform_actions(actions)
+        .await
+        .into_iter()
+        .map(|(key, result)| CommandResult::from_result(key, &result))
+        .collect();
+    let failed = results.iter().filter(|r| !r.success).count();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "status": if failed == 0 { "ok" } else { "partial" },
+            "failed": failed,
+            "results": results,
+        })),
+    )
+        .into_response()
+}
+
+/// Streams state changes as Server-Sent Events until the client disconnects.
+/// Answers 503 once `max_event_clients` streams are open.
+async fn stream_events(State(state): State<ApiState>, Query(query): Query<EventsQuery>) -> impl IntoResponse {
+    let filter = match state.state_manager.event_filter(query.key.as_deref(), query.type_.as_deref()).await {
+        Ok(filter) => filter,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error: e.to_string() })).into_response(),
+    };
+    let subscription = match state.state_manager.subscribe_client(filter) {
+        Ok(subscription) => subscription,
+        Err(e @ TooManySubscribers { .. }) => {
+            warn!("API: Rejecting event stream: {}", e);
+            return (StatusCode::SERVICE_UNAVAILABLE, Json(ErrorResponse { error: e.to_string() })).into_response();
+        }
+    };
+    info!("API: Event stream opened");
+
+    let stream = futures::stream::unfold(subscription, |mut subscription: EventSubscription| async move {
+        let event = match subscription.recv().await {
+            Ok(event) => sse_event(&event),
+            // A slow client missed some events; tell it so it can re-read `/devices`.
+            Err(RecvError::Lagged(missed)) => Event::default()
+                .event("lagged")
+                .data(serde_json::json!({"missed": missed, "timestamp": Utc::now()}).to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok::<_, Infallible>(event), subscription))
+    });
+    // Open streams would otherwise hold up the graceful shutdown indefinitely.
+    let stream = stream.take_until(shutdown_requested(state.shutdown.clone()));
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// One SSE message per event, named after the event kind. State changes carry the
+/// same state shape as `GET /device/:key/state`.
+fn sse_event(event: &StateEvent) -> Event {
+    let mut data = match event {
+        StateEvent::DeviceStateChanged { key, device_type, state } => serde_json::json!({
+            "event": "device_state_changed",
+            "key": key,
+            "device_type": device_type,
+            "state": DeviceStateInfo::from(state),
+        }),
+        other => serde_json::to_value(other).unwrap_or_default(),
+    };
+    data["timestamp"] = serde_json::json!(Utc::now());
+    let name = data["event"].as_str().unwrap_or("message").to_string();
+    Event::default().event(name).data(data.to_string())
+}
+
+async fn export_registry(State(state): State<ApiState>) -> impl IntoResponse {
+    let mut devices = state.state_manager.get_all_devices().await;
+    devices.sort_by_key(Device::key);
+
+    (StatusCode::OK, Json(devices))
+}
+
+async fn import_registry(
+    State(state): State<ApiState>,
+    Json(devices): Json<Vec<Device>>,
+) -> impl IntoResponse {
+    info!("API: Import request with {} devices", devices.len());
+
+    match state.state_manager.replace_devices(devices).await {
+        Ok(count) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"status": "ok", "imported": count})),
+        )
+            .into_response(),
+        Err(e) => {
+            warn!("API: Rejected registry import: {}", e);
+            (
+                StatusCode::BAD_REQUEST,
+                Json(ErrorResponse {
+                    error: format!("Failed to import registry: {e}"),
+                }),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn validate_command(Json(payload): Json<ValidateCommandRequest>) -> impl IntoResponse {
+    let response = match payload.command.parse::<KnxCommand>() {
+        Ok(command) => ValidateCommandResponse {
+            valid: true,
+            command: Some(command),
+            error: None,
+        },
+        Err(error) => ValidateCommandResponse {
+            valid: false,
+            command: None,
+            error: Some(error),
+        },
+    };
+    Json(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn test_validate_percent() {
+        assert!(validate_percent("position", 0).is_ok());
+        assert!(validate_percent("position", 100).is_ok());
+        assert_eq!(
+            validate_percent("position", 101),
+            Err("position must be between 0 and 100, got 101".to_string())
+        );
+    }
+
+    #[test]
+    fn test_should_filter_device() {
+        let mapper = CommandMapper::from_toml(
+            "[lights]\n\"Single_1_page02\" = \"READONLY\"\n\"Single_2_page02\" = \"1+01+00+02\"\n\
+             [sensors]\n\"Temp_1_page02\" = \"READONLY\"\n",
+        )
+        .unwrap();
+        let device = |id: &str, type_| Device::new(id.to_string(), id.to_string(), type_, "02".to_string(), "1".to_string());
+        assert!(should_filter_device(&device("Single_1", DeviceType::Light), &mapper));
+        assert!(!should_filter_device(&device("Single_2", DeviceType::Light), &mapper));
+        assert!(!should_filter_device(&device("Temp_1", DeviceType::TemperatureSensor), &mapper));
+    }
+
+    fn test_state_manager(mappings: &str) -> Arc<StateManager> {
+        let client = Arc::new(crate::knx_client::KnxClient::new(Arc::new(crate::config::KnxConfig::test_default()), true).unwrap());
+        let mapper = Arc::new(CommandMapper::from_toml(mappings).unwrap());
+        Arc::new(StateManager::new(client, mapper, crate::config::BridgeConfig::default()))
+    }
+
+    #[tokio::test]
+    async fn test_probes_skip_token() {
+        use tower::ServiceExt;
+
+        let config = crate::config::Config {
+            knx: crate::config::KnxConfig::test_default(),
+            homekit: crate::config::HomeKitConfig {
+                name: "Bridge".to_string(),
+                pin: "031-45-154".to_string(),
+                port: 8080,
+                bind_address: std::net::IpAddr::from([127, 0, 0, 1]),
+                debug_endpoints: false,
+                metrics_endpoint: false,
+                admin_token: None,
+                api_token: Some("bearer-s3cret".to_string()),
+            },
+            polling: crate::config::PollingConfig::default(),
+            bridge: crate::config::BridgeConfig::default(),
+            mqtt: None,
+        };
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let state = ApiState {
+            state_manager: test_state_manager(""),
+            admin_token: None,
+            api_token: Some(Arc::from("bearer-s3cret")),
+            effective_config: Arc::new(config.effective()),
+            shutdown,
+        };
+        let app = Router::new()
+            .route("/ready", get(|| async { "ready" }))
+            .route("/devices", get(|| async { "devices" }))
+            .layer(middleware::from_fn_with_state(state, require_token));
+
+        let status = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder().uri(path).body(Body::empty()).unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+        assert_eq!(status("/ready").await, StatusCode::OK);
+        assert_eq!(status("/devices").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_device_list() {
+        let state_manager = test_state_manager("");
+        let lights: Vec<Device> = (0..STREAM_THRESHOLD + 7)
+            .map(|i| {
+                Device::new(format!("Single_{i}"), format!("Light {i}"), DeviceType::Light, "02".to_string(), i.to_string())
+            })
+            .collect();
+        let devices = || -> Vec<DeviceInfo> { lights.iter().map(|d| DeviceInfo::new(d, &state_manager)).collect() };
+
+        let mut streamed = Vec::new();
+        let mut chunks = std::pin::pin!(device_list_chunks(devices(), 300, 12));
+        while let Some(chunk) = chunks.next().await {
+            streamed.extend_from_slice(&chunk.unwrap());
+        }
+        let buffered = serde_json::to_vec(&DeviceListResponse { devices: devices(), total: 300, unmapped: 12 }).unwrap();
+        assert_eq!(String::from_utf8(streamed).unwrap(), String::from_utf8(buffered).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_device_listing_unmapped() {
+        let state_manager = test_state_manager(
+            "[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n\"Single_3_page02\" = \"READONLY\"\n",
+        );
+        let device = |id: &str, type_, page: &str| {
+            Device::new(id.to_string(), id.to_string(), type_, page.to_string(), "1".to_string())
+        };
+        state_manager
+            .replace_devices(vec![
+                device("Single_1", DeviceType::Light, "02"),
+                device("Single_2", DeviceType::Light, "02"),
+                device("Single_3", DeviceType::Light, "02"),
+                device("Temp_1", DeviceType::TemperatureSensor, "02"),
+                device("Single_4", DeviceType::Switch, "03"),
+            ])
+            .await
+            .unwrap();
+        let list = |query: DeviceListQuery| {
+            let state_manager = state_manager.clone();
+            async move {
+                let listing = device_listing(&state_manager, &query).await.unwrap();
+                let keys: Vec<String> = listing.devices.into_iter().map(|d| d.key).collect();
+                (keys, listing.unmapped)
+            }
+        };
+
+        // The READONLY light is hidden and the sensor is read-only by nature.
+        let (keys, unmapped) = list(DeviceListQuery::default()).await;
+        assert_eq!(keys, ["Single_1_page02", "Single_2_page02", "Single_4_page03", "Temp_1_page02"]);
+        assert_eq!(unmapped, 2);
+
+        let (keys, unmapped) = list(DeviceListQuery { mapped: Some(true), ..DeviceListQuery::default() }).await;
+        assert_eq!(keys, ["Single_1_page02"]);
+        assert_eq!(unmapped, 2);
+        let (keys, unmapped) = list(DeviceListQuery {
+            mapped: Some(false),
+            page: Some("2".to_string()),
+            ..DeviceListQuery::default()
+        })
+        .await;
+        assert_eq!(keys, ["Single_2_page02", "Temp_1_page02"]);
+        assert_eq!(unmapped, 1);
+        assert_eq!(list(DeviceListQuery { type_: Some("switch".to_string()), ..DeviceListQuery::default() }).await, (vec!["Single_4_page03".to_string()], 1));
+    }
+
+    #[test]
+    fn test_batch_request() {
+        let request: BatchRequest = serde_json::from_str(
+            r#"{"commands": [
+                {"key": "Single_1_page02", "action": "toggle", "on": true},
+                {"key": "Double3_1_page02", "action": "position", "position": 50},
+                {"key": "Dimmer_1_page02", "action": "brightness", "level": 30}
+            ]}"#,
+        )
+        .unwrap();
+        let actions: Vec<(String, DeviceAction)> =
+            request.commands.into_iter().map(|c| (c.key, c.action.into())).collect();
+        assert_eq!(
+            actions,
+            vec![
+                ("Single_1_page02".to_string(), DeviceAction::On),
+                ("Double3_1_page02".to_string(), DeviceAction::SetPosition { position: 50 }),
+                ("Dimmer_1_page02".to_string(), DeviceAction::Brightness { level: 30 }),
+            ]
+        );
+        assert!(serde_json::from_str::<BatchRequest>(r#"{"commands": [{"key": "x", "action": "fly"}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_actions() {
+        let dimmer: DeviceStateInfo = serde_json::from_str(r#"{"type":"brightness","on":true,"level":40}"#).unwrap();
+        assert_eq!(reconcile_actions(&dimmer, &dimmer), Ok(vec![]));
+
+        let off = DeviceStateInfo::Brightness { on: false, level: 40 };
+        assert_eq!(reconcile_actions(&dimmer, &off), Ok(vec![DeviceAction::Off]));
+        assert_eq!(reconcile_actions(&off, &dimmer), Ok(vec![DeviceAction::On]));
+        let brighter = DeviceStateInfo::Brightness { on: true, level: 80 };
+        assert_eq!(reconcile_actions(&off, &brighter), Ok(vec![DeviceAction::Brightness { level: 80 }]));
+
+        let blind: DeviceStateInfo = serde_json::from_str(r#"{"type":"windowcovering","position":30}"#).unwrap();
+        let current = DeviceStateInfo::WindowCovering { position: 100, obstruction: true, tilt: Some(50) };
+        assert_eq!(reconcile_actions(&current, &blind), Ok(vec![DeviceAction::SetPosition { position: 30 }]));
+        let tilted = DeviceStateInfo::WindowCovering { position: 100, obstruction: false, tilt: Some(20) };
+        assert_eq!(reconcile_actions(&current, &tilted), Ok(vec![DeviceAction::Tilt { tilt: 20 }]));
+
+        assert_eq!(
+            reconcile_actions(&current, &DeviceStateInfo::OnOff { on: true }),
+            Err("expects a windowcovering state".to_string())
+        );
+        let temp = DeviceStateInfo::Temperature { celsius: 21.0 };
+        assert!(reconcile_actions(&temp, &temp).is_err());
+    }
+}
@@ -1,40 +1,126 @@
 use anyhow::{Context, Result};
 use headless_chrome::{Browser, LaunchOptions};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
-use std::fmt::Write;
 use std::fs;
-use std::time::Duration;
-use tracing::info;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::command_mapper::{CommandMapper, DeviceMappings};
+use crate::knx_client::{inject_stealth, wait_for_login_state, LoginState};
+
+/// Mapping sections a discovered icon can be assigned to with `SMARTHOME_ICON_MAP`.
+const ICON_SECTIONS: &[&str] = &["lights", "switches", "dimmers", "blinds", "ventilation", "scenes", "sensors"];
+
+/// Built-in icon rules: `icon-1` is the visu's lightbulb, `icon-3` its socket/relay.
+const DEFAULT_ICON_MAP: &[(&str, &str)] = &[
+    ("icon-1", "lights"),
+    ("icon-3", "switches"),
+    ("icon-11", "scenes"),
+    ("icon-45", "ventilation"),
+    ("icon-76", "scenes"),
+];
+
+/// Result of comparing a fresh discovery against an existing mappings file.
+pub struct MappingDiff {
+    /// Discovered keys without a mapping, with their suggested section and command.
+    pub added: Vec<(String, String, String)>,
+    /// Mapped keys that discovery no longer finds.
+    pub removed: Vec<String>,
+    pub unchanged: Vec<String>,
+}
 
 pub struct AutoDiscovery {
     base_url: String,
+    page_path: String,
     #[allow(dead_code)]
     username: String,
     #[allow(dead_code)]
     password: String,
     headless: bool,
+    max_duration: Option<Duration>,
+    login_wait: Duration,
+    /// Icon class → mapping section, checked in order; configured entries come first.
+    icon_map: Vec<(String, &'static str)>,
 }
 
 impl AutoDiscovery {
     pub fn new(headless: bool) -> Result<Self> {
         let base_url = env::var("SMARTHOME_BASE_URL")
             .context("SMARTHOME_BASE_URL not set in .env")?;
+        let base_url = crate::config::interpolate_env(&base_url)
+            .context("Invalid SMARTHOME_BASE_URL")?;
+        let page_path = crate::config::env_path("SMARTHOME_PAGE_PATH", crate::config::DEFAULT_PAGE_PATH)?;
         let username = env::var("SMARTHOME_USERNAME")
             .context("SMARTHOME_USERNAME not set in .env")?;
         let password = env::var("SMARTHOME_PASSWORD")
             .context("SMARTHOME_PASSWORD not set in .env")?;
+        let max_duration = crate::config::env_secs("SMARTHOME_DISCOVERY_TIMEOUT_SECS")?;
+        let login_wait = crate::config::env_secs("SMARTHOME_LOGIN_WAIT_SECS")?
+            .unwrap_or(crate::config::DEFAULT_LOGIN_WAIT);
+        let icon_map = parse_icon_map(&crate::config::env_list("SMARTHOME_ICON_MAP").unwrap_or_default())
+            .context("Invalid SMARTHOME_ICON_MAP")?;
 
         Ok(Self {
             base_url,
+            page_path,
             username,
             password,
             headless,
+            max_duration,
+            login_wait,
+            icon_map,
         })
     }
 
-    #[allow(clippy::too_many_lines)]
     pub fn discover_all_mappings(&self, _pages: &[String]) -> Result<HashMap<String, String>> {
+        let all_mappings = self.collect_mappings()?;
+
+        Self::save_mappings(&all_mappings, &self.icon_map)?;
+
+        Ok(all_mappings)
+    }
+
+    /// Discovers the current device set and compares it against an existing
+    /// mappings file without writing anything.
+    pub fn discover_diff(&self, mappings_path: &str) -> Result<MappingDiff> {
+        let current: BTreeSet<String> = CommandMapper::load(mappings_path)
+            .with_context(|| format!("Failed to load {mappings_path}"))?
+            .all_keys()
+            .into_iter()
+            .collect();
+
+        let discovered: BTreeMap<String, (String, String)> = self
+            .collect_mappings()?
+            .into_iter()
+            .map(|(key, command)| {
+                let category = Self::categorize(&key, &self.icon_map);
+                let command = if category == "sensors" { "READONLY".to_string() } else { command };
+                (Self::clean_key(&key), (category.to_string(), command))
+            })
+            .collect();
+
+        let added = discovered
+            .iter()
+            .filter(|(key, _)| !current.contains(*key))
+            .map(|(key, (category, command))| (key.clone(), category.clone(), command.clone()))
+            .collect();
+        let removed = current
+            .iter()
+            .filter(|key| !discovered.contains_key(*key))
+            .cloned()
+            .collect();
+        let unchanged = discovered
+            .keys()
+            .filter(|key| current.contains(*key))
+            .cloned()
+            .collect();
+
+        Ok(MappingDiff { added, removed, unchanged })
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn collect_mappings(&self) -> Result<HashMap<String, String>> {
         info!("🔍 Starting auto-discovery mode...");
         info!("Auto-detecting all pages with devices...");
         info!("");
@@ -115,42 +201,25 @@ impl AutoDiscovery {
 
         let tab = browser.new_tab().context("Failed to create tab")?;
 
-        tab.evaluate(
-            "
-            Object.defineProperty(navigator, 'webdriver', {get: () => undefined});
-
-            window.chrome = {
-                runtime: {},
-                loadTimes: function() {},
-                csi: function() {},
-                app: {}
-            };
-
-            Object.defineProperty(navigator, 'plugins', {
-                get: () => [1, 2, 3, 4, 5]
-            });
-
-            Object.defineProperty(navigator, 'languages', {
-                get: () => ['en-US', 'en', 'de']
-            });
-
-            const originalQuery = window.navigator.permissions.query;
-            window.navigator.permissions.query = (parameters) => (
-                parameters.name === 'notifications' ?
-                    Promise.resolve({ state: Notification.permission }) :
-                    originalQuery(parameters)
-            );
-            ",
-            false,
-        )
-        .ok();
+        inject_stealth(&tab);
 
         self.login(&tab)?;
 
         let mut consecutive_empty_pages = 0;
+        let started = Instant::now();
 
         for page_num in 1..=99 {
             let page = format!("{page_num:02}");
+
+            if self.max_duration.is_some_and(|max| started.elapsed() >= max) {
+                warn!(
+                    "Discovery time limit reached before page {}, saving {} mappings found so far",
+                    page,
+                    all_mappings.len()
+                );
+                break;
+            }
+
             info!("📄 Discovering devices on page {}...", page);
             let page_mappings = self.discover_page(&tab, &page)?;
 
@@ -172,8 +241,6 @@ impl AutoDiscovery {
 
         info!("✅ Discovery complete! Found {} device mappings", all_mappings.len());
 
-        Self::save_mappings(&all_mappings)?;
-
         Ok(all_mappings)
     }
 
@@ -199,13 +266,11 @@ impl AutoDiscovery {
     fn login(&self, tab: &headless_chrome::Tab) -> Result<()> {
         info!("Navigating to login page...");
 
-        let start_url = format!("{}/visu/index.fcgi?00", self.base_url);
+        let start_url = format!("{}{}?00", self.base_url, self.page_path);
         tab.navigate_to(&start_url)
             .context("Failed to navigate to start URL")?;
 
-        std::thread::sleep(Duration::from_secs(3));
-
-        if Self::is_logged_in(tab) {
+        if wait_for_login_state(tab, self.login_wait) == LoginState::LoggedIn {
             info!("✅ Already logged in! (Session restored from chrome_data/)");
             return Ok(());
         }
@@ -247,7 +312,7 @@ impl AutoDiscovery {
     fn discover_page(&self, tab: &headless_chrome::Tab, page: &str) -> Result<HashMap<String, String>> {
         let mut mappings = HashMap::new();
 
-        let page_url = format!("{}/visu/index.fcgi?{page}", self.base_url);
+        let page_url = format!("{}{}?{page}", self.base_url, self.page_path);
         tab.navigate_to(&page_url)?;
 
         std::thread::sleep(Duration::from_secs(3));
@@ -307,7 +372,7 @@ impl AutoDiscovery {
                         .unwrap_or("");
 
                     if is_shifter {
-                        let device_key = format!("{id}_page{device_page}");
+                        let device_key = CommandMapper::device_key(id, device_page);
 
                         let cmd_up = format!("{index}+01+00+{device_page}");
                         let cmd_stop = format!("{index}+02+00+{device_page}");
@@ -321,7 +386,7 @@ impl AutoDiscovery {
                             name, cmd_up, cmd_stop, cmd_down);
                     } else {
                         let command = format!("{index}+01+00+{device_page}");
-                        let device_key = format!("{id}_page{device_page}");
+                        let device_key = CommandMapper::device_key(id, device_page);
 
                         mappings.insert(format!("{device_key}_{icon_type}"), command.clone());
                         info!("    ✓ {} → {}", name, command);
@@ -333,103 +398,138 @@ impl AutoDiscovery {
         Ok(mappings)
     }
 
-    fn save_mappings(mappings: &HashMap<String, String>) -> Result<()> {
-        info!("💾 Saving mappings to device_mappings_auto.toml...");
-
-        let mut lights = HashMap::new();
-        let mut blinds = HashMap::new();
-        let mut dimmers = HashMap::new();
-        let mut ventilation = HashMap::new();
-        let mut scenes = HashMap::new();
-        let mut sensors = HashMap::new();
-        let mut switches = HashMap::new();
+    /// Strips the `_icon-*` suffix discovery appends to non-blind keys.
+    fn clean_key(key: &str) -> String {
+        key.split("_icon-")
+            .next()
+            .unwrap_or(key)
+            .trim_end_matches('_')
+            .to_string()
+    }
 
-        for (key, command) in mappings {
-            let clean_key = key.split("_icon-").next().unwrap_or(key).to_string();
-
-            if key.contains("Double3") {
-                blinds.insert(clean_key, command.clone());
-            } else if key.contains("ExtendedSlider") {
-                dimmers.insert(clean_key, command.clone());
-            } else if key.contains("icon-45") {
-                ventilation.insert(clean_key, command.clone());
-            } else if key.contains("Szene") || key.contains("Scene") || key.contains("icon-11") || key.contains("icon-76") {
-                scenes.insert(clean_key, command.clone());
-            } else if key.contains("Temp") || key.contains("Datum") || key.contains("Uhrzeit") || key.contains("gesperrt") {
-                sensors.insert(clean_key, command.clone());
-            } else if key.contains("Single") {
-                lights.insert(clean_key, command.clone());
-            } else {
-                switches.insert(clean_key, command.clone());
-            }
+    /// Mapping section a discovered key belongs to, based on its id and icon class.
+    fn categorize(key: &str, icon_map: &[(String, &'static str)]) -> &'static str {
+        let icon = key.split_once("_icon-").map(|(_, icon)| format!("icon-{icon}"));
+        let icon_section = icon
+            .and_then(|icon| icon_map.iter().find(|(class, _)| *class == icon))
+            .map(|(_, section)| *section);
+
+        if key.contains("Double3") {
+            "blinds"
+        } else if key.contains("ExtendedSlider") {
+            "dimmers"
+        } else if let Some(section) = icon_section {
+            section
+        } else if key.contains("Szene") || key.contains("Scene") {
+            "scenes"
+        } else if key.contains("Temp") || key.contains("Datum") || key.contains("Uhrzeit") || key.contains("gesperrt") {
+            "sensors"
+        } else if key.contains("Single") {
+            "lights"
+        } else {
+            "switches"
         }
+    }
 
-        let mut content = String::new();
-        content.push_str("# Auto-generated device mappings\n");
-        content.push_str("# Generated by auto-discovery mode\n\n");
+    fn save_mappings(mappings: &HashMap<String, String>, icon_map: &[(String, &'static str)]) -> Result<()> {
+        info!("💾 Saving mappings to device_mappings_auto.toml...");
 
-        if !lights.is_empty() {
-            content.push_str("[lights]\n");
-            for (key, cmd) in lights {
-                writeln!(content, "\"{key}\" = \"{cmd}\"").ok();
-            }
-            content.push('\n');
-        }
+        let content = Self::render_mappings(mappings, icon_map)?;
 
-        if !blinds.is_empty() {
-            content.push_str("[blinds]\n");
-            for (key, cmd) in blinds {
-                writeln!(content, "\"{key}\" = \"{cmd}\"").ok();
-            }
-            content.push('\n');
-        }
+        fs::write("device_mappings_auto.toml", content)
+            .context("Failed to write device_mappings_auto.toml")?;
 
-        if !dimmers.is_empty() {
-            content.push_str("[dimmers]\n");
-            for (key, cmd) in dimmers {
-                writeln!(content, "\"{key}\" = \"{cmd}\"").ok();
-            }
-            content.push('\n');
-        }
+        info!("✅ Saved to device_mappings_auto.toml");
+        info!("You can review it and rename to device_mappings.toml");
 
-        if !ventilation.is_empty() {
-            content.push_str("[ventilation]\n");
-            for (key, cmd) in ventilation {
-                writeln!(content, "\"{key}\" = \"{cmd}\"").ok();
-            }
-            content.push('\n');
-        }
+        Ok(())
+    }
 
-        if !scenes.is_empty() {
-            content.push_str("[scenes]\n");
-            for (key, cmd) in scenes {
-                writeln!(content, "\"{key}\" = \"{cmd}\"").ok();
-            }
-            content.push('\n');
-        }
+    /// Renders discovered commands as a mappings file, sorted so that repeated runs diff cleanly.
+    fn render_mappings(mappings: &HashMap<String, String>, icon_map: &[(String, &'static str)]) -> Result<String> {
+        let mut sections = DeviceMappings::default();
 
-        if !sensors.is_empty() {
-            content.push_str("[sensors]\n");
-            for (key, _cmd) in sensors {
-                writeln!(content, "\"{key}\" = \"READONLY\"").ok();
-            }
-            content.push('\n');
+        for (key, command) in mappings {
+            let clean_key = Self::clean_key(key);
+
+            let (section, command) = match Self::categorize(key, icon_map) {
+                "blinds" => (&mut sections.blinds, command.clone()),
+                "dimmers" => (&mut sections.dimmers, command.clone()),
+                "ventilation" => (&mut sections.ventilation, command.clone()),
+                "scenes" => (&mut sections.scenes, command.clone()),
+                "sensors" => (&mut sections.sensors, "READONLY".to_string()),
+                "lights" => (&mut sections.lights, command.clone()),
+                _ => (&mut sections.switches, command.clone()),
+            };
+            section.insert(clean_key, command);
         }
 
-        if !switches.is_empty() {
-            content.push_str("[switches]\n");
-            for (key, cmd) in switches {
-                writeln!(content, "\"{key}\" = \"{cmd}\"").ok();
-            }
-            content.push('\n');
-        }
+        let mut content = String::new();
+        content.push_str("# Auto-generated device mappings\n");
+        content.push_str("# Generated by auto-discovery mode\n\n");
+        content.push_str(&sections.to_toml()?);
+        Ok(content)
+    }
+}
 
-        fs::write("device_mappings_auto.toml", content)
-            .context("Failed to write device_mappings_auto.toml")?;
+/// `icon-N=section` entries from `SMARTHOME_ICON_MAP`, followed by the built-in rules.
+fn parse_icon_map(entries: &[String]) -> Result<Vec<(String, &'static str)>> {
+    let mut icon_map = Vec::new();
+    for entry in entries {
+        let (icon, section) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected icon=section, got '{entry}'"))?;
+        let section = section.trim();
+        let section = ICON_SECTIONS.iter().find(|s| **s == section).ok_or_else(|| {
+            anyhow::anyhow!("unknown section '{section}' for {icon}, expected one of {}", ICON_SECTIONS.join(", "))
+        })?;
+        icon_map.push((icon.trim().to_string(), *section));
+    }
+    icon_map.extend(DEFAULT_ICON_MAP.iter().map(|(icon, section)| ((*icon).to_string(), *section)));
+    Ok(icon_map)
+}
 
-        info!("✅ Saved to device_mappings_auto.toml");
-        info!("You can review it and rename to device_mappings.toml");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_mappings_round_trip() {
+        let discovered: HashMap<String, String> = [
+            ("Single_2_page02", "02+01+01+02"),
+            ("Single_1_page02", "01+01+01+02"),
+            ("Jalousie_1_page03_up", "01+01+00+03"),
+            ("Temperatur_1_page04", "READONLY"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let icon_map = parse_icon_map(&[]).unwrap();
+        let rendered = AutoDiscovery::render_mappings(&discovered, &icon_map).unwrap();
+        assert!(rendered.find("Single_1_page02").unwrap() < rendered.find("Single_2_page02").unwrap());
+
+        // Save -> load through the real loader -> save again must not change a byte.
+        let loaded = CommandMapper::from_toml(&rendered).unwrap();
+        let rerendered = AutoDiscovery::render_mappings(&loaded.command_cache, &icon_map).unwrap();
+        assert_eq!(rendered, rerendered);
+    }
 
-        Ok(())
+    #[test]
+    fn test_categorize_by_icon() {
+        let defaults = parse_icon_map(&[]).unwrap();
+        assert_eq!(AutoDiscovery::categorize("Single_1_page02_icon-1", &defaults), "lights");
+        assert_eq!(AutoDiscovery::categorize("Single_7_page03_icon-3", &defaults), "switches");
+        assert_eq!(AutoDiscovery::categorize("Single_6_page03_icon-45", &defaults), "ventilation");
+        assert_eq!(AutoDiscovery::categorize("Single_5_page03_icon-11", &defaults), "scenes");
+        assert_eq!(AutoDiscovery::categorize("Single_9_page03_icon-450", &defaults), "lights");
+        assert_eq!(AutoDiscovery::categorize("Button_2_page03_icon-1", &defaults), "lights");
+        assert_eq!(AutoDiscovery::categorize("Double3_1_page02_up", &defaults), "blinds");
+
+        let custom = parse_icon_map(&["icon-1=switches".to_string(), "icon-9 = lights".to_string()]).unwrap();
+        assert_eq!(AutoDiscovery::categorize("Single_1_page02_icon-1", &custom), "switches");
+        assert_eq!(AutoDiscovery::categorize("Button_2_page03_icon-9", &custom), "lights");
+        assert!(parse_icon_map(&["icon-9=lamps".to_string()]).is_err());
+        assert!(parse_icon_map(&["icon-9".to_string()]).is_err());
     }
 }
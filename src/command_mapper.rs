@@ -1,30 +1,391 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::path::Path;
-use tracing::{debug, info};
+use std::sync::OnceLock;
+use tracing::{debug, info, warn};
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::config::interpolate_env;
+use crate::device::{Device, DeviceType, VIRTUAL_PAGE};
+
+/// Mappings file read at startup and by `POST /mappings/reload`.
+pub const MAPPINGS_PATH: &str = "device_mappings.toml";
+
+/// Contents of a mappings file. Sections are `BTreeMap`s so that serializing
+/// produces keys in a stable, sorted order.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DeviceMappings {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub lights: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub blinds: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dimmers: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ventilation: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub scenes: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub switches: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sensors: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub device_options: BTreeMap<String, DeviceOptions>,
+    /// Human-friendly alias → device key, accepted wherever the API takes a key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+    /// Free-form string fields per device key (manufacturer, model, ...), passed
+    /// through to API clients untouched.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, BTreeMap<String, String>>,
+    /// User-defined groups, keyed by an id that becomes the device key `{id}_page00`.
+    #[serde(rename = "virtual", default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub virtual_devices: BTreeMap<String, VirtualDevice>,
+    /// Corrections to what discovery reported, keyed by device key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub overrides: BTreeMap<String, DeviceOverride>,
+}
+
+/// An `[overrides."<key>"]` entry replacing a discovered device's type and/or name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<DeviceType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub friendly_name: Option<String>,
+}
+
+/// A `[virtual.<id>]` entry: one on/off device whose toggle is sent to every member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VirtualDevice {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", default = "default_virtual_type")]
+    pub type_: DeviceType,
+    /// Member device keys.
+    pub members: Vec<String>,
+    /// Whether the group reports on when any member is on, or only when all are.
     #[serde(default)]
-    pub lights: HashMap<String, String>,
-    #[serde(default)]
-    pub blinds: HashMap<String, String>,
-    #[serde(default)]
-    pub dimmers: HashMap<String, String>,
-    #[serde(default)]
-    pub ventilation: HashMap<String, String>,
-    #[serde(default)]
-    pub scenes: HashMap<String, String>,
-    #[serde(default)]
-    pub switches: HashMap<String, String>,
-    #[serde(default)]
-    pub sensors: HashMap<String, String>,
+    pub on_when: GroupState,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupState {
+    #[default]
+    Any,
+    All,
+}
+
+impl GroupState {
+    pub fn aggregate(self, mut members: impl Iterator<Item = bool>) -> bool {
+        match self {
+            GroupState::Any => members.any(|on| on),
+            GroupState::All => members.all(|on| on),
+        }
+    }
+}
+
+fn default_virtual_type() -> DeviceType {
+    DeviceType::Switch
+}
+
+impl DeviceMappings {
+    /// Zero-pads single-digit page numbers in every key (`Single_1_page2` → `Single_1_page02`)
+    /// and returns the keys it changed. A padded key that already exists wins over its
+    /// unpadded twin.
+    pub fn normalize_page_keys(&mut self) -> Vec<(String, String)> {
+        let mut corrected = Vec::new();
+        self.rename_keys(normalize_page_key, &mut corrected);
+        corrected
+    }
+
+    /// Namespaces every key (and alias target) that doesn't carry `prefix` yet, so a
+    /// file written without the prefix keeps working once `SMARTHOME_KEY_PREFIX` (or
+    /// `BRIDGE_KEY_PREFIX`) is set.
+    pub fn apply_key_prefix(&mut self, prefix: &str) {
+        if prefix.is_empty() {
+            return;
+        }
+        let namespace = |key: &str| (!has_prefix(prefix, key)).then(|| with_prefix(prefix, key));
+        self.rename_keys(namespace, &mut Vec::new());
+    }
+
+    fn rename_keys(&mut self, rename: impl Fn(&str) -> Option<String>, corrected: &mut Vec<(String, String)>) {
+        rename_section(&mut self.lights, &rename, corrected);
+        rename_section(&mut self.blinds, &rename, corrected);
+        rename_section(&mut self.dimmers, &rename, corrected);
+        rename_section(&mut self.ventilation, &rename, corrected);
+        rename_section(&mut self.scenes, &rename, corrected);
+        rename_section(&mut self.switches, &rename, corrected);
+        rename_section(&mut self.sensors, &rename, corrected);
+        rename_section(&mut self.device_options, &rename, corrected);
+        rename_section(&mut self.metadata, &rename, corrected);
+        rename_section(&mut self.overrides, &rename, corrected);
+        for (alias, target) in self.aliases.iter_mut() {
+            if let Some(fixed) = rename(target) {
+                corrected.push((format!("{alias} = {target}"), format!("{alias} = {fixed}")));
+                *target = fixed;
+            }
+        }
+        for (id, group) in &mut self.virtual_devices {
+            for member in &mut group.members {
+                if let Some(fixed) = rename(member) {
+                    corrected.push((format!("virtual.{id} member {member}"), format!("virtual.{id} member {fixed}")));
+                    *member = fixed;
+                }
+            }
+        }
+    }
+
+    /// Rejects commands that aren't `READONLY` or `index+function+value+page`, listing
+    /// every malformed entry at once. Placeholders count as the field they stand for,
+    /// but templates are only rendered for `_level` keys and are an error anywhere else.
+    /// `{level}` is rejected: `_level` templates take `{value}`, a byte rather than percent.
+    fn validate_commands(&self) -> Result<()> {
+        let malformed: Vec<String> = [
+            &self.lights,
+            &self.blinds,
+            &self.dimmers,
+            &self.ventilation,
+            &self.scenes,
+            &self.switches,
+            &self.sensors,
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|(key, command)| {
+            let reason = if is_template(command) && !key.ends_with("_level") {
+                Some("templates are only supported for _level commands".to_string())
+            } else if key.ends_with("_level") && command.contains("{level}") {
+                Some("use {value}, which is filled with a 0-255 byte rather than percent".to_string())
+            } else {
+                check_command(command).err()
+            };
+            reason.map(|reason| format!("{key} = \"{command}\" ({reason})"))
+        })
+        .collect();
+
+        if !malformed.is_empty() {
+            anyhow::bail!(
+                "{} malformed commands in device mappings (expected index+function+value+page, e.g. 12+01+00+02):\n  {}",
+                malformed.len(),
+                malformed.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Rejects groups that couldn't be controlled: no members, a type without a plain
+    /// on/off, or another group as a member.
+    fn validate_virtual_devices(&self) -> Result<()> {
+        for (id, group) in &self.virtual_devices {
+            if group.members.is_empty() {
+                anyhow::bail!("Virtual device '{id}' has no members");
+            }
+            if !matches!(
+                group.type_,
+                DeviceType::Light | DeviceType::Switch | DeviceType::Outlet | DeviceType::Fan
+            ) {
+                anyhow::bail!(
+                    "Virtual device '{id}' has type {:?}; only Light, Switch, Outlet and Fan can be grouped",
+                    group.type_
+                );
+            }
+            let member_ids: Vec<String> =
+                self.virtual_devices.keys().map(|id| CommandMapper::device_key(id, VIRTUAL_PAGE)).collect();
+            if let Some(nested) = group.members.iter().find(|m| member_ids.contains(m)) {
+                anyhow::bail!("Virtual device '{id}' can't contain another virtual device ({nested})");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn rename_section<V>(
+    section: &mut BTreeMap<String, V>,
+    rename: impl Fn(&str) -> Option<String>,
+    corrected: &mut Vec<(String, String)>,
+) {
+    let renames: Vec<(String, String)> = section
+        .keys()
+        .filter_map(|key| rename(key).map(|fixed| (key.clone(), fixed)))
+        .collect();
+
+    for (from, to) in renames {
+        let value = section.remove(&from).expect("key collected above");
+        if section.contains_key(&to) {
+            warn!("Dropping mapping {} because {} is also defined", from, to);
+        } else {
+            section.insert(to.clone(), value);
+        }
+        corrected.push((from, to));
+    }
+}
+
+/// `Some(fixed)` if `key` has a single-digit `_pageN`, e.g. `Blind_3_page2_up` → `Blind_3_page02_up`.
+pub fn normalize_page_key(key: &str) -> Option<String> {
+    let start = key.rfind("_page")? + "_page".len();
+    let digits = key[start..].bytes().take_while(u8::is_ascii_digit).count();
+    (digits == 1).then(|| format!("{}0{}", &key[..start], &key[start..]))
+}
+
+static KEY_PREFIX: OnceLock<String> = OnceLock::new();
+
+/// Sets the namespace every device key gets (`house1` → `house1:Single_3_page02`) so
+/// several bridges can share one HomeKit home. Only the first call has an effect;
+/// it must happen before any key is built or mappings are loaded.
+pub fn set_key_prefix(prefix: &str) {
+    if KEY_PREFIX.set(prefix.to_string()).is_err() {
+        warn!("Device key prefix is already set, ignoring '{}'", prefix);
+    }
+}
+
+/// The configured key prefix, empty when keys aren't namespaced.
+pub fn key_prefix() -> &'static str {
+    KEY_PREFIX.get().map_or("", String::as_str)
+}
+
+fn has_prefix(prefix: &str, key: &str) -> bool {
+    key.strip_prefix(prefix).is_some_and(|rest| rest.starts_with(':'))
+}
+
+/// `key` namespaced with `prefix`; unchanged if the prefix is empty or already there.
+pub fn with_prefix(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() || has_prefix(prefix, key) {
+        key.to_string()
+    } else {
+        format!("{prefix}:{key}")
+    }
+}
+
+/// Per-device tuning, keyed by device key in the `[device_options]` table.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceOptions {
+    /// Seconds after a blind command before re-reading its actual position;
+    /// overrides the global setting, `0` disables it for this blind.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirm_after_secs: Option<u64>,
+    /// Minimum pause between consecutive commands to this device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command_delay_ms: Option<u64>,
+    /// Seconds a blind takes from fully closed to fully open. When set, positions
+    /// between the up/down thresholds are reached by stopping the blind after the
+    /// matching share of that time.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub travel_time_secs: Option<f64>,
+    /// Treat a light as dimmable even though the visu shows no slider for it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dimmable: bool,
+}
+
+impl DeviceMappings {
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).context("Failed to serialize device mappings")
+    }
+}
+
+/// A decoded `controlKNX` command string: `{index}+{function}+{value}+{page}`,
+/// e.g. `12+01+00+02`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct KnxCommand {
+    pub index: u32,
+    pub function: u8,
+    pub value: u8,
+    pub page: String,
+}
+
+/// A command value that does not fit the gateway's one-byte value field. The
+/// gateway accepts such commands with a 200 and silently ignores them, so they
+/// are rejected before anything is sent.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{field} must be between 0 and {max}, got {value}")]
+pub struct ValueOutOfRange {
+    pub field: &'static str,
+    pub value: i64,
+    pub max: u8,
+}
+
+impl KnxCommand {
+    pub fn new(index: u32, function: u8, value: i64, page: impl Into<String>) -> Result<Self, ValueOutOfRange> {
+        let value = u8::try_from(value).map_err(|_| ValueOutOfRange { field: "value", value, max: u8::MAX })?;
+        Ok(KnxCommand { index, function, value, page: page.into() })
+    }
+}
+
+/// Checks a mapped command's shape; `READONLY` marks a device without one.
+pub fn check_command(command: &str) -> Result<(), String> {
+    if command == "READONLY" {
+        return Ok(());
+    }
+    let filled = command
+        .replace("{tilt}", "0")
+        .replace("{index}", "1")
+        .replace("{value}", "0")
+        .replace("{page}", "01");
+    filled.parse::<KnxCommand>().map(|_| ())
+}
+
+/// Whether `command` is a template like `{index}+07+{value}+{page}`, rendered by
+/// [`CommandMapper::render_command`].
+pub fn is_template(command: &str) -> bool {
+    ["{index}", "{value}", "{page}"].iter().any(|placeholder| command.contains(placeholder))
+}
+
+/// The absolute KNX dimming byte for a brightness in percent: `round(level * 255 / 100)`,
+/// so 1% is 3, 50% is 128 and 100% is 255.
+pub fn level_to_byte(level: u8) -> u8 {
+    ((u16::from(level.min(100)) * 255 + 50) / 100) as u8
+}
+
+/// Splits `Fan_1_page02_speed2` into the device key and speed step.
+fn speed_step(key: &str) -> Option<(&str, u8)> {
+    let (base, suffix) = key.rsplit_once("_speed")?;
+    Some((base, suffix.parse().ok()?))
+}
+
+/// `command` with its index field changed to `new_index`, keeping the field's zero
+/// padding. `None` if the command isn't a KNX command or already uses `new_index`.
+pub fn reindex_command(command: &str, new_index: &str) -> Option<String> {
+    let parsed: KnxCommand = command.parse().ok()?;
+    let new: u32 = new_index.trim().parse().ok()?;
+    if new == parsed.index {
+        return None;
+    }
+    let (field, rest) = command.trim().split_once('+')?;
+    Some(format!("{new:0width$}+{rest}", width = field.len()))
+}
+
+impl std::str::FromStr for KnxCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('+').collect();
+        let [index, function, value, page] = parts[..] else {
+            return Err(format!(
+                "Expected 4 '+'-separated fields (index+function+value+page), got {}",
+                parts.len()
+            ));
+        };
+
+        fn number<T: std::str::FromStr>(field: &str, raw: &str) -> Result<T, String> {
+            if raw.is_empty() || !raw.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(format!("{field} must be a number, got '{raw}'"));
+            }
+            raw.parse().map_err(|_| format!("{field} is out of range: '{raw}'"))
+        }
+
+        number::<u32>("page", page)?;
+        let value = number::<i64>("value", value)?;
+        KnxCommand::new(number("index", index)?, number("function", function)?, value, page)
+            .map_err(|e| e.to_string())
+    }
 }
 
 pub struct CommandMapper {
-    #[allow(dead_code)]
     mappings: DeviceMappings,
     pub command_cache: HashMap<String, String>,
 }
@@ -33,9 +394,52 @@ impl CommandMapper {
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
         let contents = fs::read_to_string(path.as_ref())
             .context("Failed to read device mappings file")?;
-        let mappings: DeviceMappings = toml::from_str(&contents)
+        Self::from_toml(&contents)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let mut mappings: DeviceMappings = toml::from_str(contents)
             .context("Failed to parse device mappings")?;
 
+        mappings.aliases = std::mem::take(&mut mappings.aliases)
+            .into_iter()
+            .map(|(alias, key)| (alias.nfc().collect(), key))
+            .collect();
+
+        let corrected = mappings.normalize_page_keys();
+        if !corrected.is_empty() {
+            warn!(
+                "Zero-padded the page number in {} mapping keys (run `fix-mappings` to update the file): {}",
+                corrected.len(),
+                corrected.iter().map(|(from, to)| format!("{from} -> {to}")).collect::<Vec<_>>().join(", ")
+            );
+        }
+        mappings.apply_key_prefix(key_prefix());
+        mappings.validate_virtual_devices()?;
+        if let Some((key, _)) = mappings
+            .overrides
+            .iter()
+            .find(|(_, entry)| entry.friendly_name.as_deref().is_some_and(|name| name.trim().is_empty()))
+        {
+            anyhow::bail!("Override for {key} has an empty friendly_name");
+        }
+
+        for section in [
+            &mut mappings.lights,
+            &mut mappings.blinds,
+            &mut mappings.dimmers,
+            &mut mappings.ventilation,
+            &mut mappings.scenes,
+            &mut mappings.switches,
+            &mut mappings.sensors,
+        ] {
+            for (key, command) in section.iter_mut() {
+                *command = interpolate_env(command)
+                    .with_context(|| format!("Invalid command for mapping {key}"))?;
+            }
+        }
+        mappings.validate_commands()?;
+
         let mut command_cache = HashMap::new();
         command_cache.extend(mappings.lights.iter().map(|(k, v)| (k.clone(), v.clone())));
         command_cache.extend(mappings.blinds.iter().map(|(k, v)| (k.clone(), v.clone())));
@@ -54,11 +458,12 @@ impl CommandMapper {
     }
 
     pub fn device_key(device_id: &str, page: &str) -> String {
-        if device_id.contains("_page") {
-            device_id.to_string()
+        let key = if device_id.contains("_page") {
+            normalize_page_key(device_id).unwrap_or_else(|| device_id.to_string())
         } else {
             format!("{device_id}_page{page}")
-        }
+        };
+        with_prefix(key_prefix(), &key)
     }
 
     pub fn get_command(&self, device_id: &str, page: &str) -> Option<&str> {
@@ -77,43 +482,261 @@ impl CommandMapper {
         }
     }
 
-    #[allow(dead_code)]
+    /// Whether the device's control commands resolve to something that can be sent.
+    pub fn is_actionable(&self, device: &Device) -> bool {
+        if device.is_virtual() {
+            self.virtual_device(&device.key()).is_some()
+        } else if device.type_ == DeviceType::WindowCovering {
+            self.get_blind_commands(&device.id, &device.page).is_some()
+        } else {
+            self.get_command(&device.id, &device.page).is_some()
+        }
+    }
+
+    /// A single blind direction (`up`, `stop` or `down`), for blinds that are only partially mapped.
+    pub fn get_blind_command(&self, device_id: &str, page: &str, direction: &str) -> Option<&str> {
+        let key = format!("{}_{direction}", Self::device_key(device_id, page));
+        self.command_cache
+            .get(&key)
+            .map(String::as_str)
+            .filter(|cmd| *cmd != "READONLY")
+    }
+
+    /// Whatever subset of up/stop/down is mapped; `None` only when none of them is.
     pub fn get_blind_commands(&self, device_id: &str, page: &str) -> Option<BlindCommands> {
-        let base_key = Self::device_key(device_id, page);
+        let command = |direction| self.get_blind_command(device_id, page, direction).map(str::to_string);
+        let commands = BlindCommands {
+            up: command("up"),
+            stop: command("stop"),
+            down: command("down"),
+        };
+
+        (!commands.available().is_empty()).then_some(commands)
+    }
+
+    /// The literal `{key}_level` command with its value field set to `level` in percent
+    /// (0-100). Templates send a 0-255 byte instead; see [`Self::render_command`].
+    pub fn get_brightness_command(&self, device_id: &str, page: &str, level: u8) -> Option<String> {
+        self.get_value_command(device_id, page, "level", level)
+    }
+
+    /// The blind's `{key}_tilt` command for a lamella angle in percent, filled in
+    /// like [`Self::get_brightness_command`] but with a `{tilt}` placeholder.
+    pub fn get_tilt_command(&self, device_id: &str, page: &str, tilt: u8) -> Option<String> {
+        self.get_value_command(device_id, page, "tilt", tilt)
+    }
 
-        let up = self.command_cache.get(&format!("{base_key}_up"))?;
-        let stop = self.command_cache.get(&format!("{base_key}_stop"))?;
-        let down = self.command_cache.get(&format!("{base_key}_down"))?;
+    /// The dimmer's `{key}_level` command for `level` percent. A template has `{index}`
+    /// and `{page}` filled from the device and `{value}` with the 0-255 byte from
+    /// [`level_to_byte`]; a literal command gets the percent, as in
+    /// [`Self::get_brightness_command`].
+    pub fn render_command(&self, device: &Device, level: u8) -> Option<String> {
+        let key = format!("{}_level", Self::device_key(&device.id, &device.page));
+        let command = self.command_cache.get(&key).filter(|cmd| *cmd != "READONLY")?;
+        if !is_template(command) {
+            return self.get_brightness_command(&device.id, &device.page, level);
+        }
+        Some(
+            command
+                .replace("{index}", &device.index)
+                .replace("{page}", &device.page)
+                .replace("{value}", &level_to_byte(level).to_string()),
+        )
+    }
 
-        if up == "READONLY" || stop == "READONLY" || down == "READONLY" {
+    fn get_value_command(&self, device_id: &str, page: &str, suffix: &str, value: u8) -> Option<String> {
+        let key = format!("{}_{suffix}", Self::device_key(device_id, page));
+        let command = self.command_cache.get(&key).filter(|cmd| *cmd != "READONLY")?;
+        if is_template(command) {
+            warn!("Command {} is a template, which only dimmer levels support: {}", key, command);
             return None;
         }
+        let placeholder = format!("{{{suffix}}}");
+        if command.contains(&placeholder) {
+            return Some(command.replace(&placeholder, &value.to_string()));
+        }
+        let fields: Vec<&str> = command.trim().split('+').collect();
+        let [index, function, current, page] = fields[..] else {
+            warn!("Command {} has neither {} nor a value field: {}", key, placeholder, command);
+            return None;
+        };
+        Some(format!("{index}+{function}+{value:0width$}+{page}", width = current.len()))
+    }
 
-        Some(BlindCommands {
-            up: up.clone(),
-            stop: stop.clone(),
-            down: down.clone(),
-        })
+    /// The fan's `{key}_speedN` command; for speed 0 also `{key}_off`.
+    pub fn get_fan_speed_command(&self, device_id: &str, page: &str, speed: u8) -> Option<&str> {
+        let key = Self::device_key(device_id, page);
+        let command = |suffix: &str| {
+            self.command_cache
+                .get(&format!("{key}_{suffix}"))
+                .map(String::as_str)
+                .filter(|cmd| *cmd != "READONLY")
+        };
+        command(&format!("speed{speed}")).or_else(|| (speed == 0).then(|| command("off")).flatten())
+    }
+
+    /// Speed steps mapped for a fan, ascending.
+    pub fn fan_speed_steps(&self, device_id: &str, page: &str) -> Vec<u8> {
+        let key = Self::device_key(device_id, page);
+        let mut steps: Vec<u8> = self
+            .mappings
+            .ventilation
+            .keys()
+            .filter_map(|k| speed_step(k).filter(|(base, _)| *base == key).map(|(_, step)| step))
+            .collect();
+        steps.sort_unstable();
+        steps
     }
 
-    #[allow(dead_code)]
     pub fn is_readonly(&self, device_id: &str, page: &str) -> bool {
         let key = Self::device_key(device_id, page);
         self.command_cache.get(&key).is_some_and(|cmd| cmd == "READONLY")
     }
 
-    #[allow(dead_code)]
+    /// Aliases are compared in NFC, so composed and decomposed umlauts match.
+    pub fn resolve_alias(&self, alias: &str) -> Option<&str> {
+        let alias: String = alias.nfc().collect();
+        self.mappings.aliases.get(&alias).map(String::as_str)
+    }
+
+    pub fn aliases(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.mappings.aliases.iter()
+    }
+
+    /// Devices defined in `[virtual]`, with their device keys already applied.
+    pub fn virtual_devices(&self) -> Vec<Device> {
+        self.mappings
+            .virtual_devices
+            .iter()
+            .map(|(id, group)| {
+                let name = group.name.clone().unwrap_or_else(|| id.clone());
+                Device::new(id.clone(), name, group.type_.clone(), VIRTUAL_PAGE.to_string(), String::new())
+            })
+            .collect()
+    }
+
+    /// The `[virtual]` definition behind a device key.
+    pub fn virtual_device(&self, device_key: &str) -> Option<&VirtualDevice> {
+        self.mappings
+            .virtual_devices
+            .iter()
+            .find(|(id, _)| Self::device_key(id, VIRTUAL_PAGE) == device_key)
+            .map(|(_, group)| group)
+    }
+
+    /// Keys of the virtual devices `member_key` belongs to.
+    pub fn groups_of(&self, member_key: &str) -> Vec<String> {
+        self.mappings
+            .virtual_devices
+            .iter()
+            .filter(|(_, group)| group.members.iter().any(|m| m == member_key))
+            .map(|(id, _)| Self::device_key(id, VIRTUAL_PAGE))
+            .collect()
+    }
+
+    pub fn device_options(&self, device_key: &str) -> Option<&DeviceOptions> {
+        self.mappings.device_options.get(device_key)
+    }
+
+    /// Applies the `[overrides]` entry for `device`, if there is one; returns whether there was.
+    pub fn apply_override(&self, device: &mut Device) -> bool {
+        let Some(entry) = self.mappings.overrides.get(&device.key()) else {
+            return false;
+        };
+        if let Some(name) = &entry.friendly_name {
+            device.name.clone_from(name);
+        }
+        if let Some(type_) = &entry.device_type {
+            device.set_type(type_.clone());
+        }
+        true
+    }
+
+    /// `[overrides]` keys that don't belong to any of the given devices.
+    pub fn unmatched_overrides(&self, devices: &[Device]) -> Vec<String> {
+        let device_keys: HashSet<String> = devices.iter().map(Device::key).collect();
+        self.mappings.overrides.keys().filter(|key| !device_keys.contains(*key)).cloned().collect()
+    }
+
+    pub fn metadata(&self, device_key: &str) -> BTreeMap<String, String> {
+        self.mappings.metadata.get(device_key).cloned().unwrap_or_default()
+    }
+
+    /// Dimmers, plus lights marked `dimmable` in `[device_options]`.
+    pub fn is_dimmable(&self, device: &Device) -> bool {
+        match device.type_ {
+            DeviceType::Dimmer => true,
+            DeviceType::Light => self.device_options(&device.key()).is_some_and(|o| o.dimmable),
+            _ => false,
+        }
+    }
+
+    /// Number of entries per mapping section.
+    pub fn section_counts(&self) -> BTreeMap<&'static str, usize> {
+        BTreeMap::from([
+            ("lights", self.mappings.lights.len()),
+            ("blinds", self.mappings.blinds.len()),
+            ("dimmers", self.mappings.dimmers.len()),
+            ("ventilation", self.mappings.ventilation.len()),
+            ("scenes", self.mappings.scenes.len()),
+            ("switches", self.mappings.switches.len()),
+            ("sensors", self.mappings.sensors.len()),
+        ])
+    }
+
+    pub fn readonly_count(&self) -> usize {
+        self.command_cache.values().filter(|cmd| *cmd == "READONLY").count()
+    }
+
+    /// Mapping keys that don't belong to any of the given devices.
+    pub fn orphaned_keys(&self, devices: &[Device]) -> Vec<String> {
+        let device_keys: HashSet<String> = devices.iter().map(Device::key).collect();
+        let mut orphans: Vec<String> = self
+            .command_cache
+            .keys()
+            .filter(|key| {
+                let base = ["_up", "_stop", "_down", "_level", "_tilt", "_off"]
+                    .iter()
+                    .find_map(|suffix| key.strip_suffix(suffix))
+                    .or_else(|| speed_step(key).map(|(base, _)| base))
+                    .unwrap_or(key);
+                !device_keys.contains(base)
+            })
+            .cloned()
+            .collect();
+        orphans.sort();
+        orphans
+    }
+
     pub fn all_keys(&self) -> Vec<String> {
         self.command_cache.keys().cloned().collect()
     }
 }
 
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct BlindCommands {
-    pub up: String,
-    pub stop: String,
-    pub down: String,
+    pub up: Option<String>,
+    pub stop: Option<String>,
+    pub down: Option<String>,
+}
+
+impl BlindCommands {
+    /// Mapped directions in up/stop/down order.
+    pub fn available(&self) -> Vec<&'static str> {
+        [("up", &self.up), ("stop", &self.stop), ("down", &self.down)]
+            .into_iter()
+            .filter_map(|(direction, command)| command.as_ref().map(|_| direction))
+            .collect()
+    }
+
+    pub fn get(&self, direction: &str) -> Option<&str> {
+        match direction {
+            "up" => self.up.as_deref(),
+            "stop" => self.stop.as_deref(),
+            "down" => self.down.as_deref(),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -131,4 +754,296 @@ mod tests {
             "Single_1_page02"
         );
     }
+
+    #[test]
+    fn test_parse_knx_command() {
+        let command: KnxCommand = "12+01+00+02".parse().unwrap();
+        assert_eq!(
+            command,
+            KnxCommand { index: 12, function: 1, value: 0, page: "02".to_string() }
+        );
+
+        assert!("12+01+00".parse::<KnxCommand>().is_err());
+        assert!("12+up+00+02".parse::<KnxCommand>().is_err());
+        assert!("12+01+300+02".parse::<KnxCommand>().is_err());
+    }
+
+    #[test]
+    fn test_command_value_bounds() {
+        assert_eq!(KnxCommand::new(12, 1, 0, "02").unwrap().value, 0);
+        assert_eq!(KnxCommand::new(12, 1, 255, "02").unwrap().value, 255);
+        assert_eq!(
+            KnxCommand::new(12, 1, 256, "02").unwrap_err(),
+            ValueOutOfRange { field: "value", value: 256, max: 255 }
+        );
+        assert!(KnxCommand::new(12, 1, -1, "02").is_err());
+
+        assert!("12+01+255+02".parse::<KnxCommand>().is_ok());
+        let err = "12+01+256+02".parse::<KnxCommand>().unwrap_err();
+        assert!(err.contains("between 0 and 255"), "{err}");
+    }
+
+    #[test]
+    fn test_normalize_page_keys() {
+        assert_eq!(normalize_page_key("Single_1_page2").as_deref(), Some("Single_1_page02"));
+        assert_eq!(normalize_page_key("Blind_3_page2_up").as_deref(), Some("Blind_3_page02_up"));
+        assert_eq!(normalize_page_key("Single_1_page02"), None);
+        assert_eq!(normalize_page_key("Single_1_page"), None);
+        assert_eq!(normalize_page_key("Single_1"), None);
+
+        let mapper = CommandMapper::from_toml(
+            "[lights]\n\"Single_1_page2\" = \"05+01+00+02\"\n\n[blinds]\n\"Blind_3_page2_up\" = \"07+01+00+02\"\n\n[aliases]\nkitchen = \"Single_1_page2\"\n",
+        )
+        .unwrap();
+        assert_eq!(mapper.get_command("Single_1", "02"), Some("05+01+00+02"));
+        assert_eq!(mapper.get_command("Single_1_page2", "02"), Some("05+01+00+02"));
+        assert_eq!(mapper.get_blind_command("Blind_3", "02", "up"), Some("07+01+00+02"));
+        assert_eq!(mapper.resolve_alias("kitchen"), Some("Single_1_page02"));
+
+        let mut mappings: DeviceMappings = toml::from_str(
+            "[lights]\n\"Single_1_page2\" = \"old\"\n\"Single_1_page02\" = \"new\"\n",
+        )
+        .unwrap();
+        assert_eq!(mappings.normalize_page_keys().len(), 1);
+        assert_eq!(mappings.lights.len(), 1);
+        assert_eq!(mappings.lights["Single_1_page02"], "new");
+    }
+
+    #[test]
+    fn test_key_prefix() {
+        assert_eq!(with_prefix("", "Single_3_page02"), "Single_3_page02");
+        assert_eq!(with_prefix("house1", "Single_3_page02"), "house1:Single_3_page02");
+        assert_eq!(with_prefix("house1", "house1:Single_3_page02"), "house1:Single_3_page02");
+        assert_eq!(with_prefix("house", "house1:Single_3_page02"), "house:house1:Single_3_page02");
+
+        let mut mappings: DeviceMappings = toml::from_str(
+            r#"
+            [lights]
+            Single_3_page02 = "3+01+01+02"
+            "house1:Single_4_page02" = "4+01+01+02"
+            [aliases]
+            kitchen = "Single_3_page02"
+            "#,
+        )
+        .unwrap();
+        mappings.apply_key_prefix("house1");
+        assert_eq!(
+            mappings.lights.keys().collect::<Vec<_>>(),
+            vec!["house1:Single_3_page02", "house1:Single_4_page02"]
+        );
+        assert_eq!(mappings.aliases["kitchen"], "house1:Single_3_page02");
+    }
+
+    #[test]
+    fn test_reindex_command() {
+        assert_eq!(reindex_command("12+01+00+02", "14").as_deref(), Some("14+01+00+02"));
+        assert_eq!(reindex_command("07+01+00+02", "9").as_deref(), Some("09+01+00+02"));
+        assert_eq!(reindex_command("12+01+00+02", "12"), None);
+        assert_eq!(reindex_command("Light_1", "2"), None);
+    }
+
+    #[test]
+    fn test_tilt_command() {
+        let mapper = CommandMapper::from_toml(
+            r#"
+            [blinds]
+            Double3_1_page02_up = "5+01+01+02"
+            Double3_1_page02_tilt = "5+07+{tilt}+02"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(mapper.get_tilt_command("Double3_1", "02", 30).as_deref(), Some("5+07+30+02"));
+        assert_eq!(mapper.get_tilt_command("Double3_2", "02", 30), None);
+    }
+
+    #[test]
+    fn test_brightness_command() {
+        let mapper = CommandMapper::from_toml(
+            r#"
+            [dimmers]
+            Dimmer_1_page02 = "3+01+00+02"
+            Dimmer_1_page02_level = "3+05+00+02"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(mapper.get_brightness_command("Dimmer_1", "02", 7).as_deref(), Some("3+05+07+02"));
+        assert_eq!(mapper.get_brightness_command("Dimmer_1", "02", 100).as_deref(), Some("3+05+100+02"));
+        assert_eq!(mapper.get_brightness_command("Dimmer_3", "02", 40), None);
+        assert!(mapper.orphaned_keys(&[]).contains(&"Dimmer_1_page02_level".to_string()));
+    }
+
+    #[test]
+    fn test_render_command() {
+        let mapper = CommandMapper::from_toml(
+            r#"
+            [dimmers]
+            Dimmer_1_page02_level = "{index}+07+{value}+{page}"
+            Dimmer_2_page02_level = "4+05+00+02"
+            "#,
+        )
+        .unwrap();
+        let dimmer = |id: &str, index: &str| {
+            Device::new(id.to_string(), id.to_string(), DeviceType::Dimmer, "02".to_string(), index.to_string())
+        };
+        let templated = dimmer("Dimmer_1", "12");
+        assert_eq!(mapper.render_command(&templated, 0).as_deref(), Some("12+07+0+02"));
+        assert_eq!(mapper.render_command(&templated, 1).as_deref(), Some("12+07+3+02"));
+        assert_eq!(mapper.render_command(&templated, 50).as_deref(), Some("12+07+128+02"));
+        assert_eq!(mapper.render_command(&templated, 100).as_deref(), Some("12+07+255+02"));
+        assert_eq!(mapper.render_command(&dimmer("Dimmer_2", "4"), 40).as_deref(), Some("4+05+40+02"));
+        assert_eq!(mapper.render_command(&dimmer("Dimmer_3", "5"), 40), None);
+    }
+
+    #[test]
+    fn test_fan_speed_commands() {
+        let mapper = CommandMapper::from_toml(
+            r#"
+            [ventilation]
+            Single_6_page03 = "3+01+00+03"
+            Single_6_page03_speed3 = "3+04+03+03"
+            Single_6_page03_speed1 = "3+04+01+03"
+            Single_6_page03_off = "3+04+00+03"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(mapper.get_fan_speed_command("Single_6", "03", 1), Some("3+04+01+03"));
+        assert_eq!(mapper.get_fan_speed_command("Single_6", "03", 0), Some("3+04+00+03"));
+        assert_eq!(mapper.get_fan_speed_command("Single_6", "03", 2), None);
+        assert_eq!(mapper.fan_speed_steps("Single_6", "03"), vec![1, 3]);
+
+        let fan = Device::new("Single_6".to_string(), "Lüftung".to_string(), DeviceType::Fan, "03".to_string(), "3".to_string());
+        assert!(mapper.orphaned_keys(&[fan]).is_empty());
+    }
+
+    #[test]
+    fn test_virtual_devices() {
+        let mapper = CommandMapper::from_toml(
+            r#"
+            [lights]
+            Single_1_page2 = "1+01+01+02"
+            [virtual.ceiling]
+            name = "Alle Deckenlichter"
+            type = "Light"
+            members = ["Single_1_page2", "Single_2_page02"]
+            on_when = "all"
+            "#,
+        )
+        .unwrap();
+
+        let devices = mapper.virtual_devices();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].key(), "ceiling_page00");
+        assert!(mapper.is_actionable(&devices[0]));
+        let group = mapper.virtual_device("ceiling_page00").unwrap();
+        assert_eq!(group.members, vec!["Single_1_page02", "Single_2_page02"]);
+        assert!(!group.on_when.aggregate([true, false].into_iter()));
+        assert_eq!(mapper.groups_of("Single_2_page02"), vec!["ceiling_page00"]);
+
+        let nested = "[virtual.a]\nmembers = [\"b_page00\"]\n[virtual.b]\nmembers = [\"Single_1_page02\"]\n";
+        assert!(CommandMapper::from_toml(nested).is_err());
+        assert!(CommandMapper::from_toml("[virtual.a]\ntype = \"WindowCovering\"\nmembers = [\"x\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_validate_commands() {
+        for command in ["12+01+00+02", "READONLY", "4+03+{tilt}+02", "007+01+255+10", "{index}+07+{value}+{page}"] {
+            assert_eq!(check_command(command), Ok(()), "{command}");
+        }
+        for command in ["12+01+00", "12+01+00+02+1", "12+on+00+02", "12+01+256+02", "", "readonly", "4+03+{level}+02"] {
+            assert!(check_command(command).is_err(), "{command}");
+        }
+
+        let Err(err) = CommandMapper::from_toml(
+            "[lights]\nSingle_1_page02 = \"05+01+00+02\"\nSingle_2_page02 = \"05+1+00\"\n\
+             [blinds]\nBlind_1_page02_up = \"06 01 00 02\"\n",
+        ) else {
+            panic!("malformed commands were accepted");
+        };
+        let err = err.to_string();
+        assert!(err.starts_with("2 malformed commands"), "{err}");
+        assert!(err.contains("Single_2_page02") && err.contains("Blind_1_page02_up"), "{err}");
+        assert!(!err.contains("Single_1_page02"), "{err}");
+
+        let Err(err) = CommandMapper::from_toml(
+            "[dimmers]\nDimmer_1_page02_level = \"{index}+07+{value}+{page}\"\n\
+             [lights]\nSingle_3_page02 = \"{index}+01+{value}+{page}\"\n",
+        ) else {
+            panic!("a template outside a _level key was accepted");
+        };
+        let err = err.to_string();
+        assert!(err.starts_with("1 malformed commands"), "{err}");
+        assert!(err.contains("Single_3_page02 = \"{index}+01+{value}+{page}\" (templates are only supported"), "{err}");
+
+        let Err(err) = CommandMapper::from_toml("[dimmers]\nDimmer_2_page02_level = \"4+05+{level}+02\"\n") else {
+            panic!("{{level}} in a _level key was accepted");
+        };
+        assert!(err.to_string().contains("Dimmer_2_page02_level = \"4+05+{level}+02\" (use {value}"), "{err}");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mapper = CommandMapper::from_toml(
+            r#"
+            [overrides.Single_1_page2]
+            device_type = "Switch"
+            friendly_name = "Pumpe"
+            [overrides.Single_9_page02]
+            friendly_name = "Gone"
+            "#,
+        )
+        .unwrap();
+
+        let mut device = Device::new("Single_1".into(), "Licht 1".into(), DeviceType::Light, "02".into(), "1".into());
+        assert!(mapper.apply_override(&mut device));
+        assert_eq!((device.name.as_str(), &device.type_), ("Pumpe", &DeviceType::Switch));
+        assert_eq!(mapper.unmatched_overrides(&[device]), vec!["Single_9_page02"]);
+
+        assert!(CommandMapper::from_toml("[overrides.Single_1_page02]
+friendly_name = \" \"\n").is_err());
+        assert!(CommandMapper::from_toml("[overrides.Single_1_page02]
+name = \"Pumpe\"\n").is_err());
+    }
+
+    #[test]
+    fn test_unicode_names() {
+        let composed = "B\u{fc}ro";
+        let decomposed = "Bu\u{308}ro";
+        assert_ne!(composed, decomposed);
+        assert_eq!(crate::device::name_key(composed), crate::device::name_key(decomposed));
+        assert_eq!(crate::device::name_key(" büro "), crate::device::name_key("BÜRO"));
+
+        let mapper =
+            CommandMapper::from_toml(&format!("[aliases]\n\"{decomposed}\" = \"Single_1_page02\"\n")).unwrap();
+        assert_eq!(mapper.resolve_alias(composed), Some("Single_1_page02"));
+        assert_eq!(mapper.resolve_alias(decomposed), Some("Single_1_page02"));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let mapper = CommandMapper::from_toml(
+            "[metadata.\"Single_1_page02\"]\nmanufacturer = \"Gira\"\nmodel = \"Tastsensor\"\n",
+        )
+        .unwrap();
+        let metadata = mapper.metadata("Single_1_page02");
+        assert_eq!(metadata.get("manufacturer").map(String::as_str), Some("Gira"));
+        assert_eq!(metadata.get("model").map(String::as_str), Some("Tastsensor"));
+        assert!(mapper.metadata("Single_2_page02").is_empty());
+    }
+
+    #[test]
+    fn test_mapping_interpolation() {
+        std::env::set_var("KNX_TEST_LIGHT_PAGE", "02");
+        let mapper = CommandMapper::from_toml(
+            "[lights]\n\"Single_1_page02\" = \"05+01+00+${KNX_TEST_LIGHT_PAGE}\"\n",
+        )
+        .unwrap();
+        assert_eq!(mapper.get_command("Single_1", "02"), Some("05+01+00+02"));
+
+        let err = CommandMapper::from_toml(
+            "[lights]\n\"Single_1_page02\" = \"05+01+00+${KNX_TEST_UNSET_VAR}\"\n",
+        )
+        .err()
+        .unwrap();
+        assert!(format!("{err:#}").contains("KNX_TEST_UNSET_VAR"));
+    }
 }
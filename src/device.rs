@@ -1,5 +1,7 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Device {
@@ -9,6 +11,40 @@ pub struct Device {
     pub page: String,
     pub index: String,
     pub state: DeviceState,
+    /// Whether `state` carries a value read from the gateway's status text
+    /// rather than the type's default.
+    #[serde(skip)]
+    pub has_reading: bool,
+    /// False once commands keep failing or discovery no longer finds the device.
+    #[serde(default = "default_reachable")]
+    pub reachable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub consecutive_failures: u32,
+    /// When `state` last changed, whether through a command or a gateway read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_changed: Option<DateTime<Utc>>,
+    /// Low-battery indicator of a wireless sensor; `None` when the visu shows none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_low: Option<bool>,
+    /// Signal strength in percent as reported by a wireless sensor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<u8>,
+}
+
+/// Page of devices defined in the mappings' `[virtual]` section; the gateway's
+/// pages start at 01.
+pub const VIRTUAL_PAGE: &str = "00";
+
+/// Comparison form of a device name: NFC-normalized, trimmed and lowercased, so a
+/// "Büro" typed with a combining diaeresis matches the precomposed one.
+pub fn name_key(name: &str) -> String {
+    name.trim().nfc().collect::<String>().to_lowercase()
+}
+
+fn default_reachable() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -17,18 +53,100 @@ pub enum DeviceType {
     Dimmer,
     WindowCovering,
     TemperatureSensor,
+    HumiditySensor,
     Fan,
     Scene,
     Switch,
+    Outlet,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl DeviceType {
+    pub const ALL: [DeviceType; 9] = [
+        DeviceType::Light,
+        DeviceType::Dimmer,
+        DeviceType::WindowCovering,
+        DeviceType::TemperatureSensor,
+        DeviceType::HumiditySensor,
+        DeviceType::Fan,
+        DeviceType::Scene,
+        DeviceType::Switch,
+        DeviceType::Outlet,
+    ];
+
+    pub fn is_sensor(&self) -> bool {
+        matches!(self, DeviceType::TemperatureSensor | DeviceType::HumiditySensor)
+    }
+
+    /// Name of the HomeKit service a Homebridge plugin should expose for this type.
+    pub fn homekit_service(&self) -> &'static str {
+        match self {
+            DeviceType::Light | DeviceType::Dimmer => "Lightbulb",
+            DeviceType::WindowCovering => "WindowCovering",
+            DeviceType::TemperatureSensor => "TemperatureSensor",
+            DeviceType::HumiditySensor => "HumiditySensor",
+            DeviceType::Fan => "Fan",
+            DeviceType::Scene | DeviceType::Switch => "Switch",
+            DeviceType::Outlet => "Outlet",
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceType {
+    type Err = String;
+
+    /// Parses the variant name, case-insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|t| format!("{t:?}").eq_ignore_ascii_case(s.trim()))
+            .cloned()
+            .ok_or_else(|| {
+                let valid: Vec<String> = Self::ALL.iter().map(|t| format!("{t:?}")).collect();
+                format!("Unknown device type '{s}', expected one of: {}", valid.join(", "))
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DeviceState {
     OnOff(bool),
     Brightness { on: bool, level: u8 },
-    WindowCovering { position: u8, state: WindowCoveringState },
+    /// `obstruction` is set when a full open/close didn't reach its end position
+    /// and cleared by the next one that does.
+    WindowCovering {
+        position: u8,
+        state: WindowCoveringState,
+        #[serde(default)]
+        obstruction: bool,
+        /// Lamella angle in percent, once one has been set; `None` for blinds without tilt.
+        #[serde(default)]
+        tilt: Option<u8>,
+    },
     Temperature(f32),
+    Humidity(f32),
     FanSpeed(u8),
+    /// `in_use` is true when the status text reports a load on the socket.
+    Outlet { on: bool, in_use: bool },
+}
+
+impl DeviceState {
+    pub fn default_for(type_: &DeviceType) -> Self {
+        match type_ {
+            DeviceType::Light | DeviceType::Switch | DeviceType::Scene | DeviceType::Fan => {
+                DeviceState::OnOff(false)
+            }
+            DeviceType::Dimmer => DeviceState::Brightness { on: false, level: 0 },
+            DeviceType::WindowCovering => DeviceState::WindowCovering {
+                position: 0,
+                state: WindowCoveringState::Stopped,
+                obstruction: false,
+                tilt: None,
+            },
+            DeviceType::TemperatureSensor => DeviceState::Temperature(0.0),
+            DeviceType::HumiditySensor => DeviceState::Humidity(0.0),
+            DeviceType::Outlet => DeviceState::Outlet { on: false, in_use: false },
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -38,23 +156,40 @@ pub enum WindowCoveringState {
     Closing,
 }
 
+/// How finely a device can be driven on a given axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlMode {
+    /// Any value from 0 to 100.
+    Continuous,
+    /// Coarse steps, e.g. a blind's up/stop/down buckets.
+    Stepped,
+    /// Only the two extremes: off/on or closed/open.
+    OnOff,
+}
+
+/// What the bridge can actually do with a device given its command mappings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Capabilities {
+    pub on_off: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<ControlMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<ControlMode>,
+    /// Mapped blind directions, so clients can tell which of up/stop/down will work.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub directions: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt: Option<ControlMode>,
+}
+
 impl Device {
     pub fn key(&self) -> String {
         crate::command_mapper::CommandMapper::device_key(&self.id, &self.page)
     }
 
     pub fn new(id: String, name: String, type_: DeviceType, page: String, index: String) -> Self {
-        let state = match type_ {
-            DeviceType::Light | DeviceType::Switch | DeviceType::Scene | DeviceType::Fan => {
-                DeviceState::OnOff(false)
-            }
-            DeviceType::Dimmer => DeviceState::Brightness { on: false, level: 0 },
-            DeviceType::WindowCovering => DeviceState::WindowCovering {
-                position: 0,
-                state: WindowCoveringState::Stopped,
-            },
-            DeviceType::TemperatureSensor => DeviceState::Temperature(0.0),
-        };
+        let state = DeviceState::default_for(&type_);
 
         Device {
             id,
@@ -63,19 +198,118 @@ impl Device {
             page,
             index,
             state,
+            has_reading: false,
+            reachable: true,
+            last_error: None,
+            consecutive_failures: 0,
+            last_changed: Some(Utc::now()),
+            battery_low: None,
+            signal: None,
+        }
+    }
+
+    /// Whether this is a `[virtual]` group rather than a device on the gateway.
+    pub fn is_virtual(&self) -> bool {
+        self.page == VIRTUAL_PAGE
+    }
+
+    /// Replaces the state, bumping `last_changed` only if the value differs.
+    pub fn set_state(&mut self, state: DeviceState) {
+        if self.state != state {
+            self.state = state;
+            self.last_changed = Some(Utc::now());
+        }
+    }
+
+    /// Checks that a device (e.g. from an imported snapshot) is internally consistent.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.id.trim().is_empty() {
+            return Err("device id is empty".to_string());
+        }
+        if self.page.is_empty() || !self.page.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!("device {} has invalid page '{}'", self.id, self.page));
+        }
+        let expected = DeviceState::default_for(&self.type_);
+        // States a type can take besides its default: dimmed lights, fans set to a speed step.
+        let alternative = matches!(
+            (&self.type_, &self.state),
+            (DeviceType::Light, DeviceState::Brightness { .. }) | (DeviceType::Fan, DeviceState::FanSpeed(_))
+        );
+        if !alternative && std::mem::discriminant(&expected) != std::mem::discriminant(&self.state) {
+            return Err(format!(
+                "device {} has state {:?} which doesn't match type {:?}",
+                self.id, self.state, self.type_
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reassigns the device type, resetting the state to the new type's default
+    /// when the type actually changes.
+    pub fn set_type(&mut self, type_: DeviceType) {
+        if self.type_ != type_ {
+            self.set_state(DeviceState::default_for(&type_));
+            self.has_reading = false;
+            self.type_ = type_;
+        }
+    }
+
+    /// Switches an on/off state to a brightness state, keeping the on/off value.
+    pub fn make_dimmable(&mut self) {
+        if let DeviceState::OnOff(on) = self.state {
+            self.set_state(DeviceState::Brightness { on, level: if on { 100 } else { 0 } });
+        }
+    }
+
+    pub fn obstruction(&self) -> bool {
+        matches!(self.state, DeviceState::WindowCovering { obstruction: true, .. })
+    }
+
+    pub fn set_obstruction(&mut self, value: bool) {
+        if let DeviceState::WindowCovering { obstruction, .. } = &mut self.state {
+            if *obstruction != value {
+                *obstruction = value;
+                self.last_changed = Some(Utc::now());
+            }
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.last_error = None;
+        self.reachable = true;
+    }
+
+    /// Records a failed command; the device turns unreachable after `threshold` failures in a row.
+    pub fn record_failure(&mut self, error: String, threshold: u32) {
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+        if self.consecutive_failures >= threshold {
+            self.reachable = false;
         }
     }
 
     pub fn is_on(&self) -> bool {
         match &self.state {
-            DeviceState::OnOff(on) | DeviceState::Brightness { on, .. } => *on,
+            DeviceState::OnOff(on) | DeviceState::Brightness { on, .. } | DeviceState::Outlet { on, .. } => *on,
+            DeviceState::FanSpeed(speed) => *speed > 0,
             _ => false,
         }
     }
 
+    /// Switching a fan on whose speed is unknown reports the lowest step.
     pub fn set_on(&mut self, value: bool) {
         match &mut self.state {
-            DeviceState::OnOff(on) | DeviceState::Brightness { on, .. } => *on = value,
+            DeviceState::OnOff(on) | DeviceState::Brightness { on, .. } | DeviceState::Outlet { on, .. }
+                if *on != value =>
+            {
+                *on = value;
+                self.last_changed = Some(Utc::now());
+            }
+            DeviceState::FanSpeed(speed) if (*speed > 0) != value => {
+                *speed = u8::from(value);
+                self.last_changed = Some(Utc::now());
+            }
             _ => {}
         }
     }
@@ -118,11 +352,16 @@ impl DeviceRegistry {
         self.devices.get_mut(&key)
     }
 
+    /// Devices whose name matches `name` under [`name_key`].
+    pub fn find_by_name(&self, name: &str) -> Vec<&Device> {
+        let wanted = name_key(name);
+        self.devices.values().filter(|d| name_key(&d.name) == wanted).collect()
+    }
+
     pub fn all(&self) -> impl Iterator<Item = &Device> {
         self.devices.values()
     }
 
-    #[allow(dead_code)]
     pub fn all_mut(&mut self) -> impl Iterator<Item = &mut Device> {
         self.devices.values_mut()
     }
@@ -1,48 +1,403 @@
 use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
 use headless_chrome::{Browser, LaunchOptions};
+use reqwest::header::{HeaderValue, COOKIE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
 use scraper::{Html, Selector};
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tracing::{debug, info, warn};
 
-use crate::config::KnxConfig;
-use crate::device::{Device, DeviceType};
+use crate::config::{KnxConfig, LoginMode};
+use crate::metrics::METRICS;
+use crate::rate_limiter::{RateLimitStatus, RateLimiter};
+use crate::device::{Device, DeviceState, DeviceType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginState {
+    LoggedIn,
+    LoginForm,
+}
+
+/// Polls a freshly navigated tab until it shows either the login form or visu
+/// elements, giving up after `max_wait` and deciding on whatever has loaded by then.
+pub fn wait_for_login_state(tab: &headless_chrome::Tab, max_wait: Duration) -> LoginState {
+    let check_js = r#"
+        (function() {
+            if (document.querySelector('input[name="email"]')) {
+                return 'login_form';
+            }
+            if (document.querySelector('[data-index]') || document.querySelector('.visu-icon')) {
+                return 'logged_in';
+            }
+            return window.location.pathname.includes('/visu/') ? 'loading_visu' : 'loading';
+        })();
+    "#;
+
+    let deadline = Instant::now() + max_wait;
+    loop {
+        let state = tab
+            .evaluate(check_js, false)
+            .ok()
+            .and_then(|result| result.value)
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        match state.as_str() {
+            "login_form" => return LoginState::LoginForm,
+            "logged_in" => return LoginState::LoggedIn,
+            _ if Instant::now() >= deadline => {
+                debug!("Page still loading after {:?}, assuming state from URL", max_wait);
+                return if state == "loading_visu" { LoginState::LoggedIn } else { LoginState::LoginForm };
+            }
+            _ => std::thread::sleep(Duration::from_millis(250)),
+        }
+    }
+}
+
+/// First non-empty session id from a `cookie_name` cookie, a JS global, or a
+/// `session_id=` parameter anywhere in the page's HTML, in that order.
+fn find_session_id(cookies: &str, global: &str, html: &str, cookie_name: &str) -> Option<String> {
+    let valid = |id: &str| !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    let from_cookie = cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == cookie_name)
+        .map(|(_, value)| value.trim());
+    let from_html = html.match_indices("session_id=").find_map(|(at, needle)| {
+        let preceded_by_separator = html[..at].ends_with(['?', '&', ';']);
+        let value = &html[at + needle.len()..];
+        let end = value.find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_')).unwrap_or(value.len());
+        (preceded_by_separator && valid(&value[..end])).then(|| &value[..end])
+    });
+
+    from_cookie
+        .filter(|id| valid(id))
+        .or(Some(global.trim()).filter(|id| valid(id)))
+        .or(from_html)
+        .map(str::to_string)
+}
+
+/// The credentials form of the gateway's login page.
+#[derive(Debug, PartialEq)]
+struct LoginForm {
+    action: reqwest::Url,
+    /// Hidden fields (CSRF tokens and the like) to post back along with the credentials.
+    fields: Vec<(String, String)>,
+}
+
+impl LoginForm {
+    /// Finds the form holding the `email` and `password` inputs, as filled in by the
+    /// browser login, and resolves its action against the page URL.
+    fn parse(html: &str, page_url: &reqwest::Url) -> Result<Self> {
+        let lower = html.to_lowercase();
+        if ["g-recaptcha", "h-captcha", "cf-turnstile"].iter().any(|marker| lower.contains(marker)) {
+            anyhow::bail!("Login page asks for a CAPTCHA");
+        }
+
+        let document = Html::parse_document(html);
+        let form_selector = Selector::parse("form").expect("valid selector");
+        let input_selector = Selector::parse("input[name]").expect("valid selector");
+        let form = document
+            .select(&form_selector)
+            .find(|form| {
+                let names: Vec<&str> = form.select(&input_selector).filter_map(|i| i.value().attr("name")).collect();
+                names.contains(&"email") && names.contains(&"password")
+            })
+            .with_context(|| format!("No login form at {}", redact_query(page_url.as_str())))?;
+
+        let action = form.value().attr("action").unwrap_or("");
+        let action = page_url.join(action).with_context(|| format!("Invalid login form action '{action}'"))?;
+        let fields = form
+            .select(&input_selector)
+            .filter(|input| input.value().attr("type").is_some_and(|t| t.eq_ignore_ascii_case("hidden")))
+            .filter_map(|input| {
+                let name = input.value().attr("name")?;
+                Some((name.to_string(), input.value().attr("value").unwrap_or("").to_string()))
+            })
+            .collect();
+        Ok(Self { action, fields })
+    }
+}
+
+/// Drops the query string of a URL, which may carry a session, before logging it.
+fn redact_query(url: &str) -> &str {
+    url.split('?').next().unwrap_or(url)
+}
+
+/// Wait used when a 429 comes without a usable `Retry-After`.
+const DEFAULT_THROTTLE_WAIT: Duration = Duration::from_secs(1);
+/// Upper bound on a gateway-requested wait, so one command can't stall for minutes.
+const MAX_THROTTLE_WAIT: Duration = Duration::from_secs(30);
+
+/// First wait before retrying a command that hit a 5xx or a network error; doubles per attempt.
+const COMMAND_RETRY_BASE: Duration = Duration::from_millis(250);
+/// Upper bound on the backoff between command attempts.
+const MAX_COMMAND_RETRY_WAIT: Duration = Duration::from_secs(4);
+
+/// Backoff before attempt `attempt + 1`. Half of the delay is fixed and half is
+/// scaled by `jitter` (in `0.0..1.0`), so commands from a burst don't retry in lockstep.
+fn retry_backoff(attempt: u32, jitter: f64) -> Duration {
+    let delay = COMMAND_RETRY_BASE
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_COMMAND_RETRY_WAIT);
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
+/// Reads `Retry-After` as delay-seconds or an HTTP date.
+fn retry_after(headers: &reqwest::header::HeaderMap, now: chrono::DateTime<chrono::Utc>) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some((at.with_timezone(&chrono::Utc) - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Rounds a reading to `decimals` places so `21.299999` reaches clients as `21.3`.
+fn round_reading(reading: f32, decimals: u8) -> f32 {
+    let factor = 10f32.powi(i32::from(decimals));
+    (reading * factor).round() / factor
+}
+
+/// Fills a page or command query template. Without a session (it travels in a
+/// cookie) the parameter carrying `{session}` is left out.
+fn fill_query(template: &str, placeholder: &str, value: &str, session: Option<&str>) -> String {
+    template
+        .split('&')
+        .filter_map(|param| match session {
+            Some(session) => Some(param.replace("{session}", session)),
+            None if param.contains("{session}") => None,
+            None => Some(param.to_string()),
+        })
+        .map(|param| param.replace(placeholder, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Hides the usual automation fingerprints before the gateway's login page loads.
+const STEALTH_JS: &str = r"
+    Object.defineProperty(navigator, 'webdriver', {get: () => undefined});
+
+    window.chrome = {
+        runtime: {},
+        loadTimes: function() {},
+        csi: function() {},
+        app: {}
+    };
+
+    Object.defineProperty(navigator, 'plugins', {
+        get: () => [1, 2, 3, 4, 5]
+    });
+
+    Object.defineProperty(navigator, 'languages', {
+        get: () => ['en-US', 'en', 'de']
+    });
+
+    const originalQuery = window.navigator.permissions.query;
+    window.navigator.permissions.query = (parameters) => (
+        parameters.name === 'notifications' ?
+            Promise.resolve({ state: Notification.permission }) :
+            originalQuery(parameters)
+    );
+";
+
+/// Injects the stealth script, retrying once. A failure is logged rather than fatal,
+/// but login may then trip the gateway's bot detection.
+pub fn inject_stealth(tab: &headless_chrome::Tab) {
+    for attempt in 1..=2 {
+        match tab.evaluate(STEALTH_JS, false) {
+            Ok(_) => return,
+            Err(e) => warn!("Stealth script injection failed (attempt {}/2): {}", attempt, e),
+        }
+    }
+    warn!("Continuing without stealth; the gateway may detect the automated browser and block login");
+}
+
+/// Destination for device commands, and the page reads behind discovery and the
+/// checks of a failed command. `KnxClient` talks to the gateway; tests substitute
+/// a recording sink.
+pub trait KnxCommandSink: Send + Sync {
+    fn send_command<'a>(&'a self, command: &'a str) -> BoxFuture<'a, Result<()>>;
+
+    fn discover_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>>;
+
+    fn discover_page_devices<'a>(&'a self, page: &'a str) -> BoxFuture<'a, Result<Vec<Device>>>;
+}
 
-#[derive(Debug)]
 pub struct KnxClient {
     client: reqwest::Client,
+    /// Follows the login redirects with a cookie jar; see [`Self::login_with_http`].
+    login_client: reqwest::Client,
     config: Arc<KnxConfig>,
     session_id: Arc<RwLock<String>>,
     headless: bool,
+    rate_limiter: Option<RateLimiter>,
+    session_established: AtomicBool,
+    page_cache: std::sync::Mutex<HashMap<String, CachedPage>>,
+    /// Sensors already warned about for lacking a numeric reading; repeats log at debug.
+    unread_sensors: std::sync::Mutex<HashSet<String>>,
+    session_listeners: std::sync::Mutex<Vec<SessionListener>>,
+    /// Serializes re-logins so concurrent 401s trigger a single login.
+    login_lock: tokio::sync::Mutex<()>,
+    /// Held while a command is on the wire; remembers when the last one finished
+    /// so the next waits out `command_spacing`.
+    command_slot: tokio::sync::Mutex<Option<Instant>>,
+    /// Commands inside `send_command`, including those still waiting for a token or their slot.
+    commands_in_flight: AtomicUsize,
+    /// Notified when `commands_in_flight` drops to zero.
+    commands_drained: tokio::sync::Notify,
+}
+
+/// Counts a command as in flight until dropped.
+struct InFlightCommand<'a>(&'a KnxClient);
+
+impl Drop for InFlightCommand<'_> {
+    fn drop(&mut self) {
+        if self.0.commands_in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.commands_drained.notify_waiters();
+        }
+    }
+}
+
+type SessionListener = Box<dyn Fn() + Send + Sync>;
+
+/// Devices parsed from a page together with the validators needed to ask the
+/// gateway whether the page has changed since.
+#[derive(Debug)]
+struct CachedPage {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    devices: Vec<Device>,
 }
 
 impl KnxClient {
     pub fn new(config: Arc<KnxConfig>, headless: bool) -> Result<Self> {
-        let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
+        if config.insecure_tls {
+            warn!("TLS certificate verification is disabled (set SMARTHOME_INSECURE_TLS=false to enforce it)");
+        }
+        let certificates = match &config.ca_bundle {
+            Some(path) => {
+                let pem = std::fs::read(path)
+                    .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+                let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+                    .with_context(|| format!("Invalid CA bundle {}", path.display()))?;
+                info!("Trusting {} additional CA certificates from {}", certificates.len(), path.display());
+                certificates
+            }
+            None => Vec::new(),
+        };
+        let builder = || {
+            let mut builder = reqwest::Client::builder().danger_accept_invalid_certs(config.insecure_tls);
+            for certificate in &certificates {
+                builder = builder.add_root_certificate(certificate.clone());
+            }
+            builder
+        };
+        let client = builder().build().context("Failed to create HTTP client")?;
+        // Keeps the login provider's cookies between logins, like Chrome's profile does.
+        let login_client = builder()
+            .cookie_store(true)
             .build()
-            .context("Failed to create HTTP client")?;
+            .context("Failed to create HTTP login client")?;
 
         let session_id = Arc::new(RwLock::new(String::new()));
 
-        Ok(Self { client, config, session_id, headless })
+        let rate_limiter = config.commands_per_sec.map(|rate| {
+            let burst = config.command_burst.unwrap_or_else(|| rate.max(1.0));
+            info!("Rate limiting commands to {}/s (burst {})", rate, burst);
+            RateLimiter::new(rate, burst)
+        });
+
+        Ok(Self {
+            client,
+            login_client,
+            config,
+            session_id,
+            headless,
+            rate_limiter,
+            session_established: AtomicBool::new(false),
+            page_cache: std::sync::Mutex::new(HashMap::new()),
+            unread_sensors: std::sync::Mutex::new(HashSet::new()),
+            session_listeners: std::sync::Mutex::new(Vec::new()),
+            login_lock: tokio::sync::Mutex::new(()),
+            command_slot: tokio::sync::Mutex::new(None),
+            commands_in_flight: AtomicUsize::new(0),
+            commands_drained: tokio::sync::Notify::new(),
+        })
     }
 
-    #[allow(dead_code)]
-    pub async fn validate_session(&self) -> Result<bool> {
-        let url = {
-            let session_id = self.session_id.read().await;
-            format!(
-                "{}/visu/index.fcgi?00&session_id={}&lang=en",
-                self.config.base_url, *session_id
-            )
+    async fn current_session(&self) -> String {
+        self.session_id.read().await.clone()
+    }
+
+    /// The session to put in URLs; `None` when it travels in a cookie instead.
+    fn url_session<'a>(&self, session_id: &'a str) -> Option<&'a str> {
+        self.config.session_cookie.is_none().then_some(session_id)
+    }
+
+    fn page_url(&self, page: &str, session_id: &str) -> String {
+        format!(
+            "{}{}?{}",
+            self.config.base_url,
+            self.config.page_path,
+            fill_query(&self.config.page_query, "{page}", page, self.url_session(session_id))
+        )
+    }
+
+    fn command_url(&self, command: &str, session_id: &str) -> String {
+        format!(
+            "{}{}?{}",
+            self.config.base_url,
+            self.config.command_path,
+            fill_query(&self.config.command_query, "{command}", command, self.url_session(session_id))
+        )
+    }
+
+    /// `SMARTHOME_STATUS_URL` with its query filled in like a page query.
+    fn status_url(&self, template: &str, session_id: &str) -> String {
+        let (path, query) = template.split_once('?').unwrap_or((template, ""));
+        let query = fill_query(query, "{session}", session_id, self.url_session(session_id));
+        if query.is_empty() {
+            format!("{}{}", self.config.base_url, path)
+        } else {
+            format!("{}{}?{}", self.config.base_url, path, query)
+        }
+    }
+
+    /// Attaches the session as a cookie when `SMARTHOME_SESSION_COOKIE` is set;
+    /// otherwise the session already travels in the URL.
+    fn with_session(&self, request: reqwest::RequestBuilder, session_id: &str) -> reqwest::RequestBuilder {
+        let Some(cookie_name) = &self.config.session_cookie else {
+            return request;
         };
 
+        match HeaderValue::from_str(&format!("{cookie_name}={session_id}")) {
+            Ok(mut value) => {
+                value.set_sensitive(true);
+                request.header(COOKIE, value)
+            }
+            Err(_) => {
+                warn!("Session id is not a valid cookie value, sending request without it");
+                request
+            }
+        }
+    }
+
+    pub async fn validate_session(&self) -> Result<bool> {
+        let session_id = self.current_session().await;
+        let url = self.page_url("00", &session_id);
+
         debug!("Validating session with test request (session_id: [REDACTED])");
 
-        match self.client.get(&url).send().await {
+        match self.with_session(self.client.get(&url), &session_id).send().await {
             Ok(response) => {
                 if response.status().is_success() {
                     info!("Session is valid");
@@ -69,69 +424,174 @@ impl KnxClient {
         Ok(())
     }
 
-    async fn check_and_refresh_if_unauthorized(&self, response: &reqwest::Response) -> Result<bool> {
+    /// Refreshes the session after a 401 unless a concurrent request already
+    /// replaced `session_id`, the session the failed request was sent with.
+    async fn check_and_refresh_if_unauthorized(&self, response: &reqwest::Response, session_id: &str) -> Result<bool> {
         if response.status() == 401 {
-            warn!("Got 401 Unauthorized - session expired, refreshing...");
-            self.refresh_session().await?;
+            let _login = self.login_lock.lock().await;
+            if self.current_session().await == session_id {
+                warn!("Got 401 Unauthorized - session expired, refreshing...");
+                self.refresh_session().await?;
+            } else {
+                debug!("Got 401 Unauthorized - session already refreshed, retrying");
+            }
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    /// Reads the configured pages, or auto-detects them from 01 up to the first
+    /// empty page. Pages are fetched `discovery_concurrency` at a time; within a
+    /// window results are handled in page order, so devices stay ordered by page.
     pub async fn discover_devices(&self) -> Result<Vec<Device>> {
         let mut devices = Vec::new();
+        let deadline = self.config.discovery_timeout.map(|t| Instant::now() + t);
 
-        info!("Auto-detecting pages...");
-        for page_num in 1..=99 {
-            let page = format!("{page_num:02}");
-
-            info!("Discovering devices on page {}", page);
-            let page_devices = self.discover_page_devices(&page).await?;
+        let auto_detect = self.config.pages.is_empty();
+        let pages: Vec<String> = if auto_detect {
+            info!("Auto-detecting pages...");
+            (1..=99).map(|page_num| format!("{page_num:02}")).collect()
+        } else {
+            self.config.pages.clone()
+        };
+        let concurrency = self.config.discovery_concurrency.max(1);
+
+        'windows: for window in pages.chunks(concurrency) {
+            let mut fetched: Vec<(usize, Result<Option<Vec<Device>>>)> = stream::iter(window.iter().cloned().enumerate())
+                .map(|(i, page)| async move { (i, self.discover_page_before(&page, deadline).await) })
+                .buffer_unordered(concurrency)
+                .collect()
+                .await;
+            fetched.sort_by_key(|(i, _)| *i);
+
+            for (page, (_, result)) in window.iter().zip(fetched) {
+                let Some(page_devices) = result? else {
+                    warn!(
+                        "Discovery timed out at page {}, continuing with {} devices found so far",
+                        page,
+                        devices.len()
+                    );
+                    break 'windows;
+                };
+
+                if page_devices.is_empty() {
+                    if auto_detect {
+                        info!("Page {} is empty, stopping auto-detection", page);
+                        break 'windows;
+                    }
+                    warn!("Configured page {} has no devices", page);
+                    continue;
+                }
 
-            if page_devices.is_empty() {
-                info!("Page {} is empty, stopping auto-detection", page);
-                break;
+                info!("Found {} devices on page {}", page_devices.len(), page);
+                devices.extend(page_devices);
             }
-
-            info!("Found {} devices on page {}", page_devices.len(), page);
-            devices.extend(page_devices);
         }
 
         info!("Total devices discovered: {}", devices.len());
         Ok(devices)
     }
 
-    async fn discover_page_devices(&self, page: &str) -> Result<Vec<Device>> {
-        let url = {
-            let session_id = self.session_id.read().await;
-            format!(
-                "{}/visu/index.fcgi?{}&session_id={}&lang=en",
-                self.config.base_url, page, *session_id
-            )
-        };
+    /// Fetches one page, or `None` if `deadline` passes first.
+    async fn discover_page_before(&self, page: &str, deadline: Option<Instant>) -> Result<Option<Vec<Device>>> {
+        info!("Discovering devices on page {}", page);
+        let fetch = self.discover_page_devices(page);
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), fetch).await.ok().transpose(),
+            None => fetch.await.map(Some),
+        }
+    }
+
+    pub async fn discover_page_devices(&self, page: &str) -> Result<Vec<Device>> {
+        let mut session_id = self.current_session().await;
+        let url = self.page_url(page, &session_id);
 
         debug!("Fetching page {} (session_id: [REDACTED])", page);
-        let response = self.client.get(&url).send().await?;
-
-        if self.check_and_refresh_if_unauthorized(&response).await? {
-            let url = {
-                let session_id = self.session_id.read().await;
-                format!(
-                    "{}/visu/index.fcgi?{}&session_id={}&lang=en",
-                    self.config.base_url, page, *session_id
-                )
+        let request = self.conditional(self.client.get(&url), page);
+        let mut response = self.with_session(request, &session_id).send().await?;
+
+        if self.check_and_refresh_if_unauthorized(&response, &session_id).await? {
+            session_id = self.current_session().await;
+            let url = self.page_url(page, &session_id);
+            response = self.with_session(self.client.get(&url), &session_id).send().await?;
+        }
+
+        if let Some(devices) = self.devices_from_response(response, page).await? {
+            return Ok(devices);
+        }
+        // A 304 for a page that isn't cached (any more) carries nothing to parse.
+        debug!("Page {} answered 304 without a cached copy, fetching it in full", page);
+        let url = self.page_url(page, &session_id);
+        let response = self.with_session(self.client.get(&url), &session_id).send().await?;
+        self.devices_from_response(response, page)
+            .await?
+            .with_context(|| format!("Page {page} answered 304 to an unconditional request"))
+    }
+
+    /// Adds `If-None-Match`/`If-Modified-Since` from the last response for this page,
+    /// when conditional requests are enabled.
+    fn conditional(&self, request: reqwest::RequestBuilder, page: &str) -> reqwest::RequestBuilder {
+        if !self.config.conditional_requests {
+            return request;
+        }
+        let cache = self.page_cache.lock().unwrap();
+        let Some(cached) = cache.get(page) else {
+            return request;
+        };
+        let request = match &cached.etag {
+            Some(etag) => request.header(IF_NONE_MATCH, etag),
+            None => request,
+        };
+        match &cached.last_modified {
+            Some(last_modified) => request.header(IF_MODIFIED_SINCE, last_modified),
+            None => request,
+        }
+    }
+
+    /// The devices of a page response; `None` for a 304 with nothing cached to reuse.
+    async fn devices_from_response(&self, response: reqwest::Response, page: &str) -> Result<Option<Vec<Device>>> {
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            let cache = self.page_cache.lock().unwrap();
+            let Some(cached) = cache.get(page) else {
+                return Ok(None);
             };
-            let response = self.client.get(&url).send().await?;
-            let html = response.text().await?;
-            return Ok(Self::parse_devices(&html, page));
+            debug!("Page {} unchanged, reusing {} cached devices", page, cached.devices.len());
+            return Ok(Some(cached.devices.clone()));
+        }
+        if !response.status().is_success() {
+            // Parsing an error page would report the page as empty and end auto-detection.
+            anyhow::bail!("Page {} returned {}", page, response.status());
         }
 
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let last_modified = header(LAST_MODIFIED);
+
         let html = response.text().await?;
-        Ok(Self::parse_devices(&html, page))
+        let devices = Self::parse_page(&html, page, &self.config, &mut self.unread_sensors.lock().unwrap());
+
+        if self.config.conditional_requests && (etag.is_some() || last_modified.is_some()) {
+            self.page_cache.lock().unwrap().insert(
+                page.to_string(),
+                CachedPage { etag, last_modified, devices: devices.clone() },
+            );
+        }
+        Ok(Some(devices))
     }
 
-    fn parse_devices(html: &str, page: &str) -> Vec<Device> {
+    pub fn parse_devices(html: &str, page: &str, config: &KnxConfig) -> Vec<Device> {
+        Self::parse_page(html, page, config, &mut HashSet::new())
+    }
+
+    /// Parses a page, warning only about sensors not yet in `unread_sensors`.
+    fn parse_page(html: &str, page: &str, config: &KnxConfig, unread_sensors: &mut HashSet<String>) -> Vec<Device> {
         let document = Html::parse_document(html);
         let mut devices = Vec::new();
 
@@ -139,6 +599,9 @@ impl KnxClient {
         let name_selector = Selector::parse(".visu-element-name").unwrap();
         let button_selector = Selector::parse(".visu-icon").unwrap();
         let status_selector = Selector::parse(".visu-status-text").unwrap();
+        // Validated when the config is loaded; an unparsable override just disables detection.
+        let battery_selector = Selector::parse(&config.battery_selector).ok();
+        let signal_selector = Selector::parse(&config.signal_selector).ok();
 
         for element in document.select(&element_selector) {
             let id = match element.value().attr("id") {
@@ -152,39 +615,82 @@ impl KnxClient {
                 .unwrap_or("")
                 .to_string();
 
-            let name = element
+            let mut name = element
                 .select(&name_selector)
-                .next().map_or_else(|| id.clone(), |n| n.text().collect::<String>().trim().to_string());
+                .next().map_or_else(|| id.clone(), |n| n.text().collect::<String>().trim().nfc().collect());
 
             if name.is_empty() {
-                continue;
+                if config.skip_nameless_devices {
+                    debug!("Skipping nameless device: {}", id);
+                    continue;
+                }
+                name = Self::fallback_name(&element, &id);
+                debug!("Device {} has no name, using fallback: {}", id, name);
             }
 
-            let classes = element.value().attr("class").unwrap_or("");
-            let type_ = Self::detect_device_type(classes, &name);
-
             if name.contains("Datum") || name.contains("Uhrzeit") {
                 debug!("Skipping informational device: {}", name);
                 continue;
             }
 
-            let is_active = element
-                .select(&button_selector)
-                .next()
-                .is_some_and(|btn| btn.value().attr("class").unwrap_or("").contains("btn-active"));
+            if !config.name_filter.allows(&name) {
+                debug!("Skipping device filtered by name: {}", name);
+                continue;
+            }
+
+            let is_active = Self::is_active(&element, &button_selector, config);
 
             let status_text = element
                 .select(&status_selector)
                 .next()
                 .map(|s| s.text().collect::<String>().trim().to_string());
 
+            let classes = element.value().attr("class").unwrap_or("");
+            let has_button = element.select(&button_selector).next().is_some();
+            let type_ = match config.detection_rules.iter().find(|rule| rule.matches(classes, &name)) {
+                Some(rule) => rule.device_type.clone(),
+                None => {
+                    let type_ = Self::detect_device_type(classes, &name);
+                    if type_ == DeviceType::Light
+                        && !has_button
+                        && status_text.as_deref().is_some_and(|t| t.ends_with('%'))
+                    {
+                        DeviceType::HumiditySensor
+                    } else {
+                        type_
+                    }
+                }
+            };
+
             debug!(
                 "Found device: id={}, name={}, type={:?}, index={}, active={}, status={:?}",
                 id, name, type_, index, is_active, status_text
             );
 
+            let reading = status_text.as_deref().and_then(Self::parse_reading);
+            let is_sensor = type_.is_sensor();
             let mut device = Device::new(id, name, type_, page.to_string(), index);
+            if reading.is_none() && is_sensor {
+                let status_text = status_text.as_deref().unwrap_or_default();
+                if unread_sensors.insert(device.key()) {
+                    warn!(
+                        "No numeric reading for sensor {} ({}) in status text {:?}, keeping 0",
+                        device.name, device.id, status_text
+                    );
+                } else {
+                    debug!("Still no numeric reading for sensor {} in status text {:?}", device.id, status_text);
+                }
+            }
             device.set_on(is_active);
+            if let Some(reading) = reading {
+                Self::apply_reading(&mut device, round_reading(reading, config.reading_decimals));
+            }
+            device.battery_low = battery_selector
+                .as_ref()
+                .and_then(|selector| Self::battery_low(&element, selector, config));
+            device.signal = signal_selector
+                .as_ref()
+                .and_then(|selector| Self::signal(&element, selector));
 
             devices.push(device);
         }
@@ -192,9 +698,70 @@ impl KnxClient {
         devices
     }
 
+    /// Whether the device's icon, or the element itself, carries one of the configured
+    /// "on" classes or an "on"-valued state attribute.
+    fn is_active(element: &scraper::ElementRef, icon_selector: &Selector, config: &KnxConfig) -> bool {
+        element.select(icon_selector).next().into_iter().chain(std::iter::once(*element)).any(|el| {
+            let classes_match = el
+                .value()
+                .classes()
+                .any(|class| config.active_classes.iter().any(|active| active == class));
+            let attribute_match = config.active_attributes.iter().any(|attr| {
+                el.value().attr(attr).is_some_and(|value| {
+                    matches!(value.trim().to_lowercase().as_str(), "on" | "1" | "true" | "active")
+                })
+            });
+            classes_match || attribute_match
+        })
+    }
+
+    /// `Some(true)` if the element has a battery indicator carrying a low class,
+    /// `Some(false)` for an indicator without one, `None` without an indicator.
+    fn battery_low(element: &scraper::ElementRef, selector: &Selector, config: &KnxConfig) -> Option<bool> {
+        let indicator = element.select(selector).next()?;
+        Some(
+            indicator
+                .value()
+                .classes()
+                .any(|class| config.battery_low_classes.iter().any(|low| low == class)),
+        )
+    }
+
+    /// Signal strength from the indicator's `data-value`, falling back to its text
+    /// (e.g. "75%"); clamped to 100.
+    fn signal(element: &scraper::ElementRef, selector: &Selector) -> Option<u8> {
+        let indicator = element.select(selector).next()?;
+        let raw = indicator
+            .value()
+            .attr("data-value")
+            .map(str::to_string)
+            .unwrap_or_else(|| indicator.text().collect());
+        let value: f32 = raw.trim().trim_end_matches('%').trim().replace(',', ".").parse().ok()?;
+        (value >= 0.0).then(|| value.min(100.0).round() as u8)
+    }
+
+    /// Derives a name for an element whose `.visu-element-name` is empty, preferring
+    /// a `title`/`aria-label` on the element or its descendants over the raw id.
+    fn fallback_name(element: &scraper::ElementRef, id: &str) -> String {
+        std::iter::once(*element)
+            .chain(element.descendants().filter_map(scraper::ElementRef::wrap))
+            .find_map(|el| {
+                ["title", "aria-label"]
+                    .iter()
+                    .filter_map(|attr| el.value().attr(attr))
+                    .map(str::trim)
+                    .find(|label| !label.is_empty())
+            })
+            .map_or_else(|| id.to_string(), str::to_string)
+    }
+
     fn detect_device_type(classes: &str, name: &str) -> DeviceType {
         let name_lower = name.to_lowercase();
 
+        if name_lower.contains("feuchte") || name_lower.contains("humidity") {
+            return DeviceType::HumiditySensor;
+        }
+
         if name_lower.contains("temperatur") || name_lower.contains("temp.") {
             return DeviceType::TemperatureSensor;
         }
@@ -215,51 +782,373 @@ impl KnxClient {
             return DeviceType::Fan;
         }
 
+        if name_lower.contains("steckdose") || name_lower.contains("socket") || name_lower.contains("outlet") {
+            return DeviceType::Outlet;
+        }
+
         DeviceType::Light
     }
 
+    /// Applies a numeric reading to sensors, outlets (watts) and blinds (percent);
+    /// returns whether the device took it.
+    fn apply_reading(device: &mut Device, reading: f32) -> bool {
+        match &mut device.state {
+            DeviceState::Temperature(value) | DeviceState::Humidity(value) => *value = reading,
+            DeviceState::Outlet { in_use, .. } => *in_use = reading > 0.0,
+            DeviceState::WindowCovering { position, .. } => {
+                #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                let percent = reading.round().clamp(0.0, 100.0) as u8;
+                *position = percent;
+            }
+            _ => return false,
+        }
+        device.has_reading = true;
+        true
+    }
+
+    /// Applies a status text from [`Self::fetch_all_states`] the same way a page's
+    /// `.visu-status-text` would be.
+    pub fn apply_status_text(&self, device: &mut Device, text: &str) -> bool {
+        Self::parse_reading(text).is_some_and(|reading| {
+            Self::apply_reading(device, round_reading(reading, self.config.reading_decimals))
+        })
+    }
+
+    /// Whether `SMARTHOME_STATUS_URL` is set, so polling can use one request.
+    pub fn has_status_endpoint(&self) -> bool {
+        self.config.status_url_template.is_some()
+    }
+
+    /// Reads every current value from the batched status endpoint, keyed by
+    /// `(page, index)`. The endpoint answers with a JSON array of
+    /// `{"page": "02", "index": "5", "value": "21,5 °C"}` entries.
+    pub async fn fetch_all_states(&self) -> Result<HashMap<(String, String), String>> {
+        let template = self
+            .config
+            .status_url_template
+            .as_deref()
+            .context("SMARTHOME_STATUS_URL is not set")?;
+
+        let session_id = self.current_session().await;
+        let url = self.status_url(template, &session_id);
+        let mut response = self.with_session(self.client.get(&url), &session_id).send().await?;
+
+        if self.check_and_refresh_if_unauthorized(&response, &session_id).await? {
+            let session_id = self.current_session().await;
+            let url = self.status_url(template, &session_id);
+            response = self.with_session(self.client.get(&url), &session_id).send().await?;
+        }
+        if !response.status().is_success() {
+            anyhow::bail!("Status endpoint returned {}", response.status());
+        }
+
+        Self::parse_status_response(&response.text().await?)
+    }
+
+    fn parse_status_response(body: &str) -> Result<HashMap<(String, String), String>> {
+        #[derive(Deserialize)]
+        struct StatusEntry {
+            page: String,
+            index: String,
+            value: serde_json::Value,
+        }
+
+        let entries: Vec<StatusEntry> =
+            serde_json::from_str(body).context("Status endpoint did not return a JSON list of values")?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let value = match entry.value {
+                    serde_json::Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                ((entry.page, entry.index), value)
+            })
+            .collect())
+    }
+
+    /// Extracts the first numeric value from a status text such as "21.5 °C", "45 %"
+    /// or "Ist: 21,5". A comma is the decimal separator when both appear ("1.021,5").
+    fn parse_reading(text: &str) -> Option<f32> {
+        let chars: Vec<char> = text.chars().collect();
+        let start = chars.iter().enumerate().position(|(i, c)| {
+            c.is_ascii_digit() || (matches!(c, '-' | '+') && chars.get(i + 1).is_some_and(char::is_ascii_digit))
+        })?;
+        let number: String = chars[start..]
+            .iter()
+            .enumerate()
+            .take_while(|(i, c)| c.is_ascii_digit() || matches!(c, '.' | ',') || (*i == 0 && matches!(c, '-' | '+')))
+            .map(|(_, c)| *c)
+            .collect();
+
+        let number = if number.contains(',') { number.replace('.', "").replace(',', ".") } else { number };
+        number.trim_end_matches('.').parse().ok()
+    }
+
+    pub fn rate_limit_status(&self) -> Option<RateLimitStatus> {
+        self.rate_limiter.as_ref().map(RateLimiter::status)
+    }
+
+    /// Sends a command, retrying 429s after the gateway's `Retry-After`, 5xx and
+    /// network errors with exponential backoff, and a 401 once after a re-login.
     pub async fn send_command(&self, command: &str) -> Result<()> {
-        let session_id = self.session_id.read().await;
-        let url = format!(
-            "{}/visu/controlKNX?{}&session_id={}",
-            self.config.base_url, command, *session_id
-        );
-        drop(session_id);
-
-        debug!("Sending command: {} (session_id: [REDACTED])", command);
-        let response = self.client.post(&url).send().await?;
-
-        if response.status().is_success() {
-            debug!("Command sent successfully");
-            Ok(())
-        } else if response.status() == 401 {
-            warn!("Session expired (401), refreshing session...");
-            self.refresh_session().await?;
-            let session_id = self.session_id.read().await;
-            let url = format!(
-                "{}/visu/controlKNX?{}&session_id={}",
-                self.config.base_url, command, *session_id
-            );
-            drop(session_id);
+        let attempts = self.config.command_retries.max(1);
+        let mut attempt = 1;
+        let mut throttled = 0;
+        let mut refreshed = false;
+        let _in_flight = self.track_command();
+        METRICS.command_sent();
+        loop {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire(crate::rate_limiter::current_priority()).await;
+            }
 
-            debug!("Retrying command with new session: {}", url);
-            let response = self.client.post(&url).send().await?;
+            let session_id = self.current_session().await;
+            let url = self.command_url(command, &session_id);
 
-            if response.status().is_success() {
-                debug!("Command sent successfully after session refresh");
-                Ok(())
-            } else {
-                warn!("Command failed after session refresh: {}", response.status());
-                Err(anyhow::anyhow!("Command failed after refresh: {}", response.status()))
+            debug!("Sending command: {} (session_id: [REDACTED])", command);
+            let response = match self.send_queued(self.with_session(self.client.post(&url), &session_id)).await {
+                Ok(response) => response,
+                Err(e) if attempt < attempts => {
+                    self.wait_before_retry(attempt, attempts, &e).await;
+                    attempt += 1;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("Command failed after {} attempts: {}", attempt, e);
+                    METRICS.command_failed("network");
+                    return Err(anyhow::Error::new(e).context(format!("Command failed after {attempt} attempts")));
+                }
+            };
+            let status = response.status();
+
+            if status.is_success() {
+                if let Err(e) = self.check_command_body(response).await {
+                    METRICS.command_failed("rejected");
+                    return Err(e);
+                }
+                debug!("Command sent successfully");
+                return Ok(());
+            }
+
+            if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                throttled += 1;
+                if throttled > self.config.throttle_retries {
+                    warn!("Gateway still throttling after {} retries, giving up on command", self.config.throttle_retries);
+                    METRICS.command_failed(status.as_u16());
+                    return Err(anyhow::anyhow!("Command failed: gateway rate limit (429)"));
+                }
+                let wait = retry_after(response.headers(), chrono::Utc::now())
+                    .unwrap_or(DEFAULT_THROTTLE_WAIT)
+                    .min(MAX_THROTTLE_WAIT);
+                warn!(
+                    "Gateway throttled command (429), retrying in {}ms ({}/{})",
+                    wait.as_millis(),
+                    throttled,
+                    self.config.throttle_retries
+                );
+                // With a rate limiter every queued command backs off, not just this one.
+                match &self.rate_limiter {
+                    Some(rate_limiter) => rate_limiter.back_off(wait),
+                    None => tokio::time::sleep(wait).await,
+                }
+                continue;
             }
+
+            if status == reqwest::StatusCode::UNAUTHORIZED {
+                if refreshed {
+                    warn!("Command failed after session refresh: {}", status);
+                    METRICS.command_failed(status.as_u16());
+                    return Err(anyhow::anyhow!("Command failed after refresh: {status}"));
+                }
+                if let Err(e) = self.check_and_refresh_if_unauthorized(&response, &session_id).await {
+                    METRICS.command_failed(status.as_u16());
+                    return Err(e);
+                }
+                refreshed = true;
+                continue;
+            }
+
+            if status.is_server_error() {
+                if attempt < attempts {
+                    self.wait_before_retry(attempt, attempts, &status).await;
+                    attempt += 1;
+                    continue;
+                }
+                warn!("Command failed after {} attempts: {}", attempt, status);
+                METRICS.command_failed(status.as_u16());
+                return Err(anyhow::anyhow!("Command failed after {attempt} attempts: {status}"));
+            }
+
+            warn!("Command failed with status: {}", status);
+            METRICS.command_failed(status.as_u16());
+            return Err(anyhow::anyhow!("Command failed: {status}"));
+        }
+    }
+
+    fn track_command(&self) -> InFlightCommand<'_> {
+        self.commands_in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightCommand(self)
+    }
+
+    /// Waits up to `timeout` for commands already being sent to finish, so a restart
+    /// doesn't cut one off halfway. Returns how many were still pending when it gave up.
+    pub async fn shutdown(&self, timeout: Duration) -> usize {
+        let pending = self.commands_in_flight.load(Ordering::SeqCst);
+        if pending == 0 {
+            info!("No commands pending at shutdown");
+            return 0;
+        }
+        info!("{} commands pending at shutdown, waiting up to {}s", pending, timeout.as_secs());
+
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                let drained = self.commands_drained.notified();
+                tokio::pin!(drained);
+                drained.as_mut().enable();
+                if self.commands_in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                drained.await;
+            }
+        })
+        .await;
+
+        let remaining = self.commands_in_flight.load(Ordering::SeqCst);
+        if drained.is_ok() {
+            info!("All pending commands finished");
         } else {
-            warn!("Command failed with status: {}", response.status());
-            Err(anyhow::anyhow!("Command failed: {}", response.status()))
+            warn!("{} commands still pending after {}s, abandoning them", remaining, timeout.as_secs());
         }
+        remaining
+    }
+
+    /// Sends a command request once the previous command is done and
+    /// `command_spacing` has passed; waiters are served in arrival order.
+    async fn send_queued(&self, request: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let mut last_sent = self.command_slot.lock().await;
+        if let Some(last_sent) = *last_sent {
+            tokio::time::sleep_until((last_sent + self.config.command_spacing).into()).await;
+        }
+        let response = request.send().await;
+        *last_sent = Some(Instant::now());
+        response
+    }
+
+    async fn wait_before_retry(&self, attempt: u32, attempts: u32, reason: &(dyn std::fmt::Display + Sync)) {
+        let wait = retry_backoff(attempt, rand::random());
+        warn!("Command attempt {}/{} failed ({}), retrying in {}ms", attempt, attempts, reason, wait.as_millis());
+        tokio::time::sleep(wait).await;
+    }
+
+    /// Fails a 2xx command response whose body carries a gateway error message.
+    async fn check_command_body(&self, response: reqwest::Response) -> Result<()> {
+        let body = response.text().await.unwrap_or_default();
+
+        if let Some(pattern) = Self::detect_soft_error(&body, &self.config.command_error_patterns) {
+            let excerpt = body_excerpt(&body);
+            warn!("Gateway rejected command (matched '{}'): {}", pattern, excerpt);
+            return Err(anyhow::anyhow!("Gateway rejected command: {excerpt}"));
+        }
+
+        Ok(())
+    }
+
+    /// The first pattern found in a plain-text body. Markup is skipped: pages and
+    /// scripts mention "error" in handlers and class names without anything failing.
+    fn detect_soft_error<'a>(body: &str, patterns: &'a [String]) -> Option<&'a str> {
+        let body = body.trim();
+        if body.starts_with('<') {
+            return None;
+        }
+        let body = body.to_lowercase();
+        patterns
+            .iter()
+            .find(|pattern| body.contains(&pattern.to_lowercase()))
+            .map(String::as_str)
     }
 
-    #[allow(clippy::too_many_lines)]
     async fn refresh_session(&self) -> Result<()> {
+        match self.config.login_mode {
+            LoginMode::Chrome => self.login_with_browser().await?,
+            LoginMode::Http => self.login_with_http().await?,
+            LoginMode::Auto => {
+                if let Err(e) = self.login_with_http().await {
+                    warn!("HTTP login failed ({:#}), falling back to the headless browser", e);
+                    self.login_with_browser().await?;
+                }
+            }
+        }
+        self.session_established.store(true, Ordering::Relaxed);
+        METRICS.session_refreshed();
+        for listener in self.session_listeners.lock().unwrap().iter() {
+            listener();
+        }
+        Ok(())
+    }
+
+    /// Registers a callback run after every successful browser login.
+    pub fn on_session_refreshed(&self, listener: impl Fn() + Send + Sync + 'static) {
+        self.session_listeners.lock().unwrap().push(Box::new(listener));
+    }
+
+    /// Whether a login has succeeded at least once since startup.
+    pub fn session_established(&self) -> bool {
+        self.session_established.load(Ordering::Relaxed)
+    }
+
+    /// Logs in without a browser: loads the visu, follows the redirects to the login
+    /// form, posts the credentials with the form's hidden fields and takes
+    /// `session_id` from wherever the redirects end up. Fails on forms it can't
+    /// fill, e.g. ones with a CAPTCHA.
+    async fn login_with_http(&self) -> Result<()> {
+        info!("Refreshing session with an HTTP form login...");
+
+        let username = env::var("SMARTHOME_USERNAME")
+            .context("SMARTHOME_USERNAME not set in .env")?;
+        let password = env::var("SMARTHOME_PASSWORD")
+            .context("SMARTHOME_PASSWORD not set in .env")?;
+
+        let start_url = format!("{}{}?00", self.config.base_url, self.config.page_path);
+        let response = self.login_client.get(&start_url).send().await?.error_for_status()?;
+        let page_url = response.url().clone();
+        let html = response.text().await?;
+
+        let new_session_id = if let Some(session_id) = self.session_id_from_response(&page_url, &html) {
+            info!("✅ Already logged in (session restored from cookies)");
+            session_id
+        } else {
+            let form = LoginForm::parse(&html, &page_url)?;
+            info!("Submitting login form...");
+            let mut fields = form.fields;
+            fields.push(("email".to_string(), username));
+            fields.push(("password".to_string(), password));
+            let response = self.login_client.post(form.action).form(&fields).send().await?.error_for_status()?;
+            let final_url = response.url().clone();
+            let html = response.text().await?;
+            self.session_id_from_response(&final_url, &html).with_context(|| {
+                format!("Login did not lead to a session (ended at {})", redact_query(final_url.as_str()))
+            })?
+        };
+
+        info!("New session ID obtained: [REDACTED]");
+        let mut session_id = self.session_id.write().await;
+        (*session_id).clone_from(&new_session_id);
+        info!("Session ready!");
+        Ok(())
+    }
+
+    /// The session a login response landed on: in the final URL, or in the page's links.
+    fn session_id_from_response(&self, url: &reqwest::Url, html: &str) -> Option<String> {
+        if let Ok(session_id) = Self::extract_session_id(url.as_str()) {
+            return Some(session_id);
+        }
+        let cookie_name = self.config.session_cookie.as_deref().unwrap_or("session_id");
+        find_session_id("", "", html, cookie_name)
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn login_with_browser(&self) -> Result<()> {
         info!("Refreshing session using headless browser...");
 
         let username = env::var("SMARTHOME_USERNAME")
@@ -308,59 +1197,14 @@ impl KnxClient {
 
         let tab = browser.new_tab().context("Failed to create new tab")?;
 
-        tab.evaluate(
-            r"
-            Object.defineProperty(navigator, 'webdriver', {get: () => undefined});
-            
-            window.chrome = {
-                runtime: {},
-                loadTimes: function() {},
-                csi: function() {},
-                app: {}
-            };
-            
-            Object.defineProperty(navigator, 'plugins', {
-                get: () => [1, 2, 3, 4, 5]
-            });
-            
-            Object.defineProperty(navigator, 'languages', {
-                get: () => ['en-US', 'en', 'de']
-            });
-            
-            const originalQuery = window.navigator.permissions.query;
-            window.navigator.permissions.query = (parameters) => (
-                parameters.name === 'notifications' ?
-                    Promise.resolve({ state: Notification.permission }) :
-                    originalQuery(parameters)
-            );
-            ",
-            false,
-        )
-        .ok();
+        inject_stealth(&tab);
 
-        let start_url = format!("{}/visu/index.fcgi?00", self.config.base_url);
+        let start_url = format!("{}{}?00", self.config.base_url, self.config.page_path);
         info!("Navigating to login page...");
         tab.navigate_to(&start_url)
             .context("Failed to navigate to start URL")?;
 
-        std::thread::sleep(Duration::from_secs(3));
-
-        let check_js = r#"
-            (function() {
-                const hasLoginForm = !!document.querySelector('input[name="email"]');
-                const hasVisuElements = !!document.querySelector('[data-index]') || 
-                                       !!document.querySelector('.visu-icon') ||
-                                       window.location.pathname.includes('/visu/');
-                
-                return !hasLoginForm && hasVisuElements;
-            })();
-        "#;
-
-        let is_logged_in = tab.evaluate(check_js, false)
-            .ok()
-            .and_then(|result| result.value)
-            .and_then(|v| v.as_bool())
-            .unwrap_or(false);
+        let is_logged_in = wait_for_login_state(&tab, self.config.login_wait) == LoginState::LoggedIn;
 
         if is_logged_in {
             info!("✅ Already logged in! (Session restored from chrome_data/)");
@@ -375,6 +1219,16 @@ impl KnxClient {
                 info!("Session ID extracted from existing session");
                 return Ok(());
             }
+
+            if self.config.reuse_browser_session {
+                if let Some(new_session_id) = self.session_id_from_page(&tab) {
+                    let mut session_id = self.session_id.write().await;
+                    (*session_id).clone_from(&new_session_id);
+                    info!("Session ID recovered from the restored page (URL had none)");
+                    return Ok(());
+                }
+                warn!("Logged in but no session ID found in the page, falling back to a fresh login");
+            }
         }
 
         info!("Not logged in, attempting automatic login...");
@@ -450,6 +1304,18 @@ impl KnxClient {
         Ok(())
     }
 
+    /// Looks for the session of an already authenticated tab whose URL lacks
+    /// `session_id=`: in its cookies, a `window.session_id` global, or links in the page.
+    fn session_id_from_page(&self, tab: &headless_chrome::Tab) -> Option<String> {
+        const PAGE_STATE_JS: &str =
+            "JSON.stringify([document.cookie, String(window.session_id || ''), document.documentElement.outerHTML])";
+
+        let value = tab.evaluate(PAGE_STATE_JS, false).ok()?.value?;
+        let [cookies, global, html]: [String; 3] = serde_json::from_str(value.as_str()?).ok()?;
+        let cookie_name = self.config.session_cookie.as_deref().unwrap_or("session_id");
+        find_session_id(&cookies, &global, &html, cookie_name)
+    }
+
     fn extract_session_id(url: &str) -> Result<String> {
         if let Some(session_part) = url.split("session_id=").nth(1) {
             let session_id = session_part
@@ -468,3 +1334,328 @@ impl KnxClient {
         }
     }
 }
+
+/// Longest part of a gateway response quoted in logs and errors.
+const BODY_EXCERPT_CHARS: usize = 200;
+
+/// The trimmed body, cut to [`BODY_EXCERPT_CHARS`] characters plus an ellipsis.
+fn body_excerpt(body: &str) -> String {
+    let body = body.trim();
+    match body.char_indices().nth(BODY_EXCERPT_CHARS) {
+        Some((end, _)) => format!("{}…", &body[..end]),
+        None => body.to_string(),
+    }
+}
+
+impl KnxCommandSink for KnxClient {
+    fn send_command<'a>(&'a self, command: &'a str) -> BoxFuture<'a, Result<()>> {
+        Box::pin(KnxClient::send_command(self, command))
+    }
+
+    fn discover_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>> {
+        Box::pin(KnxClient::discover_devices(self))
+    }
+
+    fn discover_page_devices<'a>(&'a self, page: &'a str) -> BoxFuture<'a, Result<Vec<Device>>> {
+        Box::pin(KnxClient::discover_page_devices(self, page))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_commands() {
+        let client = KnxClient::new(Arc::new(KnxConfig::test_default()), true).unwrap();
+        assert_eq!(client.shutdown(Duration::from_millis(10)).await, 0);
+
+        let in_flight = client.track_command();
+        assert_eq!(client.shutdown(Duration::from_millis(10)).await, 1);
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(in_flight);
+        };
+        let (remaining, ()) = tokio::join!(client.shutdown(Duration::from_secs(5)), release);
+        assert_eq!(remaining, 0);
+    }
+
+    #[test]
+    fn test_login_form() {
+        let page = reqwest::Url::parse("https://login.example.com/auth?client_id=visu").unwrap();
+        let html = r#"
+            <form action="/search"><input name="q"></form>
+            <form method="post" action="/auth/login?state=xyz">
+                <input type="hidden" name="csrf" value="t0ken">
+                <input type="email" name="email">
+                <input type="password" name="password">
+                <button type="submit">Login</button>
+            </form>"#;
+        let form = LoginForm::parse(html, &page).unwrap();
+        assert_eq!(form.action.as_str(), "https://login.example.com/auth/login?state=xyz");
+        assert_eq!(form.fields, vec![("csrf".to_string(), "t0ken".to_string())]);
+
+        let captcha = format!(r#"{html}<div class="g-recaptcha"></div>"#);
+        assert!(LoginForm::parse(&captcha, &page).is_err());
+        assert!(LoginForm::parse("<form><input name=\"q\"></form>", &page).is_err());
+    }
+
+    #[test]
+    fn test_find_session_id() {
+        let html = r#"<a href="/visu/index.fcgi?page=02&amp;session_id=abc123">Küche</a>"#;
+        assert_eq!(find_session_id("", "", html, "session_id").as_deref(), Some("abc123"));
+        assert_eq!(find_session_id("theme=dark; sid=xyz", "", html, "sid").as_deref(), Some("xyz"));
+        assert_eq!(find_session_id("", "g42", html, "session_id").as_deref(), Some("g42"));
+        assert_eq!(find_session_id("", "", "<p>no session_id=</p>", "session_id"), None);
+        assert_eq!(find_session_id("", "", "<p>ok</p>", "session_id"), None);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        assert_eq!(retry_backoff(1, 0.0), Duration::from_millis(125));
+        assert_eq!(retry_backoff(2, 0.0), Duration::from_millis(250));
+        assert_eq!(retry_backoff(3, 0.5), Duration::from_millis(750));
+        assert_eq!(retry_backoff(30, 0.0), MAX_COMMAND_RETRY_WAIT / 2);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        use reqwest::header::{HeaderMap, RETRY_AFTER};
+        let now = chrono::DateTime::parse_from_rfc2822("Wed, 21 Oct 2026 07:28:00 GMT").unwrap().to_utc();
+        let header = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        assert_eq!(retry_after(&header("5"), now), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(&header("Wed, 21 Oct 2026 07:28:12 GMT"), now), Some(Duration::from_secs(12)));
+        assert_eq!(retry_after(&header("Wed, 21 Oct 2026 07:27:00 GMT"), now), Some(Duration::ZERO));
+        assert_eq!(retry_after(&header("soon"), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+
+    #[test]
+    fn test_parse_reading() {
+        assert_eq!(KnxClient::parse_reading("21,5 °C"), Some(21.5));
+        assert_eq!(KnxClient::parse_reading("21.5°C"), Some(21.5));
+        assert_eq!(KnxClient::parse_reading("-3,2"), Some(-3.2));
+        assert_eq!(KnxClient::parse_reading("Ist: 19,0 °C"), Some(19.0));
+        assert_eq!(KnxClient::parse_reading("1.021,5 hPa"), Some(1021.5));
+        assert_eq!(KnxClient::parse_reading("48 %"), Some(48.0));
+        assert_eq!(KnxClient::parse_reading("22."), Some(22.0));
+        assert_eq!(KnxClient::parse_reading("--"), None);
+        assert_eq!(KnxClient::parse_reading("Fehler"), None);
+        assert_eq!(KnxClient::parse_reading(""), None);
+    }
+
+    #[test]
+    fn test_fill_query() {
+        use crate::config::{DEFAULT_COMMAND_QUERY, DEFAULT_PAGE_QUERY};
+        assert_eq!(fill_query(DEFAULT_PAGE_QUERY, "{page}", "01", Some("abc")), "01&session_id=abc&lang=en");
+        assert_eq!(fill_query(DEFAULT_PAGE_QUERY, "{page}", "01", None), "01&lang=en");
+        assert_eq!(fill_query(DEFAULT_COMMAND_QUERY, "{command}", "0203", Some("abc")), "0203&session_id=abc");
+        assert_eq!(fill_query("session_id={session}&{page}", "{page}", "01", Some("abc")), "session_id=abc&01");
+    }
+
+    #[test]
+    fn test_status_url() {
+        let template = "/visu/status.fcgi?session_id={session}&lang=en";
+        let client = KnxClient::new(Arc::new(KnxConfig::test_default()), true).unwrap();
+        let base = &client.config.base_url;
+        assert_eq!(client.status_url(template, "abc"), format!("{base}/visu/status.fcgi?session_id=abc&lang=en"));
+        assert_eq!(client.status_url("/status", "abc"), format!("{base}/status"));
+
+        let config = KnxConfig { session_cookie: Some("SESSION".to_string()), ..KnxConfig::test_default() };
+        let client = KnxClient::new(Arc::new(config), true).unwrap();
+        assert_eq!(client.status_url(template, "abc"), format!("{base}/visu/status.fcgi?lang=en"));
+        assert_eq!(client.status_url("/status?session_id={session}", "abc"), format!("{base}/status"));
+    }
+
+    #[test]
+    fn test_round_reading() {
+        assert_eq!(round_reading(21.27, 1), 21.3);
+        assert_eq!(round_reading(48.04, 0), 48.0);
+        assert_eq!(serde_json::to_string(&round_reading(21.346, 2)).unwrap(), "21.35");
+    }
+
+    #[test]
+    fn test_detect_soft_error() {
+        let patterns = vec!["error".to_string(), "device busy".to_string()];
+
+        assert_eq!(KnxClient::detect_soft_error("OK", &patterns), None);
+        assert_eq!(KnxClient::detect_soft_error("", &patterns), None);
+        assert_eq!(
+            KnxClient::detect_soft_error("Device Busy, try again", &patterns),
+            Some("device busy")
+        );
+        assert_eq!(
+            KnxClient::detect_soft_error("ERROR: not permitted", &patterns),
+            Some("error")
+        );
+        assert_eq!(KnxClient::detect_soft_error("ERROR", &[]), None);
+        assert_eq!(
+            KnxClient::detect_soft_error("<html><img onerror=\"retry()\" class=\"error\"></html>", &patterns),
+            None
+        );
+        assert_eq!(body_excerpt(&"x".repeat(500)).chars().count(), BODY_EXCERPT_CHARS + 1);
+    }
+
+    fn summarize(devices: &[Device]) -> Vec<(String, DeviceType, bool)> {
+        devices.iter().map(|d| (d.key(), d.type_.clone(), d.is_on())).collect()
+    }
+
+    #[test]
+    fn test_parse_fixture_page02() {
+        let html = include_str!("../tests/fixtures/visu_page02.html");
+        let devices = KnxClient::parse_devices(html, "02", &KnxConfig::test_default());
+
+        assert_eq!(
+            summarize(&devices),
+            vec![
+                ("Single_1_page02".to_string(), DeviceType::Light, true),
+                ("Single_2_page02".to_string(), DeviceType::Light, false),
+                ("ExtendedSlider_1_page02".to_string(), DeviceType::Dimmer, true),
+                ("Double3_1_page02".to_string(), DeviceType::WindowCovering, false),
+                ("Temp_1_page02".to_string(), DeviceType::TemperatureSensor, false),
+                ("Temp_2_page02".to_string(), DeviceType::HumiditySensor, false),
+            ]
+        );
+        assert!(matches!(devices[3].state, DeviceState::WindowCovering { position: 40, .. }));
+        assert!(matches!(devices[4].state, DeviceState::Temperature(t) if (t - 21.5).abs() < f32::EPSILON));
+        assert!(matches!(devices[5].state, DeviceState::Humidity(h) if (h - 48.0).abs() < f32::EPSILON));
+    }
+
+    #[test]
+    fn test_parse_battery_and_signal() {
+        let html = r#"
+            <div class="visu-element" id="Temp_1" data-index="1">
+              <span class="visu-element-name">Temperatur Bad</span>
+              <span class="visu-status-text">20,0 °C</span>
+              <i class="visu-battery low"></i>
+              <span class="visu-signal" data-value="62"></span>
+            </div>
+            <div class="visu-element" id="Temp_2" data-index="2">
+              <span class="visu-element-name">Temperatur Flur</span>
+              <span class="visu-status-text">19,0 °C</span>
+              <i class="visu-battery"></i>
+              <span class="visu-signal">80 %</span>
+            </div>
+            <div class="visu-element" id="Temp_3" data-index="3">
+              <span class="visu-element-name">Temperatur Keller</span>
+              <span class="visu-status-text">15,0 °C</span>
+            </div>"#;
+        let devices = KnxClient::parse_devices(html, "01", &KnxConfig::test_default());
+
+        let health: Vec<_> = devices.iter().map(|d| (d.battery_low, d.signal)).collect();
+        assert_eq!(health, vec![(Some(true), Some(62)), (Some(false), Some(80)), (None, None)]);
+    }
+
+    #[test]
+    fn test_unread_sensor_remembered() {
+        let html = r#"
+            <div class="visu-element" id="Temp_1" data-index="1">
+              <span class="visu-element-name">Temperatur Bad</span>
+              <span class="visu-status-text">--</span>
+            </div>
+            <div class="visu-element" id="Temp_2" data-index="2">
+              <span class="visu-element-name">Temperatur Flur</span>
+              <span class="visu-status-text">19,0 °C</span>
+            </div>"#;
+        let mut unread = HashSet::new();
+        let devices = KnxClient::parse_page(html, "01", &KnxConfig::test_default(), &mut unread);
+        KnxClient::parse_page(html, "01", &KnxConfig::test_default(), &mut unread);
+
+        assert_eq!(unread, HashSet::from([devices[0].key()]));
+    }
+
+    #[test]
+    fn test_parse_fixture_page03() {
+        let html = include_str!("../tests/fixtures/visu_page03.html");
+        let devices = KnxClient::parse_devices(html, "03", &KnxConfig::test_default());
+
+        assert_eq!(
+            summarize(&devices),
+            vec![
+                ("Single_5_page03".to_string(), DeviceType::Scene, false),
+                ("Single_6_page03".to_string(), DeviceType::Fan, true),
+                ("Single_7_page03".to_string(), DeviceType::Outlet, true),
+                ("Single_8_page03".to_string(), DeviceType::Light, false),
+            ]
+        );
+        assert!(matches!(devices[2].state, DeviceState::Outlet { in_use: true, .. }));
+        assert_eq!(devices[3].name, "Gang Nachtlicht");
+    }
+
+    #[test]
+    fn test_detection_rules() {
+        let html = r#"
+            <div class="visu-element" id="Single_1" data-index="1">
+              <span class="visu-element-name">Klima Temperatur</span>
+            </div>
+            <div class="visu-element" id="Single_2" data-index="2">
+              <span class="visu-element-name">Temperatur Bad</span>
+              <span class="visu-status-text">20,0 °C</span>
+            </div>"#;
+        let types = |config: &KnxConfig| -> Vec<DeviceType> {
+            KnxClient::parse_devices(html, "01", config).into_iter().map(|d| d.type_).collect()
+        };
+
+        let mut config = KnxConfig::test_default();
+        assert_eq!(types(&config), vec![DeviceType::TemperatureSensor, DeviceType::TemperatureSensor]);
+
+        config.detection_rules.push(crate::config::DetectionRule {
+            pattern: regex::Regex::new("(?i)^klima").unwrap(),
+            on_classes: false,
+            device_type: DeviceType::Fan,
+        });
+        assert_eq!(types(&config), vec![DeviceType::Fan, DeviceType::TemperatureSensor]);
+    }
+
+    #[test]
+    fn test_active_state_variants() {
+        let config = KnxConfig::test_default();
+        let parse = |icon: &str| {
+            let html = format!(
+                r#"<div class="visu-element" id="Single_1" data-index="1">
+                    <span class="visu-element-name">Kitchen</span>{icon}
+                </div>"#
+            );
+            KnxClient::parse_devices(&html, "02", &config)[0].is_on()
+        };
+
+        assert!(parse(r#"<i class="visu-icon btn-active"></i>"#));
+        assert!(parse(r#"<i class="visu-icon active"></i>"#));
+        assert!(parse(r#"<i class="visu-icon is-on"></i>"#));
+        assert!(parse(r#"<i class="visu-icon" data-state="on"></i>"#));
+        assert!(!parse(r#"<i class="visu-icon" data-state="off"></i>"#));
+        assert!(!parse(r#"<i class="visu-icon inactive"></i>"#));
+        assert!(!parse(r#"<i class="visu-icon"></i>"#));
+    }
+
+    #[test]
+    fn test_parse_status_response() {
+        let states = KnxClient::parse_status_response(
+            r#"[{"page":"02","index":"7","value":"21,5 °C"},{"page":"02","index":"3","value":true}]"#,
+        )
+        .unwrap();
+        let value = |page: &str, index: &str| states.get(&(page.to_string(), index.to_string())).cloned();
+        assert_eq!(value("02", "7").as_deref(), Some("21,5 °C"));
+        assert_eq!(value("02", "3").as_deref(), Some("true"));
+
+        let mut sensor = Device::new(
+            "Temp_1".to_string(),
+            "Wohnzimmer Temperatur".to_string(),
+            DeviceType::TemperatureSensor,
+            "02".to_string(),
+            "7".to_string(),
+        );
+        let client = KnxClient::new(Arc::new(KnxConfig::test_default()), true).unwrap();
+        assert!(client.apply_status_text(&mut sensor, "21,5 °C"));
+        assert_eq!(sensor.state, DeviceState::Temperature(21.5));
+        assert!(!client.apply_status_text(&mut sensor, "n/a"));
+
+        assert!(KnxClient::parse_status_response("<html></html>").is_err());
+    }
+}
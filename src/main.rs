@@ -4,15 +4,25 @@ mod command_mapper;
 mod config;
 mod device;
 mod knx_client;
+mod metrics;
+mod mqtt;
+mod rate_limiter;
+mod redaction;
 mod state_manager;
 
 use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tracing::{error, info};
+use std::time::Duration;
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::command_mapper::CommandMapper;
-use crate::config::Config;
+use crate::command_mapper::{CommandMapper, DeviceMappings, MAPPINGS_PATH};
+use crate::config::{BridgeMode, Config};
+use crate::device::{Device, DeviceType};
 use crate::knx_client::KnxClient;
 use crate::state_manager::StateManager;
 
@@ -20,46 +30,263 @@ use crate::state_manager::StateManager;
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
 
+    let redactor = redaction::Redactor::from_env()?;
+
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "info,knx_homekit_bridge=debug".into()),
         )
-        .with(tracing_subscriber::fmt::layer())
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(redaction::RedactingMakeWriter::new(std::io::stdout, redactor)),
+        )
         .init();
 
+    let cli = Cli::parse();
+    let headless = cli.headless;
+    let config_path = cli.config.as_deref();
+
+    let command = match cli.command {
+        Some(command) => command,
+        None if cli.discover_preview => Command::DiscoverPreview,
+        None if cli.fix_mappings => Command::FixMappings,
+        None if cli.check_mappings => Command::CheckMappings,
+        None if cli.discover_diff => Command::DiscoverDiff,
+        None if cli.discover => Command::Discover,
+        None => Command::Run,
+    };
+
+    match command {
+        Command::Run => run_bridge(headless, config_path).await,
+        Command::Discover => run_discover(headless, config_path),
+        Command::DiscoverDiff => run_discover_diff(headless, config_path),
+        Command::DiscoverPreview => run_discover_preview(headless, config_path).await,
+        Command::Validate => run_validate(config_path),
+        Command::FixMappings => run_fix_mappings(MAPPINGS_PATH),
+        Command::CheckMappings => run_check_mappings(MAPPINGS_PATH),
+        Command::ParseFile { path, page } => run_parse_file(&path, &page, config_path),
+    }
+}
+
+#[derive(Parser)]
+#[command(version, about = "Bridges a KNX visualisation gateway to an HTTP API for Homebridge")]
+struct Cli {
+    /// Run Chrome in the background without a window
+    #[arg(long, global = true)]
+    headless: bool,
+
+    /// Read settings from a JSON or TOML file; anything it leaves out still comes from .env
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Same as the `discover` subcommand (kept for existing scripts)
+    #[arg(long, hide = true)]
+    discover: bool,
+
+    /// Same as the `discover-diff` subcommand (kept for existing scripts)
+    #[arg(long, hide = true)]
+    discover_diff: bool,
+
+    /// Same as the `discover-preview` subcommand
+    #[arg(long, hide = true)]
+    discover_preview: bool,
+
+    /// Same as the `fix-mappings` subcommand
+    #[arg(long, hide = true)]
+    fix_mappings: bool,
+
+    /// Same as the `check-mappings` subcommand
+    #[arg(long, hide = true)]
+    check_mappings: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Discover devices and serve the HTTP API (default)
+    Run,
+    /// Find all device commands and write device_mappings_auto.toml
+    Discover,
+    /// Compare discovered devices against device_mappings.toml without writing anything
+    DiscoverDiff,
+    /// Print the devices discovery would register, without writing mappings or serving the API
+    DiscoverPreview,
+    /// Check .env and device_mappings.toml without contacting the gateway
+    Validate,
+    /// Check every command in device_mappings.toml, without reading .env or starting the bridge
+    CheckMappings,
+    /// Zero-pad page numbers in device_mappings.toml keys (`_page2` → `_page02`) and write it back
+    FixMappings,
+    /// Run a saved visu page through the device parser and print what it finds
+    ParseFile {
+        /// HTML file saved from the gateway, e.g. via "Save page as" in the browser
+        path: PathBuf,
+        /// Page number the HTML was captured from
+        #[arg(long, default_value = "01")]
+        page: String,
+    },
+}
+
+fn run_discover_diff(headless: bool, config_path: Option<&Path>) -> Result<()> {
+    load_config(config_path)?;
+    info!("🔍 Running in DISCOVER-DIFF mode (read-only)");
+    info!("Comparing discovered devices against device_mappings.toml");
+    info!("");
+
+    let discovery = auto_discovery::AutoDiscovery::new(headless)?;
+    let diff = discovery.discover_diff(MAPPINGS_PATH)?;
+
+    info!("");
+    info!("➕ Added ({}) - need mappings:", diff.added.len());
+    for (key, section, command) in &diff.added {
+        info!("   [{}] \"{}\" = \"{}\"", section, key, command);
+    }
+    info!("➖ Removed ({}) - stale mappings:", diff.removed.len());
+    for key in &diff.removed {
+        info!("   {}", key);
+    }
+    info!("✔️  Unchanged: {}", diff.unchanged.len());
+    info!("");
+    info!("No files were written.");
+    Ok(())
+}
+
+async fn run_discover_preview(headless: bool, config_path: Option<&Path>) -> Result<()> {
+    info!("🔍 Running in DISCOVER-PREVIEW mode (read-only)");
 
-    let args: Vec<String> = std::env::args().collect();
-    let headless = args.contains(&"--headless".to_string());
+    let config = load_config(config_path)?;
+    let client = KnxClient::new(Arc::new(config.knx), headless)?;
+    client.ensure_valid_session().await?;
 
-    if args.contains(&"--discover".to_string()) {
-        info!("🔍 Running in AUTO-DISCOVERY mode");
-        info!("This will automatically find all device commands");
-        if headless {
-            info!("🤖 Headless mode: Chrome will run in background (no window)");
+    let devices = client.discover_devices().await?;
+    info!("");
+    info!("Would register {} devices:", devices.len());
+    for device in &devices {
+        let key = device.key();
+        let command_key = if device.type_ == DeviceType::WindowCovering {
+            format!("{key}_up / {key}_stop / {key}_down")
         } else {
-            info!("🖥️  GUI mode: Chrome window will appear for manual login");
-        }
-        info!("");
+            key
+        };
+        info!(
+            "  - {} ({}) - Type: {:?}, Page: {}, Index: {}, State: {:?}",
+            device.name, device.id, device.type_, device.page, device.index, device.state
+        );
+        info!("      mapping key: {}", command_key);
+    }
+    info!("");
+    info!("No files were written.");
+    Ok(())
+}
+
+fn run_discover(headless: bool, config_path: Option<&Path>) -> Result<()> {
+    load_config(config_path)?;
+    info!("🔍 Running in AUTO-DISCOVERY mode");
+    info!("This will automatically find all device commands");
+    if headless {
+        info!("🤖 Headless mode: Chrome will run in background (no window)");
+    } else {
+        info!("🖥️  GUI mode: Chrome window will appear for manual login");
+    }
+    info!("");
+
+    let discovery = auto_discovery::AutoDiscovery::new(headless)?;
+    let pages = vec!["01".to_string(), "02".to_string(), "03".to_string(), "04".to_string()];
 
-        let discovery = auto_discovery::AutoDiscovery::new(headless)?;
-        let pages = vec!["01".to_string(), "02".to_string(), "03".to_string(), "04".to_string()];
+    discovery.discover_all_mappings(&pages)?;
 
-        discovery.discover_all_mappings(&pages)?;
+    info!("");
+    info!("✅ Auto-discovery complete!");
+    info!("Review device_mappings_auto.toml and rename to device_mappings.toml");
+    Ok(())
+}
 
-        info!("");
-        info!("✅ Auto-discovery complete!");
-        info!("Review device_mappings_auto.toml and rename to device_mappings.toml");
+/// Loads `--config` (or `.env` alone) and sets the device key prefix before anything
+/// builds a key.
+fn load_config(config_path: Option<&Path>) -> Result<Config> {
+    let config = match config_path {
+        Some(path) => Config::load_from_file(path)?,
+        None => Config::load_from_env().context("Failed to load configuration from .env")?,
+    };
+    command_mapper::set_key_prefix(&config.bridge.key_prefix);
+    Ok(config)
+}
+
+fn run_validate(config_path: Option<&Path>) -> Result<()> {
+    load_config(config_path)?;
+    match config_path {
+        Some(path) => info!("✅ Configuration in {} and .env is valid", path.display()),
+        None => info!("✅ Configuration in .env is valid"),
+    }
+
+    let command_mapper = CommandMapper::load(MAPPINGS_PATH)
+        .context("Failed to load device mappings")?;
+    info!("✅ device_mappings.toml is valid");
+    for (section, count) in command_mapper.section_counts() {
+        info!("   - {}: {}", section, count);
+    }
+    Ok(())
+}
+
+fn run_check_mappings(path: &str) -> Result<()> {
+    let command_mapper = CommandMapper::load(path).with_context(|| format!("Invalid {path}"))?;
+    info!(
+        "✅ All {} commands in {} are well-formed ({} read-only)",
+        command_mapper.command_cache.len(),
+        path,
+        command_mapper.readonly_count()
+    );
+    Ok(())
+}
+
+fn run_fix_mappings(path: &str) -> Result<()> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    let mut mappings: DeviceMappings =
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {path}"))?;
+
+    let corrected = mappings.normalize_page_keys();
+    if corrected.is_empty() {
+        info!("✅ {} needs no changes", path);
         return Ok(());
     }
+    for (from, to) in &corrected {
+        info!("   {} -> {}", from, to);
+    }
 
+    let backup = format!("{path}.bak");
+    std::fs::copy(path, &backup).with_context(|| format!("Failed to back up {path}"))?;
+    std::fs::write(path, mappings.to_toml()?).with_context(|| format!("Failed to write {path}"))?;
+    info!("✅ Corrected {} keys in {} (previous version saved as {}; comments are not kept)", corrected.len(), path, backup);
+    Ok(())
+}
+
+fn run_parse_file(path: &Path, page: &str, config_path: Option<&Path>) -> Result<()> {
+    let config = load_config(config_path)?;
+    let html = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let devices = KnxClient::parse_devices(&html, page, &config.knx);
+    info!("Parsed {} devices from {} (page {})", devices.len(), path.display(), page);
+    for device in &devices {
+        info!(
+            "  - {} ({}) - Type: {:?}, Index: {}, State: {:?}",
+            device.name, device.key(), device.type_, device.index, device.state
+        );
+    }
+    Ok(())
+}
+
+async fn run_bridge(headless: bool, config_path: Option<&Path>) -> Result<()> {
     info!("Starting KNX-HomeKit Bridge");
 
-    let config = Config::load_from_env().context("Failed to load configuration from .env")?;
+    let config = load_config(config_path)?;
     info!("Configuration loaded from .env");
 
     let command_mapper = Arc::new(
-        CommandMapper::load("device_mappings.toml")
+        CommandMapper::load(MAPPINGS_PATH)
             .context("Failed to load device mappings")?
     );
     info!("Device mappings loaded successfully");
@@ -73,7 +300,11 @@ async fn main() -> Result<()> {
 
     client.ensure_valid_session().await?;
 
-    let state_manager = Arc::new(StateManager::new(client.clone(), command_mapper.clone()));
+    let state_manager = Arc::new(StateManager::new(
+        client.clone(),
+        command_mapper.clone(),
+        config.bridge.clone(),
+    ));
 
     state_manager.initialize().await?;
     info!("Device discovery completed");
@@ -87,31 +318,143 @@ async fn main() -> Result<()> {
         );
     }
 
-    info!("State polling: DISABLED (command-only mode)");
+    match config.bridge.mode {
+        BridgeMode::CommandOnly => info!("Bridge mode: command-only (state is what the bridge last sent)"),
+        BridgeMode::Stateful => info!("Bridge mode: stateful (polling and confirm-reads correct device state)"),
+    }
+
+    if let Some(interval) = config.polling.sensor_interval {
+        state_manager.start_sensor_polling(interval);
+        info!("Sensor polling: every {}s", interval.as_secs());
+    } else {
+        info!("Sensor polling: DISABLED");
+    }
+    if let Some(interval) = config.polling.state_interval {
+        state_manager.start_polling(interval);
+        info!("State polling: every {}s (all devices)", interval.as_secs());
+    } else {
+        info!("State polling: DISABLED");
+    }
 
     let state_manager_api = state_manager.clone();
-    let api_port = config.homekit.port;
-    tokio::spawn(async move {
-        if let Err(e) = api_server::start_api_server(state_manager_api, api_port).await {
+    let api_config = config.clone();
+    let listen_address = SocketAddr::new(api_config.homekit.bind_address, api_config.homekit.port);
+    // A wildcard bind isn't something to connect to; point at this machine instead.
+    let api_url = if listen_address.ip().is_unspecified() {
+        format!("http://localhost:{}", listen_address.port())
+    } else {
+        format!("http://{listen_address}")
+    };
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let api_server = tokio::spawn(async move {
+        if let Err(e) = api_server::start_api_server(state_manager_api, &api_config, shutdown_rx).await {
             error!("API server failed: {}", e);
         }
     });
 
+    if let Some(mqtt_config) = &config.mqtt {
+        mqtt::MqttPublisher::start(state_manager.clone(), mqtt_config);
+    }
+
+    let session_valid = client.validate_session().await.unwrap_or(false);
+    let summary = startup_summary(
+        &devices,
+        &command_mapper,
+        config.bridge.mode,
+        session_valid,
+        listen_address,
+    );
+    info!(target: "startup_summary", "{}", summary);
+
     info!("");
     info!("✅ KNX-HomeKit Bridge is running!");
     info!("   - KNX devices: {} discovered", devices.len());
     info!("   - Command mappings: {} loaded", command_mapper.command_cache.len());
-    info!("   - HTTP API: http://localhost:{}", api_port);
+    info!("   - HTTP API: {}", api_url);
     info!("");
     info!("📱 Connect Homebridge:");
     info!("   1. Install the homebridge-knx-bridge plugin");
-    info!("   2. Configure bridge URL: http://localhost:{}", api_port);
+    info!("   2. Configure bridge URL: {}", api_url);
     info!("   3. Add to Home app and pair");
     info!("");
     info!("Press Ctrl+C to exit.");
 
-    tokio::signal::ctrl_c().await?;
+    shutdown_signal().await?;
     info!("Shutting down...");
 
+    // Stop taking requests and let the ones already running finish their commands.
+    shutdown_tx.send_replace(true);
+    if tokio::time::timeout(SHUTDOWN_DRAIN_TIMEOUT, api_server).await.is_err() {
+        warn!("API requests still running after {}s, exiting anyway", SHUTDOWN_DRAIN_TIMEOUT.as_secs());
+    }
+
+    if !config.bridge.shutdown_actions.is_empty() {
+        info!("Running {} shutdown actions", config.bridge.shutdown_actions.len());
+        if tokio::time::timeout(SHUTDOWN_ACTIONS_TIMEOUT, state_manager.run_shutdown_actions())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Shutdown actions did not finish within {}s, exiting anyway",
+                SHUTDOWN_ACTIONS_TIMEOUT.as_secs()
+            );
+        }
+    }
+
+    state_manager.shutdown(SHUTDOWN_DRAIN_TIMEOUT).await;
+
     Ok(())
 }
+
+/// Longest the shutdown actions may delay exiting.
+const SHUTDOWN_ACTIONS_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest to wait for in-flight API requests, and again for commands still being sent.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves on Ctrl+C, or on SIGTERM as sent by `docker stop` and Kubernetes.
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Machine-parseable health picture of the bridge at boot, built from real counts.
+fn startup_summary(
+    devices: &[Device],
+    command_mapper: &CommandMapper,
+    mode: BridgeMode,
+    session_valid: bool,
+    listen_address: SocketAddr,
+) -> serde_json::Value {
+    let mut devices_by_type: BTreeMap<String, usize> = BTreeMap::new();
+    for device in devices {
+        *devices_by_type.entry(format!("{:?}", device.type_)).or_default() += 1;
+    }
+
+    let unmapped_devices = devices
+        .iter()
+        .filter(|d| !d.type_.is_sensor() && !command_mapper.is_actionable(d))
+        .count();
+
+    serde_json::json!({
+        "event": "startup_summary",
+        "mode": mode,
+        "devices_total": devices.len(),
+        "devices_by_type": devices_by_type,
+        "mappings_total": command_mapper.command_cache.len(),
+        "mappings_by_category": command_mapper.section_counts(),
+        "readonly_mappings": command_mapper.readonly_count(),
+        "orphaned_mappings": command_mapper.orphaned_keys(devices).len(),
+        "unmapped_devices": unmapped_devices,
+        "session_valid": session_valid,
+        "listen_address": listen_address.to_string(),
+    })
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Process-wide counters, served in the Prometheus text format by `GET /metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug)]
+pub struct Metrics {
+    commands_sent: AtomicU64,
+    /// Failed commands by status: the HTTP status code, `network` or `rejected`.
+    command_failures: Mutex<BTreeMap<String, u64>>,
+    session_refreshes: AtomicU64,
+    discovery_runs: AtomicU64,
+    devices: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            commands_sent: AtomicU64::new(0),
+            command_failures: Mutex::new(BTreeMap::new()),
+            session_refreshes: AtomicU64::new(0),
+            discovery_runs: AtomicU64::new(0),
+            devices: AtomicU64::new(0),
+        }
+    }
+
+    pub fn command_sent(&self) {
+        self.commands_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_failed(&self, status: impl ToString) {
+        let mut failures = self.command_failures.lock().expect("metrics lock poisoned");
+        *failures.entry(status.to_string()).or_default() += 1;
+    }
+
+    pub fn session_refreshed(&self) {
+        self.session_refreshes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn discovery_run(&self) {
+        self.discovery_runs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_devices(&self, count: usize) {
+        self.devices.store(count as u64, Ordering::Relaxed);
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let plain = |counter: &AtomicU64| vec![(String::new(), counter.load(Ordering::Relaxed))];
+
+        metric(
+            "knx_bridge_commands_sent_total",
+            "counter",
+            "Commands sent to the gateway.",
+            &plain(&self.commands_sent),
+        );
+        let failures: Vec<(String, u64)> = self
+            .command_failures
+            .lock()
+            .expect("metrics lock poisoned")
+            .iter()
+            .map(|(status, count)| (format!("{{status=\"{status}\"}}"), *count))
+            .collect();
+        metric(
+            "knx_bridge_command_failures_total",
+            "counter",
+            "Commands that failed after all retries, by status.",
+            &failures,
+        );
+        metric(
+            "knx_bridge_session_refreshes_total",
+            "counter",
+            "Successful gateway logins, including the first one.",
+            &plain(&self.session_refreshes),
+        );
+        metric(
+            "knx_bridge_discovery_runs_total",
+            "counter",
+            "Device discovery runs, at startup and on rediscover.",
+            &plain(&self.discovery_runs),
+        );
+        metric(
+            "knx_bridge_devices",
+            "gauge",
+            "Devices registered by the last discovery, including virtual ones.",
+            &plain(&self.devices),
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.command_sent();
+        metrics.command_sent();
+        metrics.command_failed(503);
+        metrics.command_failed("network");
+        metrics.command_failed(503);
+        metrics.set_devices(12);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE knx_bridge_commands_sent_total counter\nknx_bridge_commands_sent_total 2\n"));
+        assert!(text.contains("knx_bridge_command_failures_total{status=\"503\"} 2\n"));
+        assert!(text.contains("knx_bridge_command_failures_total{status=\"network\"} 1\n"));
+        assert!(text.contains("knx_bridge_session_refreshes_total 0\n"));
+        assert!(text.contains("# TYPE knx_bridge_devices gauge\nknx_bridge_devices 12\n"));
+    }
+}
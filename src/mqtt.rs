@@ -0,0 +1,364 @@
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use crate::api_server::DeviceStateInfo;
+use crate::config::MqttConfig;
+use crate::device::{Device, DeviceState, DeviceType};
+use crate::state_manager::{DeviceAction, StateEvent, StateManager};
+
+/// Topics are `smarthome/<key>/state` and `smarthome/<key>/set`.
+const TOPIC_ROOT: &str = "smarthome";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Publishes every device's state to an MQTT broker along with Home Assistant
+/// discovery configs, and runs commands received on `smarthome/<key>/set`.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    state_manager: Arc<StateManager>,
+    discovery_prefix: String,
+}
+
+/// What a payload on a `set` topic asks for.
+#[derive(Debug, PartialEq)]
+enum MqttCommand {
+    Toggle,
+    Action(DeviceAction),
+}
+
+impl MqttPublisher {
+    /// Connects in the background and keeps the broker in sync with the state manager's events.
+    pub fn start(state_manager: Arc<StateManager>, config: &MqttConfig) {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(availability_topic(), "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, eventloop) = AsyncClient::new(options, 64);
+        let publisher = Arc::new(Self {
+            client,
+            state_manager,
+            discovery_prefix: config.discovery_prefix.clone(),
+        });
+        info!("MQTT: Publishing to {}:{} (discovery prefix '{}')", config.host, config.port, config.discovery_prefix);
+
+        tokio::spawn(publisher.clone().run_connection(eventloop));
+        tokio::spawn(publisher.forward_events());
+    }
+
+    /// Drives the connection. Publishing happens on other tasks so a full request
+    /// queue can't stall the loop that drains it.
+    async fn run_connection(self: Arc<Self>, mut eventloop: EventLoop) {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("MQTT: Connected to broker");
+                    tokio::spawn(self.clone().on_connect());
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    let publisher = self.clone();
+                    tokio::spawn(async move {
+                        let payload = String::from_utf8_lossy(&publish.payload);
+                        publisher.handle_command(&publish.topic, &payload).await;
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT: Connection error: {}, reconnecting in {}s", e, RECONNECT_DELAY.as_secs());
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn on_connect(self: Arc<Self>) {
+        if let Err(e) = self.client.subscribe(format!("{TOPIC_ROOT}/+/set"), QoS::AtLeastOnce).await {
+            warn!("MQTT: Failed to subscribe to command topics: {}", e);
+        }
+        self.publish(availability_topic(), "online".to_string()).await;
+        self.publish_all().await;
+    }
+
+    async fn forward_events(self: Arc<Self>) {
+        let mut events = self.state_manager.subscribe();
+        loop {
+            match events.recv().await {
+                Ok(StateEvent::DeviceStateChanged { key, state, .. }) => self.publish_state(&key, &state).await,
+                Ok(StateEvent::DeviceAdded { key, .. }) => {
+                    if let Some(device) = self.state_manager.get_device(&key).await {
+                        self.publish_device(&device).await;
+                    }
+                }
+                Ok(StateEvent::DeviceRemoved { key, device_type }) => {
+                    let topic = config_topic(&self.discovery_prefix, component(&device_type), &key);
+                    self.publish(topic, String::new()).await;
+                }
+                Ok(StateEvent::DiscoveryCompleted { .. }) => self.publish_all().await,
+                Ok(StateEvent::SessionRefreshed) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("MQTT: Missed {} state events, republishing every device", skipped);
+                    self.publish_all().await;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    async fn publish_all(&self) {
+        let devices = self.state_manager.get_all_devices().await;
+        for device in &devices {
+            self.publish_device(device).await;
+        }
+        debug!("MQTT: Published {} devices", devices.len());
+    }
+
+    async fn publish_device(&self, device: &Device) {
+        let key = device.key();
+        let config = discovery_config(device);
+        let topic = config_topic(&self.discovery_prefix, component(&device.type_), &key);
+        self.publish(topic, config.to_string()).await;
+        self.publish_state(&key, &device.state).await;
+    }
+
+    async fn publish_state(&self, key: &str, state: &DeviceState) {
+        let payload = serde_json::to_string(&DeviceStateInfo::from(state)).unwrap_or_default();
+        self.publish(state_topic(key), payload).await;
+    }
+
+    /// Publishes a retained message, so Home Assistant picks it up after a restart.
+    async fn publish(&self, topic: String, payload: String) {
+        if let Err(e) = self.client.publish(&topic, QoS::AtLeastOnce, true, payload).await {
+            warn!("MQTT: Failed to publish to {}: {}", topic, e);
+        }
+    }
+
+    async fn handle_command(&self, topic: &str, payload: &str) {
+        let Some(key) = topic
+            .strip_prefix(TOPIC_ROOT)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|rest| rest.strip_suffix("/set"))
+        else {
+            return;
+        };
+        let Some(device) = self.state_manager.get_device(key).await else {
+            warn!("MQTT: Command for unknown device {}", key);
+            return;
+        };
+        let command = match parse_command(&device.type_, payload) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!("MQTT: Ignoring command '{}' for {}: {}", payload.trim(), key, e);
+                return;
+            }
+        };
+
+        info!("MQTT: {:?} for {}", command, key);
+        let result = match command {
+            MqttCommand::Toggle => self.state_manager.toggle_device(key, !device.is_on()).await,
+            MqttCommand::Action(DeviceAction::SetPosition { position }) => {
+                self.state_manager.set_blind_position(key, position).await
+            }
+            MqttCommand::Action(action) => self.state_manager.perform_action(key, action).await,
+        };
+        if let Err(e) = result {
+            warn!("MQTT: Command for {} failed: {:#}", key, e);
+        }
+    }
+}
+
+fn availability_topic() -> String {
+    format!("{TOPIC_ROOT}/status")
+}
+
+fn state_topic(key: &str) -> String {
+    format!("{TOPIC_ROOT}/{key}/state")
+}
+
+fn command_topic(key: &str) -> String {
+    format!("{TOPIC_ROOT}/{key}/set")
+}
+
+/// Device key reduced to the characters Home Assistant allows in an object id.
+fn object_id(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .collect()
+}
+
+fn config_topic(discovery_prefix: &str, component: &str, key: &str) -> String {
+    format!("{discovery_prefix}/{component}/{}/config", object_id(key))
+}
+
+/// Home Assistant integration a device type shows up as.
+fn component(type_: &DeviceType) -> &'static str {
+    match type_ {
+        DeviceType::Light | DeviceType::Dimmer => "light",
+        DeviceType::Switch | DeviceType::Outlet => "switch",
+        DeviceType::Fan => "fan",
+        DeviceType::WindowCovering => "cover",
+        DeviceType::TemperatureSensor | DeviceType::HumiditySensor => "sensor",
+        DeviceType::Scene => "scene",
+    }
+}
+
+/// Home Assistant MQTT discovery payload for `device`, reading the JSON that
+/// [`MqttPublisher::publish_state`] sends.
+fn discovery_config(device: &Device) -> Value {
+    let key = device.key();
+    let object_id = object_id(&key);
+    let mut config = json!({
+        "name": Value::Null,
+        "unique_id": format!("knx_bridge_{object_id}"),
+        "object_id": object_id,
+        "availability_topic": availability_topic(),
+        "device": {
+            "identifiers": [format!("knx_bridge_{object_id}")],
+            "name": device.name,
+            "manufacturer": "KNX",
+            "model": format!("{:?}", device.type_),
+        },
+    });
+    let on_template = match device.state {
+        DeviceState::FanSpeed(_) => "{{ 'ON' if value_json.speed > 0 else 'OFF' }}",
+        _ => "{{ 'ON' if value_json.on else 'OFF' }}",
+    };
+    let switchable = json!({
+        "state_topic": state_topic(&key),
+        "command_topic": command_topic(&key),
+        "payload_on": "ON",
+        "payload_off": "OFF",
+    });
+
+    let extra = match device.type_ {
+        DeviceType::Light | DeviceType::Dimmer => {
+            let mut light = switchable;
+            light["state_value_template"] = json!(on_template);
+            if matches!(device.state, DeviceState::Brightness { .. }) {
+                light["brightness_state_topic"] = json!(state_topic(&key));
+                light["brightness_value_template"] = json!("{{ value_json.level }}");
+                light["brightness_command_topic"] = json!(command_topic(&key));
+                light["brightness_scale"] = json!(100);
+            }
+            light
+        }
+        DeviceType::Switch | DeviceType::Outlet => {
+            let mut switch = switchable;
+            switch["value_template"] = json!(on_template);
+            if device.type_ == DeviceType::Outlet {
+                switch["device_class"] = json!("outlet");
+            }
+            switch
+        }
+        DeviceType::Fan => {
+            let mut fan = switchable;
+            fan["state_value_template"] = json!(on_template);
+            fan
+        }
+        DeviceType::WindowCovering => json!({
+            "device_class": "blind",
+            "command_topic": command_topic(&key),
+            "payload_open": "OPEN",
+            "payload_close": "CLOSE",
+            "payload_stop": "STOP",
+            "position_topic": state_topic(&key),
+            "position_template": "{{ value_json.position }}",
+            "set_position_topic": command_topic(&key),
+        }),
+        DeviceType::TemperatureSensor => json!({
+            "device_class": "temperature",
+            "state_class": "measurement",
+            "unit_of_measurement": "°C",
+            "state_topic": state_topic(&key),
+            "value_template": "{{ value_json.celsius }}",
+        }),
+        DeviceType::HumiditySensor => json!({
+            "device_class": "humidity",
+            "state_class": "measurement",
+            "unit_of_measurement": "%",
+            "state_topic": state_topic(&key),
+            "value_template": "{{ value_json.percent }}",
+        }),
+        DeviceType::Scene => json!({
+            "command_topic": command_topic(&key),
+            "payload_on": "ON",
+        }),
+    };
+    if let (Value::Object(config), Value::Object(extra)) = (&mut config, extra) {
+        config.extend(extra);
+    }
+    config
+}
+
+/// Reads the payloads Home Assistant sends (`ON`, `OFF`, `OPEN`, `CLOSE`, `STOP`, a
+/// percentage) plus `TOGGLE` and the compact actions of `SMARTHOME_SHUTDOWN_ACTIONS`.
+fn parse_command(type_: &DeviceType, payload: &str) -> Result<MqttCommand, String> {
+    let payload = payload.trim();
+    let action = match payload.to_uppercase().as_str() {
+        "TOGGLE" => return Ok(MqttCommand::Toggle),
+        "ON" => DeviceAction::On,
+        "OFF" => DeviceAction::Off,
+        "OPEN" => DeviceAction::SetPosition { position: 100 },
+        "CLOSE" => DeviceAction::SetPosition { position: 0 },
+        "STOP" => DeviceAction::Stop,
+        _ => match payload.parse::<u8>() {
+            Ok(value) => match type_ {
+                DeviceType::WindowCovering => DeviceAction::SetPosition { position: value },
+                DeviceType::Fan => DeviceAction::FanSpeed { speed: value },
+                _ => DeviceAction::Brightness { level: value },
+            },
+            Err(_) => payload.parse()?,
+        },
+    };
+    action.check_range().map_err(|e| e.to_string())?;
+    Ok(MqttCommand::Action(action))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery_config() {
+        let mut dimmer = Device::new("Dimmer_1".into(), "Esstisch".into(), DeviceType::Dimmer, "02".into(), "4".into());
+        dimmer.state = DeviceState::Brightness { on: true, level: 40 };
+        let config = discovery_config(&dimmer);
+        assert_eq!(
+            config_topic("homeassistant", component(&dimmer.type_), &dimmer.key()),
+            "homeassistant/light/Dimmer_1_page02/config"
+        );
+        assert_eq!(config["command_topic"], "smarthome/Dimmer_1_page02/set");
+        assert_eq!(config["brightness_value_template"], "{{ value_json.level }}");
+        assert_eq!(config["device"]["name"], "Esstisch");
+
+        let blind = Device::new("Blind_1".into(), "Wohnzimmer".into(), DeviceType::WindowCovering, "02".into(), "5".into());
+        let config = discovery_config(&blind);
+        assert_eq!(config["position_topic"], "smarthome/Blind_1_page02/state");
+        assert!(config.get("brightness_command_topic").is_none());
+        assert_eq!(object_id("house1:Single_1_page02"), "house1_Single_1_page02");
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(&DeviceType::Light, " on "), Ok(MqttCommand::Action(DeviceAction::On)));
+        assert_eq!(parse_command(&DeviceType::Light, "TOGGLE"), Ok(MqttCommand::Toggle));
+        assert_eq!(
+            parse_command(&DeviceType::WindowCovering, "40"),
+            Ok(MqttCommand::Action(DeviceAction::SetPosition { position: 40 }))
+        );
+        assert_eq!(
+            parse_command(&DeviceType::Dimmer, "40"),
+            Ok(MqttCommand::Action(DeviceAction::Brightness { level: 40 }))
+        );
+        assert_eq!(
+            parse_command(&DeviceType::WindowCovering, "tilt:30"),
+            Ok(MqttCommand::Action(DeviceAction::Tilt { tilt: 30 }))
+        );
+        assert!(parse_command(&DeviceType::Dimmer, "140").is_err());
+        assert!(parse_command(&DeviceType::Light, "blink").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// Who a command is sent for. Interactive API commands take the next free token
+/// ahead of any background work (polling, confirm reads, shutdown actions) still waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandPriority {
+    Background,
+    Interactive,
+}
+
+tokio::task_local! {
+    /// Priority of commands sent from the current task; unset means interactive.
+    pub static COMMAND_PRIORITY: CommandPriority;
+}
+
+/// Priority of commands sent from the current task.
+pub fn current_priority() -> CommandPriority {
+    COMMAND_PRIORITY.try_with(|priority| *priority).unwrap_or(CommandPriority::Interactive)
+}
+
+/// Token bucket capping how many commands per second reach the gateway.
+///
+/// Callers waiting for a token form a queue ordered by priority, then arrival, so
+/// a user's command overtakes queued background commands but never another
+/// user's command.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+    /// Wakes waiters whenever the head of the queue changes.
+    turn: Notify,
+    throttled: AtomicU64,
+    gateway_backoffs: AtomicU64,
+}
+
+type Ticket = (CommandPriority, Reverse<u64>);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    waiting: BinaryHeap<Ticket>,
+    next_ticket: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RateLimitStatus {
+    pub commands_per_sec: f64,
+    pub burst: f64,
+    /// Commands that had to wait for a token since startup.
+    pub throttled_total: u64,
+    /// Times the gateway answered 429 and all commands were held back.
+    pub gateway_backoffs_total: u64,
+    /// Commands currently waiting, by priority.
+    pub waiting_interactive: usize,
+    pub waiting_background: usize,
+}
+
+/// Leaves the queue when the waiting command gets its token or is cancelled.
+struct QueuedTicket<'a> {
+    limiter: &'a RateLimiter,
+    ticket: Ticket,
+}
+
+impl Drop for QueuedTicket<'_> {
+    fn drop(&mut self) {
+        let mut bucket = self.limiter.bucket.lock().expect("rate limiter lock poisoned");
+        bucket.waiting.retain(|queued| *queued != self.ticket);
+        drop(bucket);
+        self.limiter.turn.notify_waiters();
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+                waiting: BinaryHeap::new(),
+                next_ticket: 0,
+            }),
+            turn: Notify::new(),
+            throttled: AtomicU64::new(0),
+            gateway_backoffs: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn acquire(&self, priority: CommandPriority) {
+        let queued = QueuedTicket { limiter: self, ticket: self.enqueue(priority) };
+        let mut throttled = false;
+        loop {
+            let turn = self.turn.notified();
+            tokio::pin!(turn);
+            turn.as_mut().enable();
+
+            let Err(wait) = self.try_take(queued.ticket, Instant::now()) else {
+                return;
+            };
+            if !throttled {
+                throttled = true;
+                self.throttled.fetch_add(1, Ordering::Relaxed);
+                debug!("Rate limit reached, queueing {:?} command for up to {}ms", priority, wait.as_millis());
+            }
+            tokio::select! {
+                () = tokio::time::sleep(wait) => {}
+                () = &mut turn => {}
+            }
+        }
+    }
+
+    fn enqueue(&self, priority: CommandPriority) -> Ticket {
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        let ticket = (priority, Reverse(bucket.next_ticket));
+        bucket.next_ticket += 1;
+        bucket.waiting.push(ticket);
+        ticket
+    }
+
+    /// Takes a token for `ticket` if it's first in line and one is available;
+    /// otherwise returns how long to wait before checking again.
+    fn try_take(&self, ticket: Ticket, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.waiting.peek() != Some(&ticket) {
+            // The head takes the next token; this waiter is woken when the head changes.
+            return Err(Duration::from_secs_f64((2.0 - bucket.tokens).max(1.0) / self.rate));
+        }
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+        bucket.tokens -= 1.0;
+        bucket.waiting.pop();
+        Ok(())
+    }
+
+    /// Holds back every command for `wait`, e.g. after the gateway answered 429.
+    pub fn back_off(&self, wait: Duration) {
+        let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate)
+            .min(self.burst)
+            .min(1.0 - wait.as_secs_f64() * self.rate);
+        bucket.last_refill = now;
+        drop(bucket);
+        self.gateway_backoffs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn status(&self) -> RateLimitStatus {
+        let bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+        let waiting_interactive = bucket
+            .waiting
+            .iter()
+            .filter(|(priority, _)| *priority == CommandPriority::Interactive)
+            .count();
+        RateLimitStatus {
+            commands_per_sec: self.rate,
+            burst: self.burst,
+            throttled_total: self.throttled.load(Ordering::Relaxed),
+            gateway_backoffs_total: self.gateway_backoffs.load(Ordering::Relaxed),
+            waiting_interactive,
+            waiting_background: bucket.waiting.len() - waiting_interactive,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_take() {
+        let limiter = RateLimiter::new(2.0, 2.0);
+        let start = Instant::now();
+
+        let first = limiter.enqueue(CommandPriority::Interactive);
+        assert_eq!(limiter.try_take(first, start), Ok(()));
+        let second = limiter.enqueue(CommandPriority::Interactive);
+        assert_eq!(limiter.try_take(second, start), Ok(()));
+
+        // Both burst tokens are spent; the next one refills after half a second.
+        let third = limiter.enqueue(CommandPriority::Interactive);
+        assert_eq!(limiter.try_take(third, start), Err(Duration::from_millis(500)));
+        assert_eq!(limiter.try_take(third, start + Duration::from_millis(500)), Ok(()));
+    }
+
+    #[test]
+    fn test_back_off() {
+        let limiter = RateLimiter::new(2.0, 2.0);
+        limiter.back_off(Duration::from_secs(2));
+        let ticket = limiter.enqueue(CommandPriority::Interactive);
+        let wait = limiter.try_take(ticket, Instant::now()).unwrap_err();
+        assert!(wait > Duration::from_millis(1900) && wait <= Duration::from_secs(2));
+        assert_eq!(limiter.status().gateway_backoffs_total, 1);
+    }
+
+    #[test]
+    fn test_interactive_goes_first() {
+        let limiter = RateLimiter::new(1.0, 1.0);
+        let start = Instant::now();
+        limiter.try_take(limiter.enqueue(CommandPriority::Interactive), start).unwrap();
+
+        let background = limiter.enqueue(CommandPriority::Background);
+        let interactive = limiter.enqueue(CommandPriority::Interactive);
+        let status = limiter.status();
+        assert_eq!((status.waiting_interactive, status.waiting_background), (1, 1));
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.try_take(background, later).is_err());
+        assert_eq!(limiter.try_take(interactive, later), Ok(()));
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::borrow::Cow;
+use std::io;
+use std::sync::Arc;
+use tracing_subscriber::fmt::MakeWriter;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Scrubs sensitive values from formatted log lines.
+///
+/// The session id is always redacted; additional regex patterns (e.g. the
+/// gateway hostname) come from `SMARTHOME_LOG_REDACT`.
+#[derive(Debug)]
+pub struct Redactor {
+    session: Regex,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(patterns: &[String]) -> Result<Self> {
+        let session = Regex::new(r"session_id=[^&\s]+").expect("valid session pattern");
+        let patterns = patterns
+            .iter()
+            .map(|p| Regex::new(p).with_context(|| format!("Invalid log redaction pattern: {p}")))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { session, patterns })
+    }
+
+    pub fn from_env() -> Result<Self> {
+        let patterns: Vec<String> = std::env::var("SMARTHOME_LOG_REDACT")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self::new(&patterns)
+    }
+
+    pub fn redact<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let mut result = self.session.replace_all(line, format!("session_id={REDACTED}"));
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&result, REDACTED) {
+                result = Cow::Owned(replaced);
+            }
+        }
+        result
+    }
+}
+
+/// `MakeWriter` wrapper that runs every formatted event through a [`Redactor`].
+pub struct RedactingMakeWriter<M> {
+    inner: M,
+    redactor: Arc<Redactor>,
+}
+
+impl<M> RedactingMakeWriter<M> {
+    pub fn new(inner: M, redactor: Redactor) -> Self {
+        Self {
+            inner,
+            redactor: Arc::new(redactor),
+        }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            inner: self.inner.make_writer(),
+            redactor: self.redactor.clone(),
+        }
+    }
+}
+
+pub struct RedactingWriter<W> {
+    inner: W,
+    redactor: Arc<Redactor>,
+}
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        self.inner.write_all(self.redactor.redact(&line).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let redactor = Redactor::new(&[r"tgs-smarthome\.example\.ch".to_string()]).unwrap();
+
+        assert_eq!(
+            redactor.redact("GET https://tgs-smarthome.example.ch:7443/visu?01&session_id=abc123&lang=en"),
+            "GET https://[REDACTED]:7443/visu?01&session_id=[REDACTED]&lang=en"
+        );
+        assert_eq!(redactor.redact("nothing sensitive"), "nothing sensitive");
+        assert!(Redactor::new(&["(".to_string()]).is_err());
+    }
+}
@@ -1,121 +1,1841 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tracing::{debug, info, warn};
 
-use crate::command_mapper::CommandMapper;
-use crate::device::{Device, DeviceRegistry, DeviceState};
-use crate::knx_client::KnxClient;
+use crate::command_mapper::{self, BlindCommands, CommandMapper, ValueOutOfRange};
+use crate::config::{BridgeConfig, BridgeMode};
+use crate::device::{Capabilities, ControlMode, Device, DeviceRegistry, DeviceState, DeviceType};
+use crate::knx_client::{KnxClient, KnxCommandSink};
+use crate::metrics::METRICS;
+use crate::rate_limiter::{CommandPriority, RateLimitStatus, COMMAND_PRIORITY};
 
 pub struct StateManager {
     registry: Arc<RwLock<DeviceRegistry>>,
     client: Arc<KnxClient>,
-    pub command_mapper: Arc<CommandMapper>,
+    command_sink: Arc<dyn KnxCommandSink>,
+    /// Swapped as a whole by `reload_mappings`; callers take a snapshot per operation.
+    command_mapper: std::sync::RwLock<Arc<CommandMapper>>,
+    config: BridgeConfig,
+    last_command: Mutex<HashMap<String, Instant>>,
+    polling_interval: OnceLock<Duration>,
+    state_polling_interval: OnceLock<Duration>,
+    polling_enabled: AtomicBool,
+    initialized: AtomicBool,
+    events: broadcast::Sender<StateEvent>,
+    /// Held for the whole of a discovery run or a poll, so the two never overlap.
+    discovery_lock: Mutex<()>,
+    event_clients: Arc<AtomicUsize>,
+    /// Pending timed stops of blinds moving to an intermediate position, by device key.
+    blind_movements: std::sync::Mutex<HashMap<String, tokio::task::AbortHandle>>,
+    /// Types set through `POST /device/:key/type`, by device key. Re-applied after every
+    /// discovery and mappings reload until the bridge restarts.
+    type_overrides: std::sync::Mutex<HashMap<String, DeviceType>>,
+    /// Commands rewritten for a device the gateway moved to another index, by device
+    /// key. Sent instead of the mapped command until the mappings are reloaded.
+    reindexed_commands: std::sync::Mutex<HashMap<String, String>>,
+}
+
+/// Events buffered per subscriber before the slowest one starts missing events.
+const EVENT_CAPACITY: usize = 256;
+
+/// Everything that changes in the bridge, published on one channel that all
+/// streaming endpoints subscribe to via [`StateManager::subscribe`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StateEvent {
+    DeviceStateChanged { key: String, device_type: DeviceType, state: DeviceState },
+    DeviceAdded { key: String, device_type: DeviceType },
+    DeviceRemoved { key: String, device_type: DeviceType },
+    SessionRefreshed,
+    DiscoveryCompleted { devices: usize },
+}
+
+impl StateEvent {
+    /// Key and type of the device the event is about; `None` for bridge-wide events.
+    fn device(&self) -> Option<(&str, &DeviceType)> {
+        match self {
+            StateEvent::DeviceStateChanged { key, device_type, .. }
+            | StateEvent::DeviceAdded { key, device_type }
+            | StateEvent::DeviceRemoved { key, device_type } => Some((key, device_type)),
+            StateEvent::SessionRefreshed | StateEvent::DiscoveryCompleted { .. } => None,
+        }
+    }
+}
+
+/// Narrows a subscription to some devices: a device event passes if its key is one
+/// of `keys` or its type one of `types`. Bridge-wide events always pass, and an empty
+/// filter passes everything.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub keys: HashSet<String>,
+    pub types: Vec<DeviceType>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &StateEvent) -> bool {
+        if self.keys.is_empty() && self.types.is_empty() {
+            return true;
+        }
+        event
+            .device()
+            .is_none_or(|(key, type_)| self.keys.contains(key) || self.types.contains(type_))
+    }
+}
+
+/// A single operation for `POST /device/:key/action`, e.g. `{"action":"set_position","position":50}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum DeviceAction {
+    On,
+    Off,
+    SetPosition { position: u8 },
+    Brightness { level: u8 },
+    /// A fan speed step; 0 switches the fan off.
+    FanSpeed { speed: u8 },
+    /// A blind's lamella angle in percent.
+    Tilt { tilt: u8 },
+    Stop,
+    Identify,
+}
+
+impl DeviceAction {
+    /// Rejects percentages the gateway can't represent before any command is built.
+    pub fn check_range(&self) -> std::result::Result<(), ValueOutOfRange> {
+        let (field, value) = match *self {
+            DeviceAction::SetPosition { position } => ("position", position),
+            DeviceAction::Brightness { level } => ("level", level),
+            DeviceAction::Tilt { tilt } => ("tilt", tilt),
+            _ => return Ok(()),
+        };
+        if value > 100 {
+            return Err(ValueOutOfRange { field, value: value.into(), max: 100 });
+        }
+        Ok(())
+    }
+}
+
+/// Parses the compact form used in the environment: `on`, `off`, `stop`, `identify`,
+/// `position:N`, `brightness:N`, `speed:N` or `tilt:N`.
+impl std::str::FromStr for DeviceAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (s.as_str(), None),
+        };
+        let percent = |field: &str| -> std::result::Result<u8, String> {
+            let value = value.ok_or_else(|| format!("{field} needs a value, e.g. '{field}:0'"))?;
+            let percent: u8 = value.parse().map_err(|_| format!("invalid {field} '{value}'"))?;
+            if percent > 100 {
+                return Err(format!("{field} must be between 0 and 100, got {percent}"));
+            }
+            Ok(percent)
+        };
+        let action = match name {
+            "on" => DeviceAction::On,
+            "off" => DeviceAction::Off,
+            "stop" => DeviceAction::Stop,
+            "identify" => DeviceAction::Identify,
+            "position" => DeviceAction::SetPosition { position: percent("position")? },
+            "brightness" => DeviceAction::Brightness { level: percent("brightness")? },
+            "tilt" => DeviceAction::Tilt { tilt: percent("tilt")? },
+            "speed" => {
+                let value = value.ok_or("speed needs a value, e.g. 'speed:1'")?;
+                let speed = value.parse().map_err(|_| format!("invalid speed '{value}'"))?;
+                DeviceAction::FanSpeed { speed }
+            }
+            other => return Err(format!("unknown action '{other}'")),
+        };
+        if value.is_some()
+            && !matches!(
+                action,
+                DeviceAction::SetPosition { .. }
+                    | DeviceAction::Brightness { .. }
+                    | DeviceAction::FanSpeed { .. }
+                    | DeviceAction::Tilt { .. }
+            )
+        {
+            return Err(format!("action '{name}' takes no value"));
+        }
+        Ok(action)
+    }
+}
+
+impl std::fmt::Display for DeviceAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeviceAction::On => write!(f, "on"),
+            DeviceAction::Off => write!(f, "off"),
+            DeviceAction::Stop => write!(f, "stop"),
+            DeviceAction::Identify => write!(f, "identify"),
+            DeviceAction::SetPosition { position } => write!(f, "position:{position}"),
+            DeviceAction::Brightness { level } => write!(f, "brightness:{level}"),
+            DeviceAction::FanSpeed { speed } => write!(f, "speed:{speed}"),
+            DeviceAction::Tilt { tilt } => write!(f, "tilt:{tilt}"),
+        }
+    }
+}
+
+/// The action doesn't apply to this kind of device (e.g. `set_position` on a light).
+#[derive(Debug, thiserror::Error)]
+#[error("Action {action:?} is not supported by {device_type:?} device {device_key}")]
+pub struct UnsupportedAction {
+    pub device_key: String,
+    pub action: DeviceAction,
+    pub device_type: DeviceType,
+}
+
+/// Another discovery or poll held the discovery lock for longer than
+/// `discovery_lock_wait`.
+#[derive(Debug, thiserror::Error)]
+#[error("Discovery already in progress")]
+pub struct DiscoveryInProgress;
+
+/// All `max_event_clients` streaming slots are taken; endpoints answer 503.
+#[derive(Debug, thiserror::Error)]
+#[error("Too many event stream clients (limit {limit})")]
+pub struct TooManySubscribers {
+    pub limit: usize,
+}
+
+/// A streaming client's view of the event channel. Holds one of the
+/// `max_event_clients` slots until dropped, e.g. when the client disconnects.
+pub struct EventSubscription {
+    receiver: broadcast::Receiver<StateEvent>,
+    filter: EventFilter,
+    clients: Arc<AtomicUsize>,
+}
+
+impl EventSubscription {
+    /// Next event that passes the subscription's filter.
+    pub async fn recv(&mut self) -> std::result::Result<StateEvent, broadcast::error::RecvError> {
+        loop {
+            let event = self.receiver.recv().await?;
+            if self.filter.matches(&event) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+impl Drop for EventSubscription {
+    fn drop(&mut self) {
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Served by `GET /ready`; the bridge is ready once it has both a gateway session
+/// and a device list.
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub session_established: bool,
+    pub devices_loaded: bool,
+}
+
+/// Why a device is listed by `GET /attention`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttentionReason {
+    /// A controllable type without any command mapping.
+    Unmapped,
+    /// A controllable type mapped `READONLY`.
+    Readonly,
+    /// The last command or refresh failed.
+    Error,
+    Unreachable,
+    LowBattery,
+    Obstructed,
+}
+
+/// Runtime status served by `GET /diagnostics`.
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub mode: BridgeMode,
+    pub discovery_in_progress: bool,
+    pub event_clients: usize,
+    pub devices: usize,
+    pub polling: PollingStatus,
+    pub rate_limit: Option<RateLimitStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PollingStatus {
+    /// Whether a polling task was started at all.
+    pub running: bool,
+    /// Whether the running task is currently allowed to poll (`false` = paused).
+    pub enabled: bool,
+    pub interval_secs: Option<u64>,
+    /// Interval of the poll that corrects every device, not just sensors.
+    pub state_interval_secs: Option<u64>,
 }
 
 impl StateManager {
     pub fn new(
         client: Arc<KnxClient>,
         command_mapper: Arc<CommandMapper>,
+        config: BridgeConfig,
     ) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let session_events = events.clone();
+        client.on_session_refreshed(move || {
+            let _ = session_events.send(StateEvent::SessionRefreshed);
+        });
+
         Self {
             registry: Arc::new(RwLock::new(DeviceRegistry::new())),
+            command_sink: client.clone(),
             client,
-            command_mapper,
+            command_mapper: std::sync::RwLock::new(command_mapper),
+            config,
+            last_command: Mutex::new(HashMap::new()),
+            polling_interval: OnceLock::new(),
+            state_polling_interval: OnceLock::new(),
+            polling_enabled: AtomicBool::new(true),
+            initialized: AtomicBool::new(false),
+            events,
+            discovery_lock: Mutex::new(()),
+            event_clients: Arc::new(AtomicUsize::new(0)),
+            blind_movements: std::sync::Mutex::new(HashMap::new()),
+            type_overrides: std::sync::Mutex::new(HashMap::new()),
+            reindexed_commands: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The mappings currently in effect.
+    pub fn command_mapper(&self) -> Arc<CommandMapper> {
+        self.command_mapper.read().expect("command mapper lock poisoned").clone()
+    }
+
+    /// Re-reads the mappings file and swaps it in, keeping the current mappings if it
+    /// doesn't load. Virtual devices are re-registered from the new file; device types
+    /// and dimmable flags follow on the next rediscovery.
+    pub async fn reload_mappings<P: AsRef<std::path::Path>>(&self, path: P) -> Result<usize> {
+        let path = path.as_ref();
+        let mapper = Arc::new(CommandMapper::load(path)?);
+        let total = mapper.command_cache.len();
+
+        let _discovery = self.discovery_lock.lock().await;
+        *self.command_mapper.write().expect("command mapper lock poisoned") = mapper.clone();
+        self.reindexed_commands.lock().expect("reindexed commands lock poisoned").clear();
+
+        let mut registry = DeviceRegistry::new();
+        for device in self.registry.read().await.all().filter(|d| !d.is_virtual()) {
+            registry.add(device.clone());
+        }
+        for device in mapper.virtual_devices() {
+            let key = device.key();
+            registry.add(device);
+            self.sync_group(&mut registry, &key);
+        }
+        self.swap_registry(registry).await;
+
+        info!("Reloaded {} command mappings from {}", total, path.display());
+        Ok(total)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    /// Subscribes a streaming client, counted against `max_event_clients`.
+    pub fn subscribe_client(&self, filter: EventFilter) -> std::result::Result<EventSubscription, TooManySubscribers> {
+        let limit = self.config.max_event_clients;
+        self.event_clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| (n < limit).then_some(n + 1))
+            .map_err(|_| TooManySubscribers { limit })?;
+        Ok(EventSubscription {
+            receiver: self.events.subscribe(),
+            filter,
+            clients: self.event_clients.clone(),
+        })
+    }
+
+    /// Builds a filter from comma-separated `?key=` and `?type=` query values. Keys may
+    /// be aliases or lack the key prefix; unknown types are an error.
+    pub async fn event_filter(&self, keys: Option<&str>, types: Option<&str>) -> Result<EventFilter> {
+        let split = |list: Option<&str>| -> Vec<String> {
+            list.unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        let mut filter = EventFilter::default();
+        for key in split(keys) {
+            filter.keys.insert(self.resolve_key(&key).await);
+        }
+        for type_ in split(types) {
+            filter.types.push(type_.parse().map_err(|e: String| anyhow::anyhow!(e))?);
+        }
+        Ok(filter)
+    }
+
+    /// Publishes an event; having no subscribers is not an error.
+    fn emit(&self, event: StateEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Applies `f` to a registered device and emits `DeviceStateChanged` if its
+    /// state changed. Returns `None` if the key isn't registered.
+    fn update_device<R>(
+        &self,
+        registry: &mut DeviceRegistry,
+        device_key: &str,
+        f: impl FnOnce(&mut Device) -> R,
+    ) -> Option<R> {
+        let device = registry.get_mut(device_key)?;
+        let before = device.state.clone();
+        let result = f(device);
+        if device.state != before {
+            self.emit(StateEvent::DeviceStateChanged {
+                key: device_key.to_string(),
+                device_type: device.type_.clone(),
+                state: device.state.clone(),
+            });
+            for group_key in self.command_mapper().groups_of(device_key) {
+                self.sync_group(registry, &group_key);
+            }
+        }
+        Some(result)
+    }
+
+    /// Recomputes a virtual device's on/off from its registered members.
+    fn sync_group(&self, registry: &mut DeviceRegistry, group_key: &str) {
+        let mapper = self.command_mapper();
+        let Some(group) = mapper.virtual_device(group_key) else {
+            return;
+        };
+        let on = group
+            .on_when
+            .aggregate(group.members.iter().filter_map(|m| registry.get(m)).map(Device::is_on));
+        self.update_device(registry, group_key, |device| device.set_on(on));
+    }
+
+    pub async fn initialize(&self) -> Result<()> {
+        info!("Initializing state manager");
+        let _discovery = self.discovery_lock.lock().await;
+        self.discover_and_register().await.map(|_| ())
+    }
+
+    /// Re-runs discovery on request, giving up with [`DiscoveryInProgress`] if a
+    /// running discovery or poll doesn't finish within `discovery_lock_wait`.
+    pub async fn rediscover(&self) -> Result<usize> {
+        let Ok(_discovery) =
+            tokio::time::timeout(self.config.discovery_lock_wait, self.discovery_lock.lock()).await
+        else {
+            return Err(DiscoveryInProgress.into());
+        };
+        info!("Rediscovering devices");
+        self.discover_and_register().await
+    }
+
+    async fn discover_and_register(&self) -> Result<usize> {
+        METRICS.discovery_run();
+        let devices = self.command_sink.discover_devices().await?;
+        let mapper = self.command_mapper();
+
+        let unmatched = mapper.unmatched_overrides(&devices);
+        if !unmatched.is_empty() {
+            warn!("{} overrides match no discovered device: {}", unmatched.len(), unmatched.join(", "));
+        }
+
+        let mut registry = DeviceRegistry::new();
+        for mut device in devices {
+            if self.apply_overrides(&mapper, &mut device) {
+                debug!("Applied override to {}: {} ({:?})", device.key(), device.name, device.type_);
+            }
+            if mapper.is_dimmable(&device) {
+                device.make_dimmable();
+            }
+            let key = device.key();
+            info!("Registered device: {} ({}) [key: {}]", device.name, device.id, key);
+            registry.add(device);
+        }
+
+        for device in mapper.virtual_devices() {
+            let key = device.key();
+            info!("Registered virtual device: {} [key: {}]", device.name, key);
+            registry.add(device);
+            self.sync_group(&mut registry, &key);
+        }
+
+        let mut unmapped_scenes: Vec<String> = registry
+            .all()
+            .filter(|d| d.type_ == DeviceType::Scene && mapper.get_command(&d.id, &d.page).is_none())
+            .map(|d| format!("{} ({})", d.name, d.key()))
+            .collect();
+        if !unmapped_scenes.is_empty() {
+            unmapped_scenes.sort();
+            warn!(
+                "{} scenes have no usable command and can't be activated: {}",
+                unmapped_scenes.len(),
+                unmapped_scenes.join(", ")
+            );
+        }
+
+        for (alias, key) in mapper.aliases() {
+            if registry.get(alias).is_some() {
+                warn!("Alias '{}' is ambiguous: it is also a device key, the device wins", alias);
+            } else if registry.get(key).is_none() {
+                warn!("Alias '{}' points to unknown device key '{}'", alias, key);
+            }
+        }
+
+        let count = registry.count();
+        self.swap_registry(registry).await;
+        self.initialized.store(true, Ordering::Relaxed);
+        METRICS.set_devices(count);
+        self.emit(StateEvent::DiscoveryCompleted { devices: count });
+
+        info!("Initialized {} devices", count);
+        if count > self.config.max_devices {
+            warn!(
+                "Discovered {} devices, more than the configured maximum of {} (SMARTHOME_MAX_DEVICES)",
+                count,
+                self.config.max_devices
+            );
+        }
+        Ok(count)
+    }
+
+    /// Spawns a background task that periodically re-reads sensor values.
+    ///
+    /// In command-only mode only read-only sensors are refreshed and the optimistic
+    /// state of controllable devices is left untouched; stateful mode corrects it too.
+    pub fn start_sensor_polling(self: &Arc<Self>, interval: Duration) {
+        if self.polling_interval.set(interval).is_err() {
+            warn!("Sensor polling already running");
+            return;
+        }
+        self.spawn_polling(interval, false);
+    }
+
+    /// Spawns a background task that periodically re-reads every page and corrects
+    /// the state of all devices, so changes made at a wall switch show up in any mode.
+    pub fn start_polling(self: &Arc<Self>, interval: Duration) {
+        if self.state_polling_interval.set(interval).is_err() {
+            warn!("State polling already running");
+            return;
+        }
+        self.spawn_polling(interval, true);
+    }
+
+    fn spawn_polling(self: &Arc<Self>, interval: Duration, all_states: bool) {
+        let manager = self.clone();
+        tokio::spawn(COMMAND_PRIORITY.scope(CommandPriority::Background, async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if !manager.polling_enabled.load(Ordering::Relaxed) {
+                    debug!("Polling paused, skipping tick");
+                    continue;
+                }
+                let result = if all_states { manager.refresh_states().await } else { manager.refresh_sensors().await };
+                if let Err(e) = result {
+                    warn!("{} polling failed: {}", if all_states { "State" } else { "Sensor" }, e);
+                }
+            }
+        }));
+    }
+
+    /// Pauses or resumes the polling loop without stopping its task.
+    pub fn set_polling_enabled(&self, enabled: bool) {
+        self.polling_enabled.store(enabled, Ordering::Relaxed);
+        info!("State polling {}", if enabled { "resumed" } else { "paused" });
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let session_established = self.client.session_established();
+        let devices_loaded = self.initialized.load(Ordering::Relaxed);
+        Readiness {
+            ready: session_established && devices_loaded,
+            session_established,
+            devices_loaded,
+        }
+    }
+
+    pub async fn diagnostics(&self) -> Diagnostics {
+        Diagnostics {
+            mode: self.config.mode,
+            discovery_in_progress: self.discovery_lock.try_lock().is_err(),
+            event_clients: self.event_clients.load(Ordering::Relaxed),
+            devices: self.registry.read().await.count(),
+            polling: PollingStatus {
+                running: self.polling_interval.get().is_some() || self.state_polling_interval.get().is_some(),
+                enabled: self.polling_enabled.load(Ordering::Relaxed),
+                interval_secs: self.polling_interval.get().map(Duration::as_secs),
+                state_interval_secs: self.state_polling_interval.get().map(Duration::as_secs),
+            },
+            rate_limit: self.client.rate_limit_status(),
+        }
+    }
+
+    pub async fn refresh_sensors(&self) -> Result<usize> {
+        let _discovery = self.discovery_lock.lock().await;
+        if self.client.has_status_endpoint() {
+            match self.client.fetch_all_states().await {
+                Ok(states) => return Ok(self.apply_status_values(&states).await),
+                Err(e) => warn!("Batched status request failed, falling back to page-by-page: {:#}", e),
+            }
+        }
+        self.refresh_pages(self.config.mode == BridgeMode::Stateful).await
+    }
+
+    /// Like `refresh_sensors`, but also corrects controllable devices in command-only
+    /// mode. Always reads the pages, as the batched status endpoint doesn't say whether
+    /// a switch is on.
+    pub async fn refresh_states(&self) -> Result<usize> {
+        let _discovery = self.discovery_lock.lock().await;
+        self.refresh_pages(true).await
+    }
+
+    /// Applies sensor readings from a fresh read of every page, plus the state of
+    /// controllable devices when `correct_controls` is set. The pages are fetched
+    /// before the registry's write lock is taken. Callers hold the discovery lock.
+    async fn refresh_pages(&self, correct_controls: bool) -> Result<usize> {
+        let devices = self.command_sink.discover_devices().await?;
+        let mapper = self.command_mapper();
+
+        let last_page = devices.iter().map(|d| d.page.clone()).max();
+        let seen: HashSet<String> = devices.iter().map(Device::key).collect();
+
+        let mut registry = self.registry.write().await;
+        let mut updated = 0;
+        for mut discovered in devices {
+            self.apply_overrides(&mapper, &mut discovered);
+            let correctable = match &discovered.type_ {
+                DeviceType::Scene => false,
+                DeviceType::WindowCovering => correct_controls && discovered.has_reading,
+                type_ if type_.is_sensor() => discovered.has_reading,
+                _ => correct_controls,
+            };
+            if !correctable {
+                continue;
+            }
+
+            let key = discovered.key();
+            let applied = self.update_device(&mut registry, &key, |device| {
+                let obstruction = device.obstruction();
+                device.battery_low = discovered.battery_low;
+                device.signal = discovered.signal;
+                match (&device.state, discovered.state) {
+                    // The page only shows whether a fan runs, not at which speed.
+                    (DeviceState::FanSpeed(_), DeviceState::OnOff(on)) => device.set_on(on),
+                    (_, state) => device.set_state(state),
+                }
+                device.set_obstruction(obstruction);
+            });
+            if applied.is_some() {
+                updated += 1;
+            }
+        }
+        Self::update_reachability(&mut registry, &seen, last_page.as_deref());
+
+        debug!("Refreshed {} sensor readings", updated);
+        Ok(updated)
+    }
+
+    /// Applies values from the batched status endpoint to registered sensors (and
+    /// blind positions in stateful mode).
+    async fn apply_status_values(&self, states: &HashMap<(String, String), String>) -> usize {
+        let stateful = self.config.mode == BridgeMode::Stateful;
+        let mut registry = self.registry.write().await;
+        let sensors: Vec<(String, String)> = registry
+            .all()
+            .filter(|d| d.type_.is_sensor() || (stateful && d.type_ == DeviceType::WindowCovering))
+            .filter_map(|d| {
+                let value = states.get(&(d.page.clone(), d.index.clone()))?;
+                Some((d.key(), value.clone()))
+            })
+            .collect();
+
+        let mut updated = 0;
+        for (key, value) in sensors {
+            if self.update_device(&mut registry, &key, |device| self.client.apply_status_text(device, &value))
+                == Some(true)
+            {
+                updated += 1;
+            }
+        }
+
+        debug!("Refreshed {} sensor readings from the status endpoint", updated);
+        updated
+    }
+
+    /// Marks devices found by discovery reachable and those missing from a scanned page unreachable.
+    fn update_reachability(
+        registry: &mut DeviceRegistry,
+        seen: &HashSet<String>,
+        last_page: Option<&str>,
+    ) {
+        let Some(last_page) = last_page else {
+            return;
+        };
+
+        for device in registry.all_mut().filter(|d| !d.is_virtual() && d.page.as_str() <= last_page) {
+            let found = seen.contains(&device.key());
+            if found && !device.reachable {
+                info!("Device {} is reachable again", device.key());
+                device.record_success();
+            } else if !found && device.reachable {
+                warn!("Device {} no longer reported by the gateway", device.key());
+                device.reachable = false;
+                device.last_error = Some("Not found during discovery".to_string());
+            }
+        }
+    }
+
+    /// Re-reads a single device from its page and applies the reported state.
+    ///
+    /// Only values actually read from the gateway are applied, so devices without
+    /// a status text keep their current state.
+    pub async fn refresh_device(&self, device_key: &str) -> Result<Option<Device>> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let page = {
+            let registry = self.registry.read().await;
+            let device = registry.get(device_key).ok_or_else(|| {
+                anyhow::anyhow!("Device not found: {device_key}")
+            })?;
+            device.page.clone()
+        };
+
+        let discovered = self
+            .command_sink
+            .discover_page_devices(&page)
+            .await?
+            .into_iter()
+            .find(|d| d.key() == device_key)
+            .map(|mut discovered| {
+                self.apply_overrides(&self.command_mapper(), &mut discovered);
+                discovered
+            });
+
+        let mut registry = self.registry.write().await;
+        let refreshed = self.update_device(&mut registry, device_key, |device| {
+            Self::apply_refresh(device, discovered, &page);
+            device.clone()
+        });
+        Ok(refreshed)
+    }
+
+    /// Applies what a page re-read reported for `device` (`None` = missing from the page).
+    fn apply_refresh(device: &mut Device, discovered: Option<Device>, page: &str) {
+        let device_key = device.key();
+        match discovered {
+            Some(discovered) if discovered.has_reading => {
+                debug!("Refreshed device {} from gateway", device_key);
+                let obstruction = device.obstruction();
+                device.battery_low = discovered.battery_low;
+                device.signal = discovered.signal;
+                device.set_state(discovered.state);
+                device.set_obstruction(obstruction);
+                device.has_reading = true;
+                device.reachable = true;
+            }
+            Some(discovered) => {
+                debug!("Device {} reports no readable state", device_key);
+                device.battery_low = discovered.battery_low;
+                device.signal = discovered.signal;
+                device.reachable = true;
+            }
+            None => {
+                warn!("Device {} not found on page {} during refresh", device_key, page);
+                device.reachable = false;
+                device.last_error = Some(format!("Not found on page {page}"));
+            }
+        }
+    }
+
+    fn blind_confirm_delay(&self, device_key: &str) -> Option<Duration> {
+        match self
+            .command_mapper()
+            .device_options(device_key)
+            .and_then(|o| o.confirm_after_secs)
+        {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => self.config.blind_confirm_delay,
+        }
+    }
+
+    /// Re-reads the blind after the confirm delay. For a full open/close (`expected`
+    /// 100 or 0) the read also sets or clears the obstruction flag.
+    fn schedule_blind_confirm(self: &Arc<Self>, device_key: &str, expected: Option<u8>) {
+        let Some(delay) = self.blind_confirm_delay(device_key) else {
+            return;
+        };
+
+        let manager = self.clone();
+        let device_key = device_key.to_string();
+        tokio::spawn(COMMAND_PRIORITY.scope(CommandPriority::Background, async move {
+            tokio::time::sleep(delay).await;
+            match manager.refresh_device(&device_key).await {
+                Ok(Some(device)) => {
+                    debug!(
+                        "Confirmed blind {} after {}s: {:?}",
+                        device_key,
+                        delay.as_secs(),
+                        device.state
+                    );
+                    if let (Some(expected), DeviceState::WindowCovering { position, .. }) = (expected, &device.state) {
+                        if device.has_reading {
+                            manager.update_obstruction(&device_key, expected, *position).await;
+                        }
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to confirm blind {}: {}", device_key, e),
+            }
+        }));
+    }
+
+    async fn update_obstruction(&self, device_key: &str, expected: u8, actual: u8) {
+        let obstructed = expected.abs_diff(actual) > OBSTRUCTION_TOLERANCE;
+        if obstructed {
+            warn!(
+                "Blind {} stopped at {}% instead of {}%, possible obstruction",
+                device_key, actual, expected
+            );
+        }
+
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| device.set_obstruction(obstructed));
+    }
+
+    /// Sends a command for a device, honouring its configured `command_delay_ms`.
+    async fn send_device_command(&self, device_key: &str, command: &str) -> Result<()> {
+        let delay = self
+            .command_mapper()
+            .device_options(device_key)
+            .and_then(|o| o.command_delay_ms)
+            .map(Duration::from_millis);
+
+        if let Some(delay) = delay {
+            let wait = {
+                let mut last_command = self.last_command.lock().await;
+                let now = Instant::now();
+                let wait = pacing_wait(last_command.get(device_key).copied(), delay, now);
+                last_command.insert(device_key.to_string(), now + wait);
+                wait
+            };
+
+            if !wait.is_zero() {
+                debug!("Delaying command to {} by {}ms", device_key, wait.as_millis());
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let result = self.command_sink.send_command(command).await;
+
+        let mut registry = self.registry.write().await;
+        if let Some(device) = registry.get_mut(device_key) {
+            match &result {
+                Ok(()) => device.record_success(),
+                Err(e) => {
+                    device.record_failure(e.to_string(), self.config.unreachable_after_failures);
+                    if !device.reachable {
+                        warn!(
+                            "Device {} unreachable after {} failed commands",
+                            device_key, device.consecutive_failures
+                        );
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Maps an `[aliases]` entry to its device key; real device keys take precedence.
+    pub async fn resolve_key(&self, key_or_alias: &str) -> String {
+        if self.registry.read().await.get(key_or_alias).is_some() {
+            return key_or_alias.to_string();
+        }
+
+        match self.command_mapper().resolve_alias(key_or_alias) {
+            Some(key) => {
+                debug!("Resolved alias {} to {}", key_or_alias, key);
+                key.to_string()
+            }
+            // Keys without the bridge's prefix are accepted as well.
+            None => command_mapper::with_prefix(command_mapper::key_prefix(), key_or_alias),
+        }
+    }
+
+    pub async fn get_device(&self, id: &str) -> Option<Device> {
+        let key = self.resolve_key(id).await;
+        let registry = self.registry.read().await;
+        registry.get(&key).cloned()
+    }
+
+    /// Changes a device's type until the bridge restarts; rediscovery and mappings
+    /// reloads keep it. To keep it for good, add it to `[overrides]` as logged.
+    pub async fn set_device_type(&self, device_key: &str, type_: DeviceType) -> Result<Device> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let mut registry = self.registry.write().await;
+        let device = self
+            .update_device(&mut registry, device_key, |device| {
+                info!(
+                    "Changing type of {} [key: {}] from {:?} to {:?}",
+                    device.id, device_key, device.type_, type_
+                );
+                device.set_type(type_.clone());
+                if self.command_mapper().is_dimmable(device) {
+                    device.make_dimmable();
+                }
+                device.clone()
+            })
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+
+        info!(
+            "Type of {} kept until restart; to persist it add [overrides.{}] device_type = \"{:?}\" to the mappings",
+            device_key, device_key, type_
+        );
+        self.type_overrides
+            .lock()
+            .expect("type overrides lock poisoned")
+            .insert(device_key.to_string(), type_);
+        Ok(device)
+    }
+
+    /// Applies the mappings' `[overrides]` entry for `device`, then a type set through
+    /// [`Self::set_device_type`]; returns whether either applied.
+    fn apply_overrides(&self, mapper: &CommandMapper, device: &mut Device) -> bool {
+        let from_mappings = mapper.apply_override(device);
+        let runtime_type = self
+            .type_overrides
+            .lock()
+            .expect("type overrides lock poisoned")
+            .get(&device.key())
+            .cloned();
+        match runtime_type {
+            Some(type_) => {
+                device.set_type(type_);
+                true
+            }
+            None => from_mappings,
+        }
+    }
+
+    /// Replaces the whole registry in one step so readers never see a partially
+    /// rebuilt device list. Returns the previous registry.
+    async fn swap_registry(&self, registry: DeviceRegistry) -> DeviceRegistry {
+        let added: Vec<StateEvent> = {
+            let current = self.registry.read().await;
+            registry
+                .all()
+                .filter(|d| current.get(&d.key()).is_none())
+                .map(|d| StateEvent::DeviceAdded { key: d.key(), device_type: d.type_.clone() })
+                .collect()
+        };
+        let previous = std::mem::replace(&mut *self.registry.write().await, registry);
+
+        let removed: Vec<StateEvent> = {
+            let current = self.registry.read().await;
+            previous
+                .all()
+                .filter(|d| current.get(&d.key()).is_none())
+                .map(|d| StateEvent::DeviceRemoved { key: d.key(), device_type: d.type_.clone() })
+                .collect()
+        };
+        for event in added.into_iter().chain(removed) {
+            self.emit(event);
+        }
+        previous
+    }
+
+    /// Replaces the whole registry with a snapshot, e.g. from `POST /import`.
+    ///
+    /// Every device is validated first; the registry is left untouched on error.
+    pub async fn replace_devices(&self, devices: Vec<Device>) -> Result<usize> {
+        let mut registry = DeviceRegistry::new();
+        for device in devices {
+            device.validate().map_err(|e| anyhow::anyhow!("Invalid device: {e}"))?;
+            let key = device.key();
+            if registry.get(&key).is_some() {
+                anyhow::bail!("Duplicate device key: {key}");
+            }
+            registry.add(device);
+        }
+
+        let count = registry.count();
+        self.swap_registry(registry).await;
+        info!("Registry replaced with {} imported devices", count);
+        Ok(count)
+    }
+
+    /// Key of the device at a KNX index on a page; numeric indices match regardless of zero-padding.
+    pub async fn find_key_by_index(&self, page: &str, index: &str) -> Option<String> {
+        let same_index = |candidate: &str| {
+            candidate == index
+                || matches!((candidate.parse::<u32>(), index.parse::<u32>()), (Ok(a), Ok(b)) if a == b)
+        };
+
+        let registry = self.registry.read().await;
+        let key = registry
+            .all()
+            .find(|d| d.page == page && same_index(&d.index))
+            .map(Device::key);
+        key
+    }
+
+    /// Maps devices under the read lock without cloning the whole registry.
+    pub async fn map_devices<T>(&self, f: impl FnMut(&Device) -> Option<T>) -> Vec<T> {
+        let registry = self.registry.read().await;
+        registry.all().filter_map(f).collect()
+    }
+
+    /// Reports the control the current mappings allow, including degraded fallbacks.
+    pub fn capabilities(&self, device: &Device) -> Capabilities {
+        let mapper = self.command_mapper();
+        let degraded = self.config.degraded_control;
+        let has_toggle = mapper.get_command(&device.id, &device.page).is_some()
+            || (device.is_virtual() && mapper.is_actionable(device));
+
+        match device.type_ {
+            DeviceType::Light | DeviceType::Dimmer if mapper.is_dimmable(device) => Capabilities {
+                on_off: has_toggle,
+                brightness: if mapper.render_command(device, 0).is_some() {
+                    Some(ControlMode::Continuous)
+                } else {
+                    (has_toggle && degraded).then_some(ControlMode::OnOff)
+                },
+                ..Capabilities::default()
+            },
+            DeviceType::Light
+            | DeviceType::Dimmer
+            | DeviceType::Switch
+            | DeviceType::Outlet
+            | DeviceType::Fan
+            | DeviceType::Scene => Capabilities {
+                on_off: has_toggle,
+                ..Capabilities::default()
+            },
+            DeviceType::WindowCovering => {
+                let directions = mapper
+                    .get_blind_commands(&device.id, &device.page)
+                    .map(|commands| commands.available())
+                    .unwrap_or_default();
+                let has = |direction| directions.contains(&direction);
+                let position = if has("up") && has("stop") && has("down") {
+                    Some(ControlMode::Stepped)
+                } else if has("up") && has("down") && degraded {
+                    Some(ControlMode::OnOff)
+                } else {
+                    None
+                };
+                Capabilities {
+                    position,
+                    directions,
+                    tilt: mapper
+                        .get_tilt_command(&device.id, &device.page, 0)
+                        .map(|_| ControlMode::Continuous),
+                    ..Capabilities::default()
+                }
+            }
+            DeviceType::TemperatureSensor | DeviceType::HumiditySensor => Capabilities::default(),
+        }
+    }
+
+    /// Scenes paired with whether they can be activated; uncontrollable ones are
+    /// dropped when `hide_unmapped_scenes` is set.
+    pub async fn scenes(&self) -> Vec<(Device, bool)> {
+        let registry = self.registry.read().await;
+        let mut scenes: Vec<(Device, bool)> = registry
+            .all()
+            .filter(|d| d.type_ == DeviceType::Scene)
+            .map(|d| (d.clone(), self.command_mapper().get_command(&d.id, &d.page).is_some()))
+            .filter(|(_, controllable)| *controllable || !self.config.hide_unmapped_scenes)
+            .collect();
+        scenes.sort_by_key(|(d, _)| d.key());
+        scenes
+    }
+
+    /// Devices with at least one problem, with every reason that applies, sorted by key.
+    pub async fn attention(&self) -> Vec<(Device, Vec<AttentionReason>)> {
+        let registry = self.registry.read().await;
+        let mut devices: Vec<(Device, Vec<AttentionReason>)> = registry
+            .all()
+            .filter_map(|device| {
+                let reasons = self.attention_reasons(device);
+                (!reasons.is_empty()).then(|| (device.clone(), reasons))
+            })
+            .collect();
+        devices.sort_by_key(|(d, _)| d.key());
+        devices
+    }
+
+    fn attention_reasons(&self, device: &Device) -> Vec<AttentionReason> {
+        let mut reasons = Vec::new();
+        if !device.type_.is_sensor() && !self.command_mapper().is_actionable(device) {
+            if self.command_mapper().is_readonly(&device.id, &device.page) {
+                reasons.push(AttentionReason::Readonly);
+            } else {
+                reasons.push(AttentionReason::Unmapped);
+            }
+        }
+        if device.last_error.is_some() {
+            reasons.push(AttentionReason::Error);
+        }
+        if !device.reachable {
+            reasons.push(AttentionReason::Unreachable);
+        }
+        if device.battery_low == Some(true) {
+            reasons.push(AttentionReason::LowBattery);
+        }
+        if device.obstruction() {
+            reasons.push(AttentionReason::Obstructed);
+        }
+        reasons
+    }
+
+    /// Devices with this name, compared case-insensitively in NFC.
+    pub async fn find_by_name(&self, name: &str) -> Vec<Device> {
+        let registry = self.registry.read().await;
+        let mut devices: Vec<Device> = registry.find_by_name(name).into_iter().cloned().collect();
+        devices.sort_by_key(Device::key);
+        devices
+    }
+
+    pub async fn get_all_devices(&self) -> Vec<Device> {
+        let registry = self.registry.read().await;
+        registry.all().cloned().collect()
+    }
+
+    pub async fn toggle_device(self: &Arc<Self>, device_key: &str, target_state: bool) -> Result<()> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let current_state = {
+            let registry = self.registry.read().await;
+            registry.get(device_key).map(super::device::Device::is_on)
+        };
+
+        let Some(current) = current_state else {
+                return Err(anyhow::anyhow!("Device not found: {device_key}"));
+            };
+
+        let (device_id, page, index, type_) = {
+            let registry = self.registry.read().await;
+            let device = registry.get(device_key).ok_or_else(|| {
+                anyhow::anyhow!("Device not found: {device_key}")
+            })?;
+            (device.id.clone(), device.page.clone(), device.index.clone(), device.type_.clone())
+        };
+
+        if let Some(group) = self.command_mapper().virtual_device(device_key) {
+            return self.toggle_group(device_key, &group.members, target_state).await;
+        }
+
+        if type_ == DeviceType::Scene {
+            return self.activate_scene(device_key, &device_id, &page, &index, target_state).await;
+        }
+
+        if current == target_state {
+            debug!(
+                "Device {} [key: {}] already in desired state: {}",
+                device_id, device_key, target_state
+            );
+        } else {
+            let reindexed = self
+                .reindexed_commands
+                .lock()
+                .expect("reindexed commands lock poisoned")
+                .get(device_key)
+                .cloned();
+            let mapped = || self.command_mapper().get_command(&device_id, &page).map(str::to_string);
+            let command = reindexed.or_else(mapped).ok_or_else(|| {
+                anyhow::anyhow!("No command mapping found for device: {device_id} (page: {page}, index: {index})")
+            })?;
+
+            info!(
+                "Toggling device {} [key: {}] from {} to {}",
+                device_id, device_key, current, target_state
+            );
+
+            if let Err(e) = self.send_device_command(device_key, &command).await {
+                let Some(command) = self.reindexed_command(device_key, &command, &e).await else {
+                    return Err(e);
+                };
+                self.send_device_command(device_key, &command).await?;
+                self.reindexed_commands
+                    .lock()
+                    .expect("reindexed commands lock poisoned")
+                    .insert(device_key.to_string(), command);
+            }
+
+            let mut registry = self.registry.write().await;
+            self.update_device(&mut registry, device_key, |device| device.set_on(target_state));
+        }
+
+        Ok(())
+    }
+
+    /// After a failed command, re-reads the device's page (if `stale_index_retry` is on)
+    /// and returns the command rewritten for the index the gateway now reports, if that
+    /// differs from the command's. Once the retry succeeds the rewritten command is kept
+    /// for the device until the mappings are reloaded; its registry index stays as mapped.
+    async fn reindexed_command(&self, device_key: &str, command: &str, error: &anyhow::Error) -> Option<String> {
+        if !self.config.stale_index_retry {
+            return None;
+        }
+        debug!("Command to {} failed ({:#}), checking for a stale index", device_key, error);
+
+        let page = self.get_device(device_key).await?.page;
+        let current_index = match self.command_sink.discover_page_devices(&page).await {
+            Ok(devices) => devices.into_iter().find(|d| d.key() == device_key)?.index,
+            Err(e) => {
+                warn!("Could not re-read page {} for {}: {}", page, device_key, e);
+                return None;
+            }
+        };
+        let corrected = command_mapper::reindex_command(command, &current_index)?;
+
+        warn!(
+            "Stale index for {}: gateway now reports index {}, the mapped command is {}",
+            device_key, current_index, command
+        );
+        info!("Retrying {} with corrected command {} (update the mappings file)", device_key, corrected);
+        Some(corrected)
+    }
+
+    /// Switches every member of a virtual device; the group's own state follows from
+    /// the members'. Fails if any member failed, naming them all.
+    async fn toggle_group(self: &Arc<Self>, group_key: &str, members: &[String], target_state: bool) -> Result<()> {
+        info!("Switching virtual device {} ({} members) to {}", group_key, members.len(), target_state);
+        let mut failed = Vec::new();
+        for member in members {
+            if let Err(e) = Box::pin(self.toggle_device(member, target_state)).await {
+                warn!("Virtual device {}: member {} failed: {}", group_key, member, e);
+                failed.push(member.as_str());
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "Virtual device {group_key}: {} of {} members failed: {}",
+                failed.len(),
+                members.len(),
+                failed.join(", ")
+            ))
+        }
+    }
+
+    /// Re-reads the device's page until the gateway reports it `on`/off, giving up
+    /// after `confirm_timeout`. Read errors count as "not yet confirmed". A virtual
+    /// device is confirmed once all of its members are.
+    pub async fn confirm_on(&self, device_key: &str, on: bool) -> Result<bool> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let deadline = Instant::now() + self.config.confirm_timeout;
+        let members = match self.command_mapper().virtual_device(device_key) {
+            Some(group) => group.members.clone(),
+            None => vec![device_key.to_string()],
+        };
+        for member in &members {
+            if !self.confirm_member_on(member, on, deadline).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn confirm_member_on(&self, device_key: &str, on: bool, deadline: Instant) -> Result<bool> {
+        let page = self
+            .get_device(device_key)
+            .await
+            .map(|d| d.page)
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+
+        loop {
+            match self.client.discover_page_devices(&page).await {
+                Ok(devices) => {
+                    if devices.iter().any(|d| d.key() == device_key && d.is_on() == on) {
+                        debug!("Gateway confirmed {} is {}", device_key, if on { "on" } else { "off" });
+                        return Ok(true);
+                    }
+                }
+                Err(e) => warn!("Read-back of {} failed: {}", device_key, e),
+            }
+
+            if Instant::now() + CONFIRM_POLL_INTERVAL > deadline {
+                return Ok(false);
+            }
+            tokio::time::sleep(CONFIRM_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Runs an action against a device after checking it applies to the device's type.
+    pub async fn perform_action(self: &Arc<Self>, device_key: &str, action: DeviceAction) -> Result<()> {
+        action.check_range()?;
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let device = self
+            .get_device(device_key)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+
+        let supported = match action {
+            DeviceAction::On if device.type_ == DeviceType::Scene => self.capabilities(&device).on_off,
+            DeviceAction::On | DeviceAction::Off => matches!(
+                device.type_,
+                DeviceType::Light
+                    | DeviceType::Dimmer
+                    | DeviceType::Switch
+                    | DeviceType::Outlet
+                    | DeviceType::Fan
+                    | DeviceType::Scene
+            ),
+            DeviceAction::SetPosition { .. } | DeviceAction::Stop => {
+                device.type_ == DeviceType::WindowCovering
+            }
+            DeviceAction::Brightness { .. } => self.command_mapper().is_dimmable(&device),
+            DeviceAction::FanSpeed { .. } => device.type_ == DeviceType::Fan,
+            DeviceAction::Tilt { .. } => self.capabilities(&device).tilt.is_some(),
+            DeviceAction::Identify => {
+                !device.type_.is_sensor() && device.type_ != DeviceType::Scene && !device.is_virtual()
+            }
+        };
+        if !supported {
+            return Err(UnsupportedAction {
+                device_key: device_key.to_string(),
+                action,
+                device_type: device.type_,
+            }
+            .into());
+        }
+
+        match action {
+            DeviceAction::On => self.toggle_device(device_key, true).await,
+            DeviceAction::Off => self.toggle_device(device_key, false).await,
+            DeviceAction::SetPosition { position } => self.set_blind_position(device_key, position).await,
+            DeviceAction::Brightness { level } => self.set_brightness(device_key, level).await,
+            DeviceAction::FanSpeed { speed } => self.set_fan_speed(device_key, speed).await,
+            DeviceAction::Tilt { tilt } => self.set_blind_tilt(device_key, tilt).await,
+            DeviceAction::Stop => self.stop_blind(device_key).await,
+            DeviceAction::Identify => self.identify_device(device_key).await,
+        }
+    }
+
+    /// Stops blinds still travelling to an intermediate position, then waits up to
+    /// `timeout` for commands already on their way to the gateway.
+    pub async fn shutdown(&self, timeout: Duration) {
+        let moving: Vec<String> = self.blind_movements.lock().unwrap().keys().cloned().collect();
+        for key in moving {
+            info!("Stopping blind {} before shutdown instead of leaving it moving", key);
+            if let Err(e) = self.stop_blind(&key).await {
+                warn!("Failed to stop blind {} at shutdown: {:#}", key, e);
+            }
+        }
+        self.client.shutdown(timeout).await;
+    }
+
+    /// Runs the configured `shutdown_actions` in order through the normal command path.
+    /// A failing action is logged and doesn't stop the rest.
+    pub async fn run_shutdown_actions(self: &Arc<Self>) {
+        COMMAND_PRIORITY
+            .scope(CommandPriority::Background, async {
+                for (key, action) in &self.config.shutdown_actions {
+                    info!("Shutdown action: {} -> {}", key, action);
+                    if let Err(e) = self.perform_action(key, action.clone()).await {
+                        warn!("Shutdown action {} for {} failed: {:#}", action, key, e);
+                    }
+                }
+            })
+            .await;
+    }
+
+    /// Scenes are momentary: "on" always fires the command and the cached state
+    /// falls back to off after the configured revert delay; "off" sends nothing.
+    async fn activate_scene(
+        self: &Arc<Self>,
+        device_key: &str,
+        device_id: &str,
+        page: &str,
+        index: &str,
+        target_state: bool,
+    ) -> Result<()> {
+        if !target_state {
+            debug!("Scene {} [key: {}] has no off action", device_id, device_key);
+            self.set_device_on(device_key, false).await;
+            return Ok(());
+        }
+
+        let mapper = self.command_mapper();
+
+        let command = mapper.get_command(device_id, page).ok_or_else(|| {
+            anyhow::anyhow!("No command mapping found for scene: {device_id} (page: {page}, index: {index})")
+        })?;
+
+        info!("Activating scene {} [key: {}]", device_id, device_key);
+        self.send_device_command(device_key, command).await?;
+        self.set_device_on(device_key, true).await;
+
+        let manager = self.clone();
+        let device_key = device_key.to_string();
+        let delay = self.config.scene_revert_delay;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            manager.set_device_on(&device_key, false).await;
+            debug!("Scene {} reverted to off", device_key);
+        });
+
+        Ok(())
+    }
+
+    /// Fires a scene's command once without touching its cached state, unlike
+    /// toggling it on.
+    pub async fn trigger_scene(&self, device_key: &str) -> Result<()> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let device = self
+            .get_device(device_key)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+        if device.type_ != DeviceType::Scene {
+            anyhow::bail!("Device {device_key} is not a scene");
+        }
+
+        let mapper = self.command_mapper();
+
+        let command = mapper.get_command(&device.id, &device.page).ok_or_else(|| {
+            anyhow::anyhow!("No command mapping found for scene: {} (page: {}, index: {})", device.id, device.page, device.index)
+        })?;
+
+        info!("Triggering scene {} [key: {}]", device.id, device_key);
+        self.send_device_command(device_key, command).await
+    }
+
+    async fn set_device_on(&self, device_key: &str, on: bool) {
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| device.set_on(on));
+    }
+
+    /// Runs actions one after another in the given order, e.g. a HomeKit scene sent
+    /// as one batch. Returns the outcome per entry; a failing one doesn't stop the rest.
+    pub async fn perform_actions(self: &Arc<Self>, actions: Vec<(String, DeviceAction)>) -> Vec<(String, Result<()>)> {
+        info!("Running a batch of {} actions", actions.len());
+
+        let mut results = Vec::with_capacity(actions.len());
+        for (key, action) in actions {
+            let result = self.perform_action(&key, action.clone()).await;
+            if let Err(e) = &result {
+                warn!("Batch action {} on {} failed: {}", action, key, e);
+            }
+            results.push((key, result));
         }
+        results
     }
 
-    pub async fn initialize(&self) -> Result<()> {
-        info!("Initializing state manager");
-        let devices = self.client.discover_devices().await?;
+    /// Switches every on/off-capable device on a page, skipping blinds, sensors and scenes.
+    ///
+    /// Returns the outcome per device key; one failing device doesn't stop the rest.
+    pub async fn toggle_page(self: &Arc<Self>, page: &str, target_state: bool) -> Vec<(String, Result<()>)> {
+        let mut keys: Vec<String> = {
+            let registry = self.registry.read().await;
+            registry
+                .all()
+                .filter(|d| d.page == page)
+                .filter(|d| {
+                    matches!(
+                        d.type_,
+                        DeviceType::Light
+                            | DeviceType::Dimmer
+                            | DeviceType::Switch
+                            | DeviceType::Outlet
+                            | DeviceType::Fan
+                    )
+                })
+                .map(Device::key)
+                .collect()
+        };
+        keys.sort();
 
-        let mut registry = self.registry.write().await;
-        for device in devices {
-            let key = device.key();
-            info!("Registered device: {} ({}) [key: {}]", device.name, device.id, key);
-            registry.add(device);
+        info!("Switching {} devices on page {} to {}", keys.len(), page, target_state);
+
+        let mut results = Vec::with_capacity(keys.len());
+        for key in keys {
+            let result = self.toggle_device(&key, target_state).await;
+            if let Err(e) = &result {
+                warn!("Failed to switch {} on page {}: {}", key, page, e);
+            }
+            results.push((key, result));
+        }
+        results
+    }
+
+    /// Blinks an on/off device (or nudges a blind) so it can be located physically.
+    ///
+    /// Light commands toggle, so an even number of sends leaves the device in its
+    /// original state.
+    pub async fn identify_device(&self, device_key: &str) -> Result<()> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let device = self
+            .get_device(device_key)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+        let interval = self.config.identify_interval;
+
+        match device.type_ {
+            DeviceType::WindowCovering => {
+                let mapper = self.command_mapper();
+                let command_for = |suffix: &str| {
+                    mapper
+                        .get_blind_command(&device.id, &device.page, suffix)
+                        .ok_or_else(|| {
+                            anyhow::anyhow!(
+                                "No command mapping found for blind: {device_key} ({suffix}, index: {})",
+                                device.index
+                            )
+                        })
+                };
+                let (up, stop) = (command_for("up")?, command_for("stop")?);
+
+                info!("Identifying blind {} [key: {}]", device.id, device_key);
+                self.send_device_command(device_key, up).await?;
+                tokio::time::sleep(interval).await;
+                self.send_device_command(device_key, stop).await
+            }
+            DeviceType::Light
+            | DeviceType::Dimmer
+            | DeviceType::Switch
+            | DeviceType::Outlet
+            | DeviceType::Fan => {
+                let mapper = self.command_mapper();
+                let command = mapper.get_command(&device.id, &device.page).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "No command mapping found for device: {} (page: {}, index: {})",
+                        device.id, device.page, device.index
+                    )
+                })?;
+
+                info!(
+                    "Identifying device {} [key: {}] with {} blinks",
+                    device.id, device_key, self.config.identify_blinks
+                );
+                for sent in 0..self.config.identify_blinks * 2 {
+                    if sent > 0 {
+                        tokio::time::sleep(interval).await;
+                    }
+                    if let Err(e) = self.send_device_command(device_key, command).await {
+                        if sent % 2 == 1 {
+                            warn!("Identify of {} interrupted with the device toggled", device_key);
+                            self.set_device_on(device_key, !device.is_on()).await;
+                        }
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(anyhow::anyhow!("Identify not supported for {:?} device: {device_key}", device.type_)),
+        }
+    }
+
+    /// Sets a dimmer's brightness. Without a brightness command this degrades to
+    /// the toggle command (0 = off, anything else = on) unless disabled in the config.
+    pub async fn set_brightness(self: &Arc<Self>, device_key: &str, level: u8) -> Result<()> {
+        if level > 100 {
+            anyhow::bail!("Brightness must be between 0 and 100, got {level}");
+        }
+
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+
+        let device = self
+            .get_device(device_key)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+        if !self.command_mapper().is_dimmable(&device) {
+            anyhow::bail!("Device {device_key} is not dimmable");
         }
 
-        info!("Initialized {} devices", registry.count());
+        if let Some(command) = self.command_mapper().render_command(&device, level) {
+            info!("Setting brightness of {} [key: {}] to {}%", device.name, device_key, level);
+            self.send_device_command(device_key, &command).await?;
+
+            let mut registry = self.registry.write().await;
+            self.update_device(&mut registry, device_key, |device| {
+                if matches!(device.state, DeviceState::Brightness { .. }) {
+                    device.set_state(DeviceState::Brightness { on: level > 0, level });
+                }
+            });
+            return Ok(());
+        }
+        if !self.config.degraded_control {
+            anyhow::bail!("No brightness command mapped for {device_key}");
+        }
+
+        let on = level > 0;
+        debug!("Degrading brightness {}% to {} for {}", level, if on { "on" } else { "off" }, device_key);
+        self.toggle_device(device_key, on).await?;
+
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| {
+            if matches!(device.state, DeviceState::Brightness { .. }) {
+                device.set_state(DeviceState::Brightness { on, level: if on { 100 } else { 0 } });
+            }
+        });
+
         Ok(())
     }
 
-    pub async fn get_device(&self, id: &str) -> Option<Device> {
-        let registry = self.registry.read().await;
-        registry.get(id).cloned()
+    /// Sends the fan's `_speedN` command from `[ventilation]`. Speed 0 uses `_speed0` or
+    /// `_off` when mapped and otherwise switches the fan off with its normal command.
+    pub async fn set_fan_speed(self: &Arc<Self>, device_key: &str, speed: u8) -> Result<()> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+
+        let device = self
+            .get_device(device_key)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+        if device.type_ != DeviceType::Fan {
+            anyhow::bail!("Device {device_key} is not a fan");
+        }
+
+        match self.command_mapper().get_fan_speed_command(&device.id, &device.page, speed) {
+            Some(command) => {
+                info!("Setting fan {} [key: {}] to speed {}", device.name, device_key, speed);
+                self.send_device_command(device_key, command).await?;
+            }
+            None if speed == 0 => self.toggle_device(device_key, false).await?,
+            None => {
+                let steps = self.command_mapper().fan_speed_steps(&device.id, &device.page);
+                anyhow::bail!("No speed {speed} command mapped for fan {device_key} (mapped steps: {steps:?})");
+            }
+        }
+
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| device.set_state(DeviceState::FanSpeed(speed)));
+        Ok(())
     }
 
-    pub async fn get_all_devices(&self) -> Vec<Device> {
-        let registry = self.registry.read().await;
-        registry.all().cloned().collect()
+    /// Sets a blind's lamella angle through its `_tilt` mapping; position is unaffected.
+    pub async fn set_blind_tilt(self: &Arc<Self>, device_key: &str, tilt: u8) -> Result<()> {
+        if tilt > 100 {
+            anyhow::bail!("Blind tilt must be between 0 and 100, got {tilt}");
+        }
+
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+
+        let device = self
+            .get_device(device_key)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Device not found: {device_key}"))?;
+        if device.type_ != DeviceType::WindowCovering {
+            anyhow::bail!("Device {device_key} is not a blind");
+        }
+
+        let command = self
+            .command_mapper()
+            .get_tilt_command(&device.id, &device.page, tilt)
+            .ok_or_else(|| anyhow::anyhow!("No tilt command mapped for blind: {device_key} (index: {})", device.index))?;
+
+        info!("Setting blind {} [key: {}] tilt to {}%", device.name, device_key, tilt);
+        self.send_device_command(device_key, &command).await?;
+
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| {
+            if let DeviceState::WindowCovering { position, state, obstruction, .. } = device.state.clone() {
+                device.set_state(DeviceState::WindowCovering { position, state, obstruction, tilt: Some(tilt) });
+            }
+        });
+        Ok(())
     }
 
-    pub async fn toggle_device(&self, device_key: &str, target_state: bool) -> Result<()> {
-        let current_state = {
-            let registry = self.registry.read().await;
-            registry.get(device_key).map(super::device::Device::is_on)
+    /// Drives a blind with a known travel time to an intermediate position: starts
+    /// it moving, then sends `stop` once the matching share of the travel time has
+    /// passed. The registry follows the estimated position on the way.
+    async fn move_blind_timed(
+        self: &Arc<Self>,
+        device_key: &str,
+        commands: &BlindCommands,
+        stop: String,
+        target: u8,
+        travel_time: Duration,
+    ) -> Result<()> {
+        use crate::device::WindowCoveringState;
+        let start = match self.get_device(device_key).await.map(|device| device.state) {
+            Some(DeviceState::WindowCovering { position, .. }) => position,
+            _ => anyhow::bail!("Device {device_key} is not a blind"),
         };
+        if start == target {
+            debug!("Blind [key: {}] already at {}%", device_key, target);
+            return Ok(());
+        }
 
-        let Some(current) = current_state else {
-                return Err(anyhow::anyhow!("Device not found: {device_key}"));
-            };
+        let (direction, moving) = if target > start {
+            ("up", WindowCoveringState::Opening)
+        } else {
+            ("down", WindowCoveringState::Closing)
+        };
+        let command = commands.get(direction).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Blind {device_key} has no '{direction}' command for {target}% (mapped: {})",
+                commands.available().join(", ")
+            )
+        })?;
+        let duration = travel_duration(start, target, travel_time);
+        info!(
+            "Moving blind [key: {}] from {}% to {}%, stopping after {}ms",
+            device_key,
+            start,
+            target,
+            duration.as_millis()
+        );
+        self.send_device_command(device_key, command).await?;
+        self.set_blind_estimate(device_key, start, moving.clone()).await;
+
+        let manager = self.clone();
+        let key = device_key.to_string();
+        let task = tokio::spawn(async move {
+            let started = Instant::now();
+            loop {
+                let remaining = duration.saturating_sub(started.elapsed());
+                if remaining.is_zero() {
+                    break;
+                }
+                tokio::time::sleep(remaining.min(BLIND_PROGRESS_INTERVAL)).await;
+                let position = travel_position(start, target, started.elapsed(), duration);
+                manager.set_blind_estimate(&key, position, moving.clone()).await;
+            }
+
+            if let Err(e) = manager.send_device_command(&key, &stop).await {
+                warn!("Failed to stop blind {} at {}%: {}", key, target, e);
+            }
+            manager.set_blind_estimate(&key, target, WindowCoveringState::Stopped).await;
+            {
+                let mut movements = manager.blind_movements.lock().unwrap();
+                if movements.get(&key).is_some_and(|handle| handle.id() == tokio::task::id()) {
+                    movements.remove(&key);
+                }
+            }
+            manager.schedule_blind_confirm(&key, None);
+        });
+        self.blind_movements
+            .lock()
+            .unwrap()
+            .insert(device_key.to_string(), task.abort_handle());
+        Ok(())
+    }
+
+    /// Drops a blind's pending timed stop; its position stays at the last estimate.
+    fn cancel_blind_movement(&self, device_key: &str) {
+        if let Some(handle) = self.blind_movements.lock().unwrap().remove(device_key) {
+            debug!("Cancelling pending stop of blind [key: {}]", device_key);
+            handle.abort();
+        }
+    }
+
+    async fn set_blind_estimate(&self, device_key: &str, position: u8, state: crate::device::WindowCoveringState) {
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| {
+            if let DeviceState::WindowCovering { obstruction, tilt, .. } = device.state {
+                device.set_state(DeviceState::WindowCovering { position, state, obstruction, tilt });
+            }
+        });
+    }
 
-        let (device_id, page) = {
+    /// Halts a moving blind, keeping the last known position.
+    pub async fn stop_blind(&self, device_key: &str) -> Result<()> {
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+        let (device_id, page, index) = {
             let registry = self.registry.read().await;
             let device = registry.get(device_key).ok_or_else(|| {
                 anyhow::anyhow!("Device not found: {device_key}")
             })?;
-            (device.id.clone(), device.page.clone())
+            (device.id.clone(), device.page.clone(), device.index.clone())
         };
 
-        if current == target_state {
-            debug!(
-                "Device {} [key: {}] already in desired state: {}",
-                device_id, device_key, target_state
-            );
-        } else {
-            let command = self.command_mapper.get_command(&device_id, &page).ok_or_else(|| {
-                anyhow::anyhow!("No command mapping found for device: {device_id} (page: {page})")
-            })?;
+        let mapper = self.command_mapper();
 
-            info!(
-                "Toggling device {} [key: {}] from {} to {}",
-                device_id, device_key, current, target_state
-            );
+        let command = mapper.get_blind_command(&device_id, &page, "stop").ok_or_else(|| {
+            anyhow::anyhow!("No command mapping found for blind: {device_key} (stop, index: {index})")
+        })?;
 
-            self.client.send_command(command).await?;
+        self.cancel_blind_movement(device_key);
+        info!("Stopping blind {} [key: {}]", device_id, device_key);
+        self.send_device_command(device_key, command).await?;
 
-            let mut registry = self.registry.write().await;
-            if let Some(device) = registry.get_mut(device_key) {
-                device.set_on(target_state);
+        let mut registry = self.registry.write().await;
+        self.update_device(&mut registry, device_key, |device| {
+            if let DeviceState::WindowCovering { position, obstruction, tilt, .. } = device.state {
+                device.set_state(DeviceState::WindowCovering {
+                    position,
+                    state: crate::device::WindowCoveringState::Stopped,
+                    obstruction,
+                    tilt,
+                });
             }
-        }
-
+        });
         Ok(())
     }
 
-    pub async fn set_blind_position(&self, device_key: &str, position: u8) -> Result<()> {
-        let (device_id, page) = {
+    pub async fn set_blind_position(self: &Arc<Self>, device_key: &str, position: u8) -> Result<()> {
+        if position > 100 {
+            anyhow::bail!("Blind position must be between 0 and 100, got {position}");
+        }
+
+        let resolved = self.resolve_key(device_key).await;
+        let device_key = resolved.as_str();
+
+        let (device_id, page, index) = {
             let registry = self.registry.read().await;
             let device = registry.get(device_key).ok_or_else(|| {
                 anyhow::anyhow!("Device not found: {device_key}")
             })?;
-            (device.id.clone(), device.page.clone())
+            (device.id.clone(), device.page.clone(), device.index.clone())
         };
 
-        let command_suffix = if position <= 10 {
+        let mut command_suffix = if position <= 10 {
             "down"
         } else if position >= 90 {
             "up"
         } else {
             "stop"
         };
+        let mut position = position;
+
+        let mapper = self.command_mapper();
+        let commands = mapper.get_blind_commands(&device_id, &page).ok_or_else(|| {
+            anyhow::anyhow!("No command mapping found for blind: {device_key} (index: {index})")
+        })?;
 
-        let base_key = CommandMapper::device_key(&device_id, &page);
-        let command_key = format!("{base_key}_{command_suffix}");
+        // Whatever happens next replaces a movement still waiting for its stop.
+        self.cancel_blind_movement(device_key);
+        let travel_time = mapper
+            .device_options(device_key)
+            .and_then(|o| o.travel_time_secs)
+            .filter(|secs| secs.is_finite() && *secs > 0.0)
+            .map(Duration::from_secs_f64);
+        if let (Some(travel_time), "stop", Some(stop)) = (travel_time, command_suffix, &commands.stop) {
+            return self.move_blind_timed(device_key, &commands, stop.clone(), position, travel_time).await;
+        }
 
-        let command = self.command_mapper.command_cache.get(&command_key).ok_or_else(|| {
-            anyhow::anyhow!("No command mapping found for blind: {device_key} ({command_suffix})")
+        if commands.stop.is_none() && command_suffix == "stop" && self.config.degraded_control {
+            // Without a stop command the blind can only be driven to either end.
+            (command_suffix, position) = if position < 50 { ("down", 0) } else { ("up", 100) };
+        }
+        let command = commands.get(command_suffix).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Blind {device_key} (index: {index}) has no '{command_suffix}' command for {position}% (mapped: {})",
+                commands.available().join(", ")
+            )
         })?;
 
         info!(
@@ -123,25 +1843,586 @@ impl StateManager {
             device_id, device_key, position, command_suffix
         );
 
-        self.client.send_command(command).await?;
+        self.send_device_command(device_key, command).await?;
 
         let mut registry = self.registry.write().await;
-        if let Some(device) = registry.get_mut(device_key) {
+        self.update_device(&mut registry, device_key, |device| {
             use crate::device::WindowCoveringState;
-            let covering_state = if position <= 10 {
-                WindowCoveringState::Closing
-            } else if position >= 90 {
-                WindowCoveringState::Opening
-            } else {
-                WindowCoveringState::Stopped
+            let DeviceState::WindowCovering { position: current, obstruction, tilt, .. } = device.state else {
+                return;
             };
-            device.state = DeviceState::WindowCovering {
+            // "stop" doesn't move the blind anywhere, so the requested position would
+            // be a guess; keep the last known one.
+            let (position, covering_state) = match command_suffix {
+                "down" => (position, WindowCoveringState::Closing),
+                "up" => (position, WindowCoveringState::Opening),
+                _ => (current, WindowCoveringState::Stopped),
+            };
+            device.set_state(DeviceState::WindowCovering {
                 position,
                 state: covering_state,
-            };
-        }
+                obstruction,
+                tilt,
+            });
+        });
+        drop(registry);
+
+        let full_travel = match command_suffix {
+            "up" => Some(100),
+            "down" => Some(0),
+            _ => None,
+        };
+        self.schedule_blind_confirm(device_key, full_travel);
 
         Ok(())
     }
 }
 
+/// How often a timed blind movement updates its estimated position.
+const BLIND_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time a blind needs to travel from `start` to `target` percent.
+fn travel_duration(start: u8, target: u8, travel_time: Duration) -> Duration {
+    travel_time.mul_f64(f64::from(start.abs_diff(target)) / 100.0)
+}
+
+/// Estimated position `elapsed` into a movement from `start` to `target` that takes `duration`.
+fn travel_position(start: u8, target: u8, elapsed: Duration, duration: Duration) -> u8 {
+    let progress = if duration.is_zero() {
+        1.0
+    } else {
+        (elapsed.as_secs_f64() / duration.as_secs_f64()).min(1.0)
+    };
+    let position = f64::from(start) + (f64::from(target) - f64::from(start)) * progress;
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let position = position.round().clamp(0.0, 100.0) as u8;
+    position
+}
+
+/// Pause between read-backs while waiting for a toggle to be confirmed.
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far (in percent) a confirmed blind may stop short of a full open/close
+/// before it's reported as obstructed.
+const OBSTRUCTION_TOLERANCE: u8 = 5;
+
+/// How long to wait before sending so that at least `delay` separates it from `last_sent`.
+fn pacing_wait(last_sent: Option<Instant>, delay: Duration, now: Instant) -> Duration {
+    last_sent.map_or(Duration::ZERO, |last| (last + delay).saturating_duration_since(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KnxConfig;
+    use futures::future::BoxFuture;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        sent: std::sync::Mutex<Vec<String>>,
+        /// Commands the "gateway" rejects; still recorded in `sent`.
+        rejected: std::sync::Mutex<HashSet<String>>,
+        /// What discovery and page re-reads return.
+        page_devices: std::sync::Mutex<Vec<Device>>,
+    }
+
+    impl KnxCommandSink for RecordingSink {
+        fn send_command<'a>(&'a self, command: &'a str) -> BoxFuture<'a, Result<()>> {
+            self.sent.lock().unwrap().push(command.to_string());
+            let rejected = self.rejected.lock().unwrap().contains(command);
+            Box::pin(async move {
+                if rejected {
+                    anyhow::bail!("Gateway rejected {command}");
+                }
+                Ok(())
+            })
+        }
+
+        fn discover_devices(&self) -> BoxFuture<'_, Result<Vec<Device>>> {
+            let devices = self.page_devices.lock().unwrap().clone();
+            Box::pin(async { Ok(devices) })
+        }
+
+        fn discover_page_devices<'a>(&'a self, _page: &'a str) -> BoxFuture<'a, Result<Vec<Device>>> {
+            let devices = self.page_devices.lock().unwrap().clone();
+            Box::pin(async { Ok(devices) })
+        }
+    }
+
+    fn test_manager(mappings: &str) -> (Arc<StateManager>, Arc<RecordingSink>) {
+        test_manager_with(mappings, BridgeConfig::default())
+    }
+
+    fn test_manager_with(mappings: &str, bridge: BridgeConfig) -> (Arc<StateManager>, Arc<RecordingSink>) {
+        let config = KnxConfig::test_default();
+        let client = Arc::new(KnxClient::new(Arc::new(config), true).unwrap());
+        let mapper = Arc::new(CommandMapper::from_toml(mappings).unwrap());
+        let sink = Arc::new(RecordingSink::default());
+
+        let mut manager = StateManager::new(client, mapper, bridge);
+        manager.command_sink = sink.clone();
+        (Arc::new(manager), sink)
+    }
+
+    #[test]
+    fn test_travel_position() {
+        let travel_time = Duration::from_secs(20);
+        assert_eq!(travel_duration(100, 30, travel_time), Duration::from_secs(14));
+        assert_eq!(travel_duration(30, 30, travel_time), Duration::ZERO);
+
+        let duration = travel_duration(100, 30, travel_time);
+        assert_eq!(travel_position(100, 30, Duration::ZERO, duration), 100);
+        assert_eq!(travel_position(100, 30, Duration::from_secs(7), duration), 65);
+        assert_eq!(travel_position(100, 30, Duration::from_secs(30), duration), 30);
+        assert_eq!(travel_position(20, 60, Duration::from_secs(4), travel_duration(20, 60, travel_time)), 40);
+    }
+
+    #[test]
+    fn test_pacing_wait() {
+        let delay = Duration::from_millis(500);
+        let start = Instant::now();
+
+        assert_eq!(pacing_wait(None, delay, start), Duration::ZERO);
+        assert_eq!(
+            pacing_wait(Some(start), delay, start + Duration::from_millis(200)),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            pacing_wait(Some(start), delay, start + Duration::from_millis(800)),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test]
+    async fn test_toggle_flow_updates_registry() {
+        let (manager, sink) = test_manager("[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n");
+        let device = Device::new(
+            "Single_1".to_string(),
+            "Kitchen".to_string(),
+            DeviceType::Light,
+            "02".to_string(),
+            "1".to_string(),
+        );
+        manager.registry.write().await.add(device);
+
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+        assert_eq!(*sink.sent.lock().unwrap(), vec!["01+01+01+02".to_string()]);
+        assert!(manager.get_device("Single_1_page02").await.unwrap().is_on());
+
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+        assert_eq!(sink.sent.lock().unwrap().len(), 1, "no command for an unchanged state");
+    }
+
+    #[tokio::test]
+    async fn test_stale_index_retry() {
+        let mappings = "[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n";
+        let config = BridgeConfig { stale_index_retry: true, ..BridgeConfig::default() };
+        let (manager, sink) = test_manager_with(mappings, config);
+
+        let light = |index: &str| {
+            Device::new(
+                "Single_1".to_string(),
+                "Kitchen".to_string(),
+                DeviceType::Light,
+                "02".to_string(),
+                index.to_string(),
+            )
+        };
+        manager.registry.write().await.add(light("1"));
+        // The gateway moved the light to index 3; the mapping still says 1.
+        sink.rejected.lock().unwrap().insert("01+01+01+02".to_string());
+        *sink.page_devices.lock().unwrap() = vec![light("3")];
+
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+        // The corrected command is remembered and sent straight away next time.
+        manager.toggle_device("Single_1_page02", false).await.unwrap();
+        assert_eq!(*sink.sent.lock().unwrap(), vec!["01+01+01+02", "03+01+01+02", "03+01+01+02"]);
+        assert_eq!(manager.get_device("Single_1_page02").await.unwrap().index, "1");
+
+        // Reloading the mappings forgets it, so the stale command is tried first again.
+        let path = std::env::temp_dir().join(format!("stale_index_{}.toml", std::process::id()));
+        std::fs::write(&path, mappings).unwrap();
+        manager.reload_mappings(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+        assert_eq!(sink.sent.lock().unwrap()[3..], ["01+01+01+02", "03+01+01+02"]);
+    }
+
+    #[tokio::test]
+    async fn test_fan_speed_survives_export_import() {
+        let (manager, sink) = test_manager("[ventilation]\n\"Single_6_page03_speed2\" = \"03+04+02+03\"\n");
+        let fan = Device::new(
+            "Single_6".to_string(),
+            "Lüftung".to_string(),
+            DeviceType::Fan,
+            "03".to_string(),
+            "3".to_string(),
+        );
+        manager.registry.write().await.add(fan);
+
+        manager.set_fan_speed("Single_6_page03", 2).await.unwrap();
+        assert_eq!(*sink.sent.lock().unwrap(), vec!["03+04+02+03"]);
+
+        let export = serde_json::to_string(&manager.get_all_devices().await).unwrap();
+        let snapshot: Vec<Device> = serde_json::from_str(&export).unwrap();
+        assert_eq!(manager.replace_devices(snapshot).await.unwrap(), 1);
+        assert_eq!(manager.get_device("Single_6_page03").await.unwrap().state, DeviceState::FanSpeed(2));
+    }
+
+    #[tokio::test]
+    async fn test_type_change_survives_rediscovery() {
+        let (manager, sink) = test_manager("[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n");
+        let light = Device::new(
+            "Single_1".to_string(),
+            "Pumpe".to_string(),
+            DeviceType::Light,
+            "02".to_string(),
+            "1".to_string(),
+        );
+        *sink.page_devices.lock().unwrap() = vec![light];
+        manager.rediscover().await.unwrap();
+
+        manager.set_device_type("Single_1_page02", DeviceType::Switch).await.unwrap();
+        manager.rediscover().await.unwrap();
+        assert_eq!(manager.get_device("Single_1_page02").await.unwrap().type_, DeviceType::Switch);
+    }
+
+    #[tokio::test]
+    async fn test_toggle_emits_one_state_event() {
+        let (manager, _sink) = test_manager("[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n");
+        let device = Device::new(
+            "Single_1".to_string(),
+            "Kitchen".to_string(),
+            DeviceType::Light,
+            "02".to_string(),
+            "1".to_string(),
+        );
+        manager.registry.write().await.add(device);
+        let mut events = manager.subscribe();
+
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            StateEvent::DeviceStateChanged {
+                key: "Single_1_page02".to_string(),
+                device_type: DeviceType::Light,
+                state: DeviceState::OnOff(true),
+            }
+        );
+        assert!(events.try_recv().is_err(), "an unchanged state emits nothing");
+    }
+
+    #[tokio::test]
+    async fn test_stop_only_keeps_position() {
+        let (manager, sink) = test_manager(
+            "[blinds]\n\"Blind_1_page02_up\" = \"05+01+00+02\"\n\"Blind_1_page02_stop\" = \"05+02+00+02\"\n\"Blind_1_page02_down\" = \"05+03+00+02\"\n",
+        );
+        let mut device = Device::new(
+            "Blind_1".to_string(),
+            "Wohnzimmer Storen".to_string(),
+            DeviceType::WindowCovering,
+            "02".to_string(),
+            "4".to_string(),
+        );
+        device.set_state(DeviceState::WindowCovering {
+            position: 40,
+            state: crate::device::WindowCoveringState::Stopped,
+            obstruction: false,
+            tilt: None,
+        });
+        manager.registry.write().await.add(device);
+
+        manager.set_blind_position("Blind_1_page02", 60).await.unwrap();
+        assert_eq!(*sink.sent.lock().unwrap(), vec!["05+02+00+02".to_string()]);
+        let state = manager.get_device("Blind_1_page02").await.unwrap().state;
+        assert!(matches!(state, DeviceState::WindowCovering { position: 40, .. }), "{state:?}");
+
+        manager.set_blind_position("Blind_1_page02", 100).await.unwrap();
+        let state = manager.get_device("Blind_1_page02").await.unwrap().state;
+        assert!(matches!(state, DeviceState::WindowCovering { position: 100, .. }), "{state:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_reads_during_registry_swap() {
+        let (manager, _) = test_manager("");
+        let registry_of = |count: usize| {
+            let mut registry = DeviceRegistry::new();
+            for i in 0..count {
+                let id = format!("Single_{i}");
+                registry.add(Device::new(id.clone(), id, DeviceType::Light, "02".to_string(), i.to_string()));
+            }
+            registry
+        };
+        manager.swap_registry(registry_of(3)).await;
+
+        let reader = {
+            let manager = manager.clone();
+            tokio::spawn(async move {
+                let mut seen = HashSet::new();
+                for _ in 0..200 {
+                    seen.insert(manager.get_all_devices().await.len());
+                    tokio::task::yield_now().await;
+                }
+                seen
+            })
+        };
+        for count in [50, 3, 50] {
+            manager.swap_registry(registry_of(count)).await;
+            tokio::task::yield_now().await;
+        }
+
+        let seen = reader.await.unwrap();
+        assert!(seen.iter().all(|count| *count == 3 || *count == 50), "saw {seen:?}");
+    }
+
+    #[tokio::test]
+    async fn test_action_type_mismatch() {
+        let (manager, sink) = test_manager("[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n");
+        let device = Device::new(
+            "Single_1".to_string(),
+            "Kitchen".to_string(),
+            DeviceType::Light,
+            "02".to_string(),
+            "1".to_string(),
+        );
+        manager.registry.write().await.add(device);
+
+        let action: DeviceAction = serde_json::from_str(r#"{"action":"set_position","position":50}"#).unwrap();
+        let err = manager.perform_action("Single_1_page02", action).await.unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedAction>().is_some());
+        assert!(sink.sent.lock().unwrap().is_empty());
+
+        let action: DeviceAction = serde_json::from_str(r#"{"action":"on"}"#).unwrap();
+        manager.perform_action("Single_1_page02", action).await.unwrap();
+        assert_eq!(sink.sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_missing_mapping_names_index() {
+        let (manager, _sink) = test_manager("");
+        let device = Device::new(
+            "Single_4".to_string(),
+            "Flur".to_string(),
+            DeviceType::Light,
+            "02".to_string(),
+            "17".to_string(),
+        );
+        manager.registry.write().await.add(device);
+
+        let err = manager.toggle_device("Single_4_page02", true).await.unwrap_err();
+        assert!(err.to_string().contains("page: 02, index: 17"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_rediscover_while_busy() {
+        let client = Arc::new(KnxClient::new(Arc::new(KnxConfig::test_default()), true).unwrap());
+        let config = BridgeConfig {
+            discovery_lock_wait: Duration::from_millis(10),
+            ..BridgeConfig::default()
+        };
+        let manager = StateManager::new(client, Arc::new(CommandMapper::from_toml("").unwrap()), config);
+
+        let _poll = manager.discovery_lock.lock().await;
+        assert!(manager.diagnostics().await.discovery_in_progress);
+        let err = manager.rediscover().await.unwrap_err();
+        assert!(err.downcast_ref::<DiscoveryInProgress>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_event_client_cap() {
+        let client = Arc::new(KnxClient::new(Arc::new(KnxConfig::test_default()), true).unwrap());
+        let config = BridgeConfig { max_event_clients: 1, ..BridgeConfig::default() };
+        let manager = StateManager::new(client, Arc::new(CommandMapper::from_toml("").unwrap()), config);
+
+        let first = manager.subscribe_client(EventFilter::default()).unwrap();
+        assert_eq!(manager.diagnostics().await.event_clients, 1);
+        let err = manager.subscribe_client(EventFilter::default()).err().unwrap();
+        assert_eq!(err.limit, 1);
+
+        drop(first);
+        assert_eq!(manager.diagnostics().await.event_clients, 0);
+        assert!(manager.subscribe_client(EventFilter::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_filtered_subscription() {
+        let (manager, _sink) = test_manager(
+            "[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n[switches]\n\"Single_2_page02\" = \"02+01+01+02\"\n\"Single_3_page02\" = \"03+01+01+02\"\n[aliases]\nhall = \"Single_3_page02\"\n",
+        );
+        for (id, type_) in [
+            ("Single_1", DeviceType::Light),
+            ("Single_2", DeviceType::Switch),
+            ("Single_3", DeviceType::Switch),
+        ] {
+            let device = Device::new(id.to_string(), id.to_string(), type_, "02".to_string(), "1".to_string());
+            manager.registry.write().await.add(device);
+        }
+        assert!(manager.event_filter(None, Some("Lamp")).await.is_err());
+        let filter = manager.event_filter(Some("hall"), Some("light")).await.unwrap();
+        let mut events = manager.subscribe_client(filter).unwrap();
+
+        for key in ["Single_2_page02", "Single_1_page02", "Single_3_page02"] {
+            manager.toggle_device(key, true).await.unwrap();
+        }
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            if let StateEvent::DeviceStateChanged { key, .. } = events.recv().await.unwrap() {
+                received.push(key);
+            }
+        }
+        assert_eq!(received, vec!["Single_1_page02", "Single_3_page02"]);
+        assert!(EventFilter::default().matches(&StateEvent::SessionRefreshed));
+    }
+
+    #[tokio::test]
+    async fn test_virtual_device() {
+        let (manager, sink) = test_manager(
+            "[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n\"Single_2_page02\" = \"02+01+01+02\"\n\
+             [virtual.ceiling]\ntype = \"Light\"\nmembers = [\"Single_1_page02\", \"Single_2_page02\"]\non_when = \"all\"\n",
+        );
+        {
+            let mut registry = manager.registry.write().await;
+            for id in ["Single_1", "Single_2"] {
+                registry.add(Device::new(id.to_string(), id.to_string(), DeviceType::Light, "02".to_string(), "1".to_string()));
+            }
+            for device in manager.command_mapper().virtual_devices() {
+                registry.add(device);
+            }
+        }
+        let is_on = |key: &'static str| {
+            let manager = manager.clone();
+            async move { manager.get_device(key).await.unwrap().is_on() }
+        };
+
+        manager.toggle_device("Single_1_page02", true).await.unwrap();
+        assert!(!is_on("ceiling_page00").await, "only one of two members is on");
+
+        manager.toggle_device("ceiling_page00", true).await.unwrap();
+        assert_eq!(*sink.sent.lock().unwrap(), vec!["01+01+01+02".to_string(), "02+01+01+02".to_string()]);
+        assert!(is_on("ceiling_page00").await);
+
+        manager.toggle_device("Single_2_page02", false).await.unwrap();
+        assert!(!is_on("ceiling_page00").await);
+        assert!(manager.capabilities(&manager.get_device("ceiling_page00").await.unwrap()).on_off);
+    }
+
+    #[tokio::test]
+    async fn test_reload_mappings() {
+        let (manager, _sink) = test_manager("[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n");
+        let path = std::env::temp_dir().join(format!("reload_mappings_{}.toml", std::process::id()));
+
+        std::fs::write(&path, "[lights\n").unwrap();
+        assert!(manager.reload_mappings(&path).await.is_err());
+        assert_eq!(manager.command_mapper().get_command("Single_1", "02"), Some("01+01+01+02"));
+
+        std::fs::write(
+            &path,
+            "[lights]\n\"Single_1_page02\" = \"09+01+01+02\"\n\
+             [virtual.ceiling]\ntype = \"Light\"\nmembers = [\"Single_1_page02\"]\n",
+        )
+        .unwrap();
+        let total = manager.reload_mappings(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(total, 1);
+        assert_eq!(manager.command_mapper().get_command("Single_1", "02"), Some("09+01+01+02"));
+        assert!(manager.get_device("ceiling_page00").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_scene() {
+        let (manager, sink) = test_manager("[scenes]\n\"Scene_2_page03\" = \"READONLY\"\n");
+        for id in ["Scene_1", "Scene_2"] {
+            let device = Device::new(
+                id.to_string(),
+                id.to_string(),
+                DeviceType::Scene,
+                "03".to_string(),
+                "1".to_string(),
+            );
+            manager.registry.write().await.add(device);
+        }
+
+        let scenes = manager.scenes().await;
+        assert_eq!(scenes.len(), 2);
+        assert!(scenes.iter().all(|(_, controllable)| !controllable));
+
+        let err = manager.perform_action("Scene_2_page03", DeviceAction::On).await.unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedAction>().is_some());
+        assert!(sink.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_trigger_scene() {
+        let (manager, sink) = test_manager("[scenes]\n\"Scene_1_page03\" = \"01+01+01+03\"\n");
+        let scene = Device::new("Scene_1".to_string(), "Abend".to_string(), DeviceType::Scene, "03".to_string(), "1".to_string());
+        let light = Device::new("Single_1".to_string(), "Decke".to_string(), DeviceType::Light, "03".to_string(), "2".to_string());
+        manager.registry.write().await.add(scene);
+        manager.registry.write().await.add(light);
+
+        manager.trigger_scene("Scene_1_page03").await.unwrap();
+        manager.trigger_scene("Scene_1_page03").await.unwrap();
+        assert_eq!(*sink.sent.lock().unwrap(), vec!["01+01+01+03".to_string(), "01+01+01+03".to_string()]);
+        assert!(!manager.get_device("Scene_1_page03").await.unwrap().is_on());
+        assert!(manager.trigger_scene("Single_1_page03").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_attention() {
+        let (manager, _) = test_manager(
+            "[lights]\n\"Single_1_page02\" = \"01+01+01+02\"\n\"Single_2_page02\" = \"READONLY\"\n",
+        );
+        for (id, type_) in [
+            ("Single_1", DeviceType::Light),
+            ("Single_2", DeviceType::Light),
+            ("Single_3", DeviceType::Light),
+            ("Temp_1", DeviceType::TemperatureSensor),
+        ] {
+            let device = Device::new(id.to_string(), id.to_string(), type_, "02".to_string(), "1".to_string());
+            manager.registry.write().await.add(device);
+        }
+        assert_eq!(
+            manager.attention().await.into_iter().map(|(d, reasons)| (d.key(), reasons)).collect::<Vec<_>>(),
+            vec![
+                ("Single_2_page02".to_string(), vec![AttentionReason::Readonly]),
+                ("Single_3_page02".to_string(), vec![AttentionReason::Unmapped]),
+            ]
+        );
+
+        {
+            let mut registry = manager.registry.write().await;
+            let sensor = registry.get_mut("Temp_1_page02").unwrap();
+            sensor.battery_low = Some(true);
+            sensor.reachable = false;
+        }
+        let attention = manager.attention().await;
+        assert_eq!(attention[2].0.key(), "Temp_1_page02");
+        assert_eq!(attention[2].1, vec![AttentionReason::Unreachable, AttentionReason::LowBattery]);
+    }
+
+    #[test]
+    fn test_parse_action() {
+        for text in ["on", "off", "stop", "identify", "position:0", "brightness:40", "speed:2"] {
+            assert_eq!(text.parse::<DeviceAction>().unwrap().to_string(), text);
+        }
+        assert!(" Position : 100 ".parse::<DeviceAction>().is_ok());
+        assert!("position".parse::<DeviceAction>().is_err());
+        assert!("position:101".parse::<DeviceAction>().is_err());
+        assert!("off:1".parse::<DeviceAction>().is_err());
+        assert!("speed".parse::<DeviceAction>().is_err());
+        assert!("close".parse::<DeviceAction>().is_err());
+    }
+
+    #[test]
+    fn test_action_range() {
+        assert!(DeviceAction::SetPosition { position: 100 }.check_range().is_ok());
+        assert_eq!(
+            DeviceAction::SetPosition { position: 101 }.check_range().unwrap_err(),
+            ValueOutOfRange { field: "position", value: 101, max: 100 }
+        );
+        assert!(DeviceAction::Brightness { level: 0 }.check_range().is_ok());
+        assert!(DeviceAction::Brightness { level: 255 }.check_range().is_err());
+    }
+}